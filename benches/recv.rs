@@ -0,0 +1,120 @@
+//! Compares the allocations and time taken to receive and parse peerinfo INFO messages with
+//! `Connection::recv`, which copies each message out of the receive buffer, and with
+//! `Connection::recv_borrowed`, which lends it out.
+//!
+//! Run with `cargo bench --bench recv`.
+
+use gnunet::runtime;
+use gnunet::service::peerinfo::msg::Info;
+use gnunet::service::Connection;
+use gnunet::util::{expect, MessageType};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const MESSAGES: usize = 100_000;
+
+/// A PEERINFO_INFO message holding a HELLO with a tcp and a udp address.
+fn info(id: u8) -> Vec<u8> {
+    let mut hello = vec![0, 0];
+    hello.extend_from_slice(&MessageType::HELLO.to_u16().to_be_bytes());
+    hello.extend_from_slice(&[0; 4]); // friend_only
+    hello.extend_from_slice(&[id; 32]);
+    for transport in &["tcp", "udp"] {
+        hello.extend_from_slice(transport.as_bytes());
+        hello.push(0);
+        hello.extend_from_slice(&6u16.to_be_bytes());
+        hello.extend_from_slice(&u64::MAX.to_be_bytes()); // expiration
+        hello.extend_from_slice(&[127, 0, 0, 1, 8, 6]);
+    }
+    let len = hello.len() as u16;
+    hello[..2].copy_from_slice(&len.to_be_bytes());
+
+    let mut b = (len + 40).to_be_bytes().to_vec();
+    b.extend_from_slice(&MessageType::PEERINFO_INFO.to_u16().to_be_bytes());
+    b.extend_from_slice(&[0; 4]); // reserved
+    b.extend_from_slice(&[id; 32]);
+    b.extend_from_slice(&hello);
+    b
+}
+
+fn connection(stream: &[u8]) -> Connection {
+    Connection::from_parts(
+        "peerinfo".to_string(),
+        Box::new(futures::io::Cursor::new(stream.to_vec())),
+        Box::new(futures::io::sink()),
+    )
+}
+
+/// Receive every message with `f`, and print the allocations and time per message.
+fn measure<F>(name: &str, stream: &[u8], f: F)
+where
+    F: FnOnce(Connection) -> usize,
+{
+    let conn = connection(stream);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let addresses = f(conn);
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    assert_eq!(addresses, 2 * MESSAGES);
+    println!(
+        "{:<24} {:>8.2} allocations/message {:>8.0} ns/message",
+        name,
+        allocations as f64 / MESSAGES as f64,
+        elapsed.as_nanos() as f64 / MESSAGES as f64,
+    );
+}
+
+fn main() {
+    let mut stream = vec![];
+    for i in 0..MESSAGES {
+        stream.extend_from_slice(&info(i as u8));
+    }
+
+    measure("recv, owned Info", &stream, |mut conn| {
+        runtime::block_on(async {
+            let mut addresses = 0;
+            for _ in 0..MESSAGES {
+                let (typ, buf) = conn.recv().await.unwrap();
+                let info: Info = expect(typ, &buf).unwrap();
+                addresses += info.hello.addresses.len();
+            }
+            addresses
+        })
+    });
+    measure("recv_borrowed, borrowed", &stream, |mut conn| {
+        runtime::block_on(async {
+            let mut addresses = 0;
+            for _ in 0..MESSAGES {
+                let (typ, buf) = conn.recv_borrowed().await.unwrap();
+                let info: Info<&str, &[u8]> = expect(typ, buf).unwrap();
+                addresses += info.hello.addresses.len();
+            }
+            addresses
+        })
+    });
+}
@@ -1,10 +1,14 @@
-extern crate gnunet;
+use gnunet::peer::Peer;
+use gnunet::service::gns;
+use gnunet::util::Config;
+use std::error::Error;
 
 fn print_help(executable: String) {
     println!("Usage: {} domain.name.gnu", executable);
 }
 
-fn main() {
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn Error>> {
     let mut args = std::env::args();
     let executable = args.next().unwrap();
     let domain = match args.next() {
@@ -12,15 +16,38 @@ fn main() {
         None => {
             println!("Missing domain name");
             print_help(executable);
-            return;
+            return Ok(());
         }
     };
-    match args.next() {
-        Some(x) => {
-            println!("Unexpected argument: {}", x);
-            print_help(executable);
-            return;
-        }
-        None => (),
+    if let Some(x) = args.next() {
+        println!("Unexpected argument: {}", x);
+        print_help(executable);
+        return Ok(());
+    }
+
+    let peer = Peer::new(Config::default()?);
+    let zone = peer
+        .identity()
+        .await?
+        .get_default_ego("gns-master")
+        .await?
+        .get_public_key();
+
+    let records = peer
+        .gns()
+        .await?
+        .lookup(
+            &domain,
+            zone,
+            gns::RecordType::A,
+            gns::LocalOptions::Default,
+            None,
+        )
+        .await?;
+    for record in records {
+        println!("\t{}", record);
     }
+
+    peer.shutdown().await;
+    Ok(())
 }
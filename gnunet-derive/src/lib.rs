@@ -0,0 +1,343 @@
+//! Derive macros for the `gnunet` crate.
+//!
+//! See `gnunet::util::message` for how to use `#[derive(GnunetMessage)]`.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident, Type};
+
+#[proc_macro_derive(GnunetMessage, attributes(gnunet))]
+pub fn derive_gnunet_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// How a field is laid out in the message.
+enum Kind {
+    /// A fixed-size struct starting with the `MessageHeader`.
+    Prefix,
+    /// A null-terminated string, with its length (including the null) optionally stored in a
+    /// prefix field.
+    CStr { len: Option<Ident> },
+    /// Raw bytes, with their length optionally stored in a prefix field.
+    Bytes { len: Option<Ident> },
+    /// A `Vec` of fixed-size elements, with their count optionally stored in a prefix field.
+    Array { count: Option<Ident> },
+}
+
+impl Kind {
+    /// Whether the field extends to the end of the message.
+    fn is_unbounded(&self) -> bool {
+        match self {
+            Kind::Bytes { len } => len.is_none(),
+            Kind::Array { count } => count.is_none(),
+            Kind::Prefix | Kind::CStr { .. } => false,
+        }
+    }
+}
+
+struct Field {
+    name: Ident,
+    ty: Type,
+    kind: Kind,
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let msg_type = parse_msg_type(&input)?;
+    let fields = parse_fields(&input)?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut generics_a = input.generics.clone();
+    generics_a.params.insert(0, syn::parse_quote!('__a));
+    let (impl_generics_a, _, _) = generics_a.split_for_impl();
+
+    // A message that borrows from its buffer is parsed with the lifetime it borrows for.
+    let mut generics_in = input.generics.clone();
+    let in_lt: syn::Lifetime = match input.generics.lifetimes().next() {
+        Some(def) => def.lifetime.clone(),
+        None => {
+            generics_in.params.insert(0, syn::parse_quote!('__a));
+            syn::parse_quote!('__a)
+        }
+    };
+    let (impl_generics_in, _, _) = generics_in.split_for_impl();
+    let type_params: Vec<_> = input.generics.type_params().map(|p| &p.ident).collect();
+    let predicates: Vec<_> = where_clause
+        .map(|w| w.predicates.iter().collect())
+        .unwrap_or_default();
+
+    let util = quote!(::gnunet::util);
+    let support = quote!(::gnunet::util::message::derive_support);
+
+    let prefix = &fields[0];
+    let prefix_name = &prefix.name;
+    let prefix_ty = &prefix.ty;
+    let names: Vec<_> = fields.iter().map(|f| &f.name).collect();
+
+    let mut in_bounds = vec![];
+    let mut out_bounds = vec![];
+    let mut parse = vec![];
+    let mut chunks = vec![];
+    let mut set_lengths = vec![];
+    for f in &fields[1..] {
+        let (field, ty) = (&f.name, &f.ty);
+        let len_of = |prefix_field: &Option<Ident>| match prefix_field {
+            Some(p) => quote!(Some(#prefix_name.#p.get() as usize)),
+            None => quote!(None),
+        };
+        match &f.kind {
+            Kind::Prefix => unreachable!(),
+            Kind::CStr { len } => {
+                if mentions_any(quote!(#ty), &type_params) {
+                    in_bounds.push(quote!(#ty: ::std::convert::From<&#in_lt str>));
+                    out_bounds.push(quote!(#ty: ::std::convert::AsRef<str>));
+                }
+                let len_val = len_of(len);
+                parse.push(quote! {
+                    let (#field, rest) = #support::parse_cstr(rest, #len_val)?;
+                    let #field = <#ty as ::std::convert::From<&#in_lt str>>::from(#field);
+                });
+                chunks.push(quote! {
+                    chunks.push(::std::convert::AsRef::<str>::as_ref(&this.#field).as_bytes());
+                    chunks.push(&[0u8][..]);
+                });
+                if let Some(len) = len {
+                    set_lengths.push(quote! {
+                        let n = ::std::convert::AsRef::<str>::as_ref(&self.#field).len() + 1;
+                        self.#prefix_name.#len.set(::std::convert::TryInto::try_into(n).ok()?);
+                    });
+                }
+            }
+            Kind::Bytes { len } => {
+                if mentions_any(quote!(#ty), &type_params) {
+                    in_bounds.push(quote!(#ty: ::std::convert::From<&#in_lt [u8]>));
+                    out_bounds.push(quote!(#ty: ::std::convert::AsRef<[u8]>));
+                }
+                let len_val = len_of(len);
+                parse.push(quote! {
+                    let (#field, rest) = #support::parse_bytes(rest, #len_val)?;
+                    let #field = <#ty as ::std::convert::From<&#in_lt [u8]>>::from(#field);
+                });
+                chunks.push(quote! {
+                    chunks.push(::std::convert::AsRef::<[u8]>::as_ref(&this.#field));
+                });
+                if let Some(len) = len {
+                    set_lengths.push(quote! {
+                        let n = ::std::convert::AsRef::<[u8]>::as_ref(&self.#field).len();
+                        self.#prefix_name.#len.set(::std::convert::TryInto::try_into(n).ok()?);
+                    });
+                }
+            }
+            Kind::Array { count } => {
+                let count_val = len_of(count);
+                parse.push(quote! {
+                    let (#field, rest) = #support::parse_array(rest, #count_val)?;
+                });
+                chunks.push(quote! {
+                    chunks.push(#util::serial::AsBytes::as_bytes(&this.#field[..]));
+                });
+                if let Some(count) = count {
+                    set_lengths.push(quote! {
+                        let n = self.#field.len();
+                        self.#prefix_name.#count.set(::std::convert::TryInto::try_into(n).ok()?);
+                    });
+                }
+            }
+        }
+    }
+    let n_chunks = 1 + fields[1..]
+        .iter()
+        .map(|f| match f.kind {
+            Kind::CStr { .. } => 2,
+            _ => 1,
+        })
+        .sum::<usize>();
+
+    Ok(quote! {
+        impl #impl_generics_in #util::MessageIn<#in_lt> for #name #ty_generics
+        where
+            #(#predicates,)*
+            #(#in_bounds,)*
+        {
+            fn msg_type() -> #util::MessageType {
+                #util::MessageType::#msg_type
+            }
+
+            fn from_bytes(b: &#in_lt [u8]) -> ::std::option::Option<Self> {
+                let (#prefix_name, rest) = #util::serial::try_cast_prefix::<#prefix_ty>(b)?;
+                let #prefix_name = *#prefix_name;
+                if #prefix_name.header.length() as usize != b.len() {
+                    return None;
+                }
+                #(#parse)*
+                if !rest.is_empty() {
+                    return None;
+                }
+                Some(#name { #(#names,)* })
+            }
+        }
+
+        impl #impl_generics_a #util::MessageOutCompound for &'__a #name #ty_generics
+        where
+            #(#predicates,)*
+            #(#out_bounds,)*
+        {
+            type Bytes = &'__a [u8];
+            type Chunks = #support::SmallVec<[&'__a [u8]; #n_chunks]>;
+
+            fn as_byte_chunks(&self) -> Self::Chunks {
+                let this: &'__a #name #ty_generics = *self;
+                let mut chunks = #support::SmallVec::new();
+                chunks.push(#util::serial::AsBytes::as_bytes(&this.#prefix_name));
+                #(#chunks)*
+                chunks
+            }
+        }
+
+        impl #impl_generics #name #ty_generics
+        where
+            #(#predicates,)*
+            #(#out_bounds,)*
+        {
+            /// Fill in the header of the message, along with any length and count fields of its
+            /// prefix. Returns `None` if the message is too long to send.
+            pub fn fill_header(mut self) -> ::std::option::Option<Self> {
+                #(#set_lengths)*
+                let len = #support::checked_len(
+                    #util::MessageOutCompound::as_byte_chunks(&&self),
+                )?;
+                self.#prefix_name.header =
+                    #util::MessageHeader::new(len, #util::MessageType::#msg_type);
+                Some(self)
+            }
+        }
+    })
+}
+
+/// Whether `tokens` contains any of `idents`, eg. whether a field's type uses a type parameter.
+fn mentions_any(tokens: TokenStream2, idents: &[&Ident]) -> bool {
+    tokens.into_iter().any(|t| match t {
+        proc_macro2::TokenTree::Ident(i) => idents.iter().any(|p| **p == i),
+        proc_macro2::TokenTree::Group(g) => mentions_any(g.stream(), idents),
+        _ => false,
+    })
+}
+
+fn parse_msg_type(input: &DeriveInput) -> Result<Ident, Error> {
+    let mut msg_type = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("gnunet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("msg_type") {
+                msg_type = Some(meta.value()?.parse::<Ident>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `msg_type = ...`"))
+            }
+        })?;
+    }
+    msg_type.ok_or_else(|| {
+        Error::new(
+            Span::call_site(),
+            "missing `#[gnunet(msg_type = ...)]` attribute",
+        )
+    })
+}
+
+fn parse_fields(input: &DeriveInput) -> Result<Vec<Field>, Error> {
+    let named = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(Error::new_spanned(&input.ident, "expected named fields")),
+        },
+        _ => return Err(Error::new_spanned(&input.ident, "expected a struct")),
+    };
+
+    let mut fields = vec![];
+    for f in named {
+        let mut kind = None;
+        for attr in f.attrs.iter().filter(|a| a.path().is_ident("gnunet")) {
+            attr.parse_nested_meta(|meta| {
+                let new = if meta.path.is_ident("prefix") {
+                    Some(Kind::Prefix)
+                } else if meta.path.is_ident("cstr") {
+                    Some(Kind::CStr { len: None })
+                } else if meta.path.is_ident("bytes") {
+                    Some(Kind::Bytes { len: None })
+                } else if meta.path.is_ident("array") {
+                    Some(Kind::Array { count: None })
+                } else {
+                    None
+                };
+                if let Some(new) = new {
+                    if kind.is_some() {
+                        return Err(meta.error("field already has a kind"));
+                    }
+                    kind = Some(new);
+                    return Ok(());
+                }
+
+                let target =
+                    match &mut kind {
+                        Some(Kind::CStr { len }) | Some(Kind::Bytes { len })
+                            if meta.path.is_ident("len") =>
+                        {
+                            len
+                        }
+                        Some(Kind::Array { count }) if meta.path.is_ident("count") => count,
+                        _ => return Err(meta.error(
+                            "expected `prefix`, `cstr`, `bytes` or `array`, optionally followed \
+                             by `len = ...` or `count = ...`",
+                        )),
+                    };
+                *target = Some(meta.value()?.parse::<Ident>()?);
+                Ok(())
+            })?;
+        }
+        let kind = kind.ok_or_else(|| {
+            Error::new_spanned(
+                f,
+                "expected a `#[gnunet(prefix)]`, `#[gnunet(cstr)]`, `#[gnunet(bytes)]` or \
+                 `#[gnunet(array)]` attribute",
+            )
+        })?;
+        fields.push(Field {
+            name: f.ident.clone().unwrap(),
+            ty: f.ty.clone(),
+            kind,
+        });
+    }
+
+    match fields.first() {
+        Some(Field {
+            kind: Kind::Prefix, ..
+        }) => (),
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "the first field must be the `#[gnunet(prefix)]`",
+            ))
+        }
+    }
+    for (i, f) in fields.iter().enumerate().skip(1) {
+        if let Kind::Prefix = f.kind {
+            return Err(Error::new_spanned(
+                &f.name,
+                "only one field can be the prefix",
+            ));
+        }
+        if f.kind.is_unbounded() && i + 1 != fields.len() {
+            return Err(Error::new_spanned(
+                &f.name,
+                "only the last field can extend to the end of the message; give this field a \
+                 `len` or `count`",
+            ));
+        }
+    }
+    Ok(fields)
+}
@@ -0,0 +1,68 @@
+//! Synchronous wrappers around the service clients, for programs that don't otherwise use async.
+//!
+//! Enabled by the `blocking` cargo feature. Each client here drives its async counterpart to
+//! completion on the current thread using `runtime::block_on`, and so must not be used from within
+//! an async task.
+//!
+//! Connecting gives up after `DEFAULT_TIMEOUT`, or the timeout passed to `connect_with_timeout`,
+//! with `Error::TimedOut`. Clients whose requests wait for the service then set the same timeout
+//! on the async client, which `set_timeout` changes. A request that times out fails with the
+//! service's own `TimedOut` error, and leaves the client usable: its request is either cancelled
+//! or, where the method says so, its late reply is discarded by the next call.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use gnunet::blocking::peerinfo;
+//! use gnunet::util::Config;
+//!
+//! let config = Config::default().unwrap();
+//! let mut pi = peerinfo::Client::connect(&config).unwrap();
+//! for hello in pi.all_peers().unwrap() {
+//!     println!("{}", hello.peer_id());
+//! }
+//! ```
+
+use crate::runtime;
+use std::future::Future;
+use std::time::Duration;
+
+pub mod arm;
+pub mod cadet;
+pub mod gns;
+pub mod identity;
+pub mod peerinfo;
+pub mod transport;
+
+/// The timeout applied to connecting and to later requests unless a client is configured
+/// otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors returned by the blocking clients.
+#[derive(Debug, Error)]
+pub enum Error<E: std::error::Error + 'static> {
+    #[error("The call did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("{source}")]
+    Service {
+        #[from]
+        source: E,
+    },
+}
+
+/// Block on `fut`, giving up after `timeout` if it is not `None`.
+fn wait<F, T, E>(timeout: Option<Duration>, fut: F) -> Result<T, Error<E>>
+where
+    F: Future<Output = Result<T, E>>,
+    E: std::error::Error + 'static,
+{
+    runtime::block_on(async {
+        match timeout {
+            Some(d) => match runtime::timeout(d, fut).await {
+                Ok(res) => Ok(res?),
+                Err(_) => Err(Error::TimedOut { timeout: d }),
+            },
+            None => Ok(fut.await?),
+        }
+    })
+}
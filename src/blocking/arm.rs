@@ -0,0 +1,50 @@
+//! A blocking client for the ARM service. See `service::arm`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::service::arm::{self, ArmError, ArmResult};
+use crate::service::ConnectError;
+use crate::util::Config;
+use std::time::Duration;
+
+/// A blocking handle to the ARM service.
+pub struct Client {
+    inner: arm::Client,
+}
+
+impl Client {
+    /// Connect to the ARM service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config) -> Result<Client, Error<ConnectError>> {
+        Client::connect_with_timeout(cfg, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the ARM service. `timeout` applies to connecting and to every later request.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<ConnectError>> {
+        let mut inner = wait(timeout, arm::Client::connect(cfg))?;
+        inner.set_timeout(timeout);
+        Ok(Client { inner })
+    }
+
+    /// Set the timeout for subsequent requests. `None` means they may block forever. See
+    /// `service::arm::Client::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_timeout(timeout);
+    }
+
+    /// Ask ARM to start the service `name`. See `service::arm::Client::start`.
+    pub fn start(&self, name: &str) -> Result<ArmResult, Error<ArmError>> {
+        wait(None, self.inner.start(name))
+    }
+
+    /// Ask ARM to stop the service `name`. See `service::arm::Client::stop`.
+    pub fn stop(&self, name: &str) -> Result<ArmResult, Error<ArmError>> {
+        wait(None, self.inner.stop(name))
+    }
+
+    /// List the services that ARM is currently running.
+    pub fn list(&self) -> Result<Vec<String>, Error<ArmError>> {
+        wait(None, self.inner.list())
+    }
+}
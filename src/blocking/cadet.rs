@@ -0,0 +1,60 @@
+//! A blocking client for the CADET service. See `service::cadet`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::service::cadet::{self, msg::ChannelOptions, msg::Port, Channel};
+use crate::service::ConnectError;
+use crate::util::{Config, PeerIdentity, ProtocolVersion};
+use std::io;
+use std::time::Duration;
+
+/// A blocking handle to the CADET service.
+pub struct Client {
+    inner: cadet::Client,
+}
+
+impl Client {
+    /// Connect to the CADET service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config, listen_ports: Vec<Port>) -> Result<Client, Error<ConnectError>> {
+        Client::connect_with_timeout(cfg, listen_ports, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the CADET service, giving up after `timeout`.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        listen_ports: Vec<Port>,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<ConnectError>> {
+        let inner = wait(timeout, cadet::Client::connect(cfg, listen_ports))?;
+        Ok(Client { inner })
+    }
+
+    /// Speak the protocol of GNUnet `version` in later calls. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.inner.set_version(version);
+    }
+
+    /// Listen for channels opened to `port`. See `service::cadet::Client::open_port`.
+    pub fn open_port(&self, port: Port) -> Result<(), io::Error> {
+        self.inner.open_port(port)
+    }
+
+    /// Open a channel to `port` on `peer`.
+    pub fn connect_to_peer(
+        &mut self,
+        peer: &PeerIdentity,
+        port: u32,
+        opt: ChannelOptions,
+    ) -> Result<Channel, Error<io::Error>> {
+        wait(None, self.inner.connect_to_peer(peer, port, opt))
+    }
+
+    /// Open a channel to `port` on `peer`. See `service::cadet::Client::open_channel`.
+    pub fn open_channel(
+        &mut self,
+        peer: &PeerIdentity,
+        port: Port,
+        opt: ChannelOptions,
+    ) -> Result<Channel, Error<io::Error>> {
+        wait(None, self.inner.open_channel(peer, port, opt))
+    }
+}
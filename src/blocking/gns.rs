@@ -0,0 +1,100 @@
+//! A blocking client for the GNS service. See `service::gns`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey};
+use crate::service::gns::{LocalOptions, LookupError, LookupHandle, Record, RecordType};
+use crate::service::ConnectError;
+use crate::util::{Config, ProtocolVersion};
+use std::time::Duration;
+
+/// A blocking handle to the GNS service.
+pub struct Client {
+    handle: LookupHandle,
+}
+
+impl Client {
+    /// Connect to the GNS service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config) -> Result<Client, Error<ConnectError>> {
+        Client::connect_with_timeout(cfg, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the GNS service. `timeout` applies to connecting and to every later request.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<ConnectError>> {
+        let mut handle = wait(timeout, LookupHandle::connect(cfg))?;
+        handle.set_timeout(timeout);
+        Ok(Client { handle })
+    }
+
+    /// Set the timeout for subsequent requests. `None` means they may block forever. See
+    /// `service::gns::LookupHandle::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.handle.set_timeout(timeout);
+    }
+
+    /// Speak the protocol of GNUnet `version` in later calls. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.handle.set_version(version);
+    }
+
+    /// Lookup a vector of GNS records. See `service::gns::Client::lookup`.
+    pub fn lookup(
+        &self,
+        name: &str,
+        zone: EcdsaPublicKey,
+        record_type: RecordType,
+        options: LocalOptions,
+        shorten: Option<EcdsaPrivateKey>,
+    ) -> Result<Vec<Record>, Error<LookupError>> {
+        wait(
+            None,
+            self.handle
+                .lookup(name, zone, record_type, options, shorten),
+        )
+    }
+}
+
+#[test]
+fn test_lookup_timeout() {
+    use crate::runtime::{self, UnixStream};
+    use crate::service::Connection;
+    use std::str::FromStr;
+    use std::thread;
+
+    let (mut handle, mut service) = runtime::block_on(async {
+        let (client, service) = UnixStream::pair().unwrap();
+        (
+            LookupHandle::from_connection(Connection::from_stream("gns".to_string(), client), 0),
+            Connection::from_stream("service".to_string(), service),
+        )
+    });
+    handle.set_timeout(Some(Duration::from_millis(50)));
+    let client = Client { handle };
+    let zone =
+        EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+    let lookup = |name| client.lookup(name, zone, RecordType::A, LocalOptions::Default, None);
+
+    match lookup("slow.gnu") {
+        Err(Error::Service {
+            source: LookupError::TimedOut { timeout },
+        }) => assert_eq!(timeout, Duration::from_millis(50)),
+        r => panic!("expected TimedOut, got {:?}", r.map(|_| ())),
+    }
+
+    // The service answers both lookups, but only the second is still waiting.
+    let service = thread::spawn(move || {
+        runtime::block_on(async {
+            for _ in 0..2 {
+                let (_, buf) = service.recv().await.unwrap();
+                let mut res = vec![0, 12, 1, 245]; // header: len 12, GNS_LOOKUP_RESULT
+                res.extend_from_slice(&buf[4..8]); // id
+                res.extend_from_slice(&0u32.to_be_bytes()); // rd_count
+                service.send(&res[..]).await.unwrap();
+            }
+        })
+    });
+    assert!(lookup("fast.gnu").unwrap().is_empty());
+    service.join().unwrap();
+}
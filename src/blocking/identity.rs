@@ -0,0 +1,57 @@
+//! A blocking client for the identity service. See `service::identity`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::crypto::HashCode;
+use crate::service::identity::{self, ConnectError, Ego, GetDefaultEgoError, UpdateStreamError};
+use crate::util::{Config, ProtocolVersion};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A blocking handle to the identity service.
+pub struct Client {
+    inner: identity::Client,
+}
+
+impl Client {
+    /// Connect to the identity service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config) -> Result<Client, Error<ConnectError>> {
+        Client::connect_with_timeout(cfg, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the identity service. `timeout` applies to connecting and to every later request.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<ConnectError>> {
+        let mut inner = wait(timeout, identity::Client::connect(cfg))?;
+        inner.set_timeout(timeout);
+        Ok(Client { inner })
+    }
+
+    /// Set the timeout for subsequent requests. `None` means they may block forever. See
+    /// `service::identity::Client::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_timeout(timeout);
+    }
+
+    /// Speak the protocol of GNUnet `version` in later calls. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.inner.set_version(version);
+    }
+
+    /// Get the default identity associated with a service.
+    ///
+    /// If this times out, the response may still arrive later. The next call discards it before
+    /// sending its own request.
+    pub fn get_default_ego(&mut self, name: &str) -> Result<Ego, Error<GetDefaultEgoError>> {
+        wait(None, self.inner.get_default_ego(name))
+    }
+
+    /// Get every ego the service knows about. See
+    /// `service::identity::Client::get_update_stream`.
+    pub fn get_update_stream(
+        &mut self,
+    ) -> Result<HashMap<HashCode, Ego>, Error<UpdateStreamError>> {
+        wait(None, self.inner.get_update_stream())
+    }
+}
@@ -0,0 +1,47 @@
+//! A blocking client for the peerinfo service. See `service::peerinfo`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::service::peerinfo::{self, PeerInfoError};
+use crate::util::{Config, Hello, PeerIdentity};
+use std::time::Duration;
+
+/// A blocking handle to the peerinfo service.
+pub struct Client {
+    inner: peerinfo::Client,
+}
+
+impl Client {
+    /// Connect to the peerinfo service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config) -> Result<Client, Error<PeerInfoError>> {
+        Client::connect_with_timeout(cfg, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the peerinfo service. `timeout` applies to connecting and to every later request.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<PeerInfoError>> {
+        let mut inner = wait(timeout, peerinfo::Client::connect(cfg))?;
+        inner.set_timeout(timeout);
+        Ok(Client { inner })
+    }
+
+    /// Set the timeout for subsequent requests. `None` means they may block forever. See
+    /// `service::peerinfo::Client::set_timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.set_timeout(timeout);
+    }
+
+    /// Get the HELLO of the peer with identity `id`, if the service knows it.
+    ///
+    /// If this times out, the response may still arrive later. The next call discards it before
+    /// sending its own request.
+    pub fn get_peer(&mut self, id: &PeerIdentity) -> Result<Option<Hello>, Error<PeerInfoError>> {
+        wait(None, self.inner.get_peer(id))
+    }
+
+    /// Returns a vector of all connected peers. See `get_peer` regarding timeouts.
+    pub fn all_peers(&mut self) -> Result<Vec<Hello>, Error<PeerInfoError>> {
+        wait(None, self.inner.all_peers())
+    }
+}
@@ -0,0 +1,32 @@
+//! A blocking client for the transport service. See `service::transport`.
+
+use super::{wait, Error, DEFAULT_TIMEOUT};
+use crate::service::transport::{self, ConnectError};
+use crate::util::{Config, Hello};
+use std::time::Duration;
+
+/// A blocking handle to the transport service.
+pub struct Client {
+    inner: transport::Client,
+}
+
+impl Client {
+    /// Connect to the transport service, using `DEFAULT_TIMEOUT`.
+    pub fn connect(cfg: &Config) -> Result<Client, Error<ConnectError>> {
+        Client::connect_with_timeout(cfg, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// Connect to the transport service, giving up after `timeout`.
+    pub fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, Error<ConnectError>> {
+        let inner = wait(timeout, transport::Client::connect(cfg))?;
+        Ok(Client { inner })
+    }
+
+    /// The HELLO of the local peer, as sent by the service when we connected.
+    pub fn our_hello(&self) -> &Hello {
+        &self.inner.our_hello
+    }
+}
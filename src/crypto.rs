@@ -7,3 +7,11 @@ pub use self::eddsa::EddsaPublicKey;
 
 pub mod hashcode;
 pub use self::hashcode::HashCode;
+
+/// The type that GNUnet 0.14 and later put before an ECDSA key. It is also the GNS `PKEY` record
+/// type.
+pub const KEY_TYPE_ECDSA: u32 = 65536;
+
+/// The type that GNUnet 0.14 and later put before an EdDSA key. It is also the GNS `EDKEY` record
+/// type.
+pub const KEY_TYPE_EDDSA: u32 = 65556;
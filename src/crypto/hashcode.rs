@@ -9,9 +9,11 @@ use std::slice;
 use std::str::FromStr;
 
 use crate::util::data;
+use crate::util::serial::{AsBytes, FromBytes};
 
 /// A 512-bit hashcode used in various places throughout GNUnet.
-#[derive(Hash, PartialEq, Eq, Clone, PartialOrd, Ord)]
+#[derive(Hash, PartialEq, Eq, Clone, PartialOrd, Ord, AsBytes, FromBytes)]
+#[repr(C)]
 pub struct HashCode {
     data: [u32; 16],
 }
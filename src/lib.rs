@@ -17,7 +17,15 @@ extern crate regex;
 #[macro_use]
 extern crate thiserror;
 extern crate either;
+// Lets code generated by `gnunet-derive` refer to `::gnunet` from within this crate too.
+extern crate self as gnunet;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod crypto;
+pub mod peer;
+pub mod runtime;
 pub mod service;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod util;
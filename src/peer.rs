@@ -0,0 +1,260 @@
+//! A single handle to the services of a local peer.
+//!
+//! A `Peer` is built from the peer's `Config`, and opens a connection to each service the first
+//! time it is used. The client is then cached, and shared by every clone of the `Peer`:
+//!
+//! * `arm` and `gns` return a cloneable handle, through which any number of tasks can make
+//!   requests at once.
+//! * `identity` and `peerinfo` lock the client for the caller's exclusive use, until the returned
+//!   `ClientGuard` is dropped.
+//!
+//! A client whose connection was lost, or poisoned by a request that failed part way through (see
+//! `service::request`), is replaced by a new connection the next time it is asked for.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use gnunet::peer::Peer;
+//! use gnunet::util::Config;
+//!
+//! let peer = Peer::new(Config::default()?);
+//! for hello in peer.peerinfo().await?.all_peers().await? {
+//!     println!("{}", hello.peer_id());
+//! }
+//! peer.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::service::peerinfo::PeerInfoError;
+use crate::service::{self, arm, gns, identity, peerinfo};
+use crate::util::Config;
+use futures::lock::{Mutex, OwnedMutexGuard};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Errors returned by the service accessors of `Peer`.
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("The peer handle has been shut down")]
+    ShutDown,
+    #[error("Failed to connect to the service. Reason: {source}")]
+    Connect {
+        #[from]
+        source: service::ConnectError,
+    },
+    #[error("Failed to connect to the identity service. Reason: {source}")]
+    Identity {
+        #[from]
+        source: identity::ConnectError,
+    },
+    #[error("Failed to connect to the peerinfo service. Reason: {source}")]
+    PeerInfo {
+        #[from]
+        source: PeerInfoError,
+    },
+}
+
+/// A cloneable handle to the services of a local peer. See the module documentation.
+#[derive(Clone)]
+pub struct Peer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    cfg: Config,
+    shut_down: AtomicBool,
+    arm: Slot<arm::Client>,
+    gns: Slot<gns::LookupHandle>,
+    identity: Slot<identity::Client>,
+    peerinfo: Slot<peerinfo::Client>,
+}
+
+/// The cached client of one service, or `None` until it is first used.
+type Slot<C> = Arc<Mutex<Option<C>>>;
+
+/// A cached client, locked for exclusive use until the guard is dropped.
+pub struct ClientGuard<C> {
+    guard: OwnedMutexGuard<Option<C>>,
+}
+
+impl<C> Deref for ClientGuard<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<C> DerefMut for ClientGuard<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+/// A client that `Peer` can tell is no longer worth keeping.
+trait Cached {
+    fn is_usable(&self) -> bool;
+}
+
+impl Cached for arm::Client {
+    fn is_usable(&self) -> bool {
+        self.is_connected()
+    }
+}
+
+impl Cached for gns::LookupHandle {
+    fn is_usable(&self) -> bool {
+        self.is_connected()
+    }
+}
+
+impl Cached for identity::Client {
+    fn is_usable(&self) -> bool {
+        !self.is_poisoned()
+    }
+}
+
+impl Cached for peerinfo::Client {
+    fn is_usable(&self) -> bool {
+        !self.is_poisoned()
+    }
+}
+
+impl Peer {
+    /// Create a handle to the peer described by `cfg`. No connections are opened until a service
+    /// is first used.
+    pub fn new(cfg: Config) -> Peer {
+        Peer {
+            inner: Arc::new(Inner {
+                cfg,
+                shut_down: AtomicBool::new(false),
+                arm: Slot::default(),
+                gns: Slot::default(),
+                identity: Slot::default(),
+                peerinfo: Slot::default(),
+            }),
+        }
+    }
+
+    /// The config the handle was created with.
+    pub fn config(&self) -> &Config {
+        &self.inner.cfg
+    }
+
+    /// A handle to the ARM service.
+    pub async fn arm(&self) -> Result<arm::Client, PeerError> {
+        let client = self.open(&self.inner.arm, arm::Client::connect).await?;
+        Ok(client.clone())
+    }
+
+    /// A handle to the GNS service.
+    pub async fn gns(&self) -> Result<gns::LookupHandle, PeerError> {
+        let handle = self
+            .open(&self.inner.gns, gns::LookupHandle::connect)
+            .await?;
+        Ok(handle.clone())
+    }
+
+    /// The identity client, once no other task is using it.
+    pub async fn identity(&self) -> Result<ClientGuard<identity::Client>, PeerError> {
+        self.open(&self.inner.identity, identity::Client::connect)
+            .await
+    }
+
+    /// The peerinfo client, once no other task is using it.
+    pub async fn peerinfo(&self) -> Result<ClientGuard<peerinfo::Client>, PeerError> {
+        self.open(&self.inner.peerinfo, peerinfo::Client::connect)
+            .await
+    }
+
+    /// Close every cached connection. Later calls to the service accessors of this handle and all
+    /// of its clones fail with `PeerError::ShutDown`.
+    ///
+    /// Waits for tasks holding a `ClientGuard` to drop it. Requests in flight through handles
+    /// returned by `arm` and `gns` fail as if the connection was lost.
+    pub async fn shutdown(&self) {
+        self.inner.shut_down.store(true, Ordering::SeqCst);
+        if let Some(client) = self.inner.arm.lock().await.take() {
+            client.close();
+        }
+        if let Some(handle) = self.inner.gns.lock().await.take() {
+            handle.close();
+        }
+        self.inner.identity.lock().await.take();
+        self.inner.peerinfo.lock().await.take();
+    }
+
+    /// Lock `slot`, connecting first if it doesn't hold a usable client.
+    async fn open<'a, C, F, Fut, E>(
+        &'a self,
+        slot: &Slot<C>,
+        connect: F,
+    ) -> Result<ClientGuard<C>, PeerError>
+    where
+        C: Cached,
+        F: FnOnce(&'a Config) -> Fut,
+        Fut: Future<Output = Result<C, E>>,
+        PeerError: From<E>,
+    {
+        let mut guard = slot.clone().lock_owned().await;
+        if self.inner.shut_down.load(Ordering::SeqCst) {
+            return Err(PeerError::ShutDown);
+        }
+        if !guard.as_ref().is_some_and(C::is_usable) {
+            *guard = Some(connect(&self.inner.cfg).await?);
+        }
+        Ok(ClientGuard { guard })
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_peer() {
+    use crate::testing::{message_bytes, MockService};
+    use crate::util::{MessageHeader, MessageType};
+
+    let info_end = message_bytes(&MessageHeader::new(4, MessageType::PEERINFO_INFO_END));
+    let mut mock = MockService::bind("peerinfo").await.unwrap();
+    mock.on(MessageType::PEERINFO_GET_ALL, vec![info_end]);
+    let peer = Peer::new(mock.config());
+    let path = mock.path().to_path_buf();
+    let mock = mock.spawn();
+
+    // Nothing is connected until it is used, so services missing from the config don't matter.
+    assert!(peer
+        .peerinfo()
+        .await
+        .unwrap()
+        .all_peers()
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Later uses, from any clone, share the connection.
+    std::fs::remove_file(&path).unwrap();
+    let clone = peer.clone();
+    assert!(clone
+        .peerinfo()
+        .await
+        .unwrap()
+        .all_peers()
+        .await
+        .unwrap()
+        .is_empty());
+    match peer.gns().await {
+        Err(PeerError::Connect { .. }) => (),
+        r => panic!("expected Connect, got {:?}", r.map(|_| ())),
+    }
+
+    peer.shutdown().await;
+    match clone.peerinfo().await {
+        Err(PeerError::ShutDown) => (),
+        r => panic!("expected ShutDown, got {:?}", r.map(|_| ())),
+    }
+    let received = mock.finish().await.unwrap();
+    assert_eq!(received.len(), 2);
+}
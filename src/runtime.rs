@@ -0,0 +1,261 @@
+//! The async runtime used for sockets, timers and background tasks.
+//!
+//! Exactly one of the `async-std` (the default) and `tokio` cargo features must be enabled. The
+//! socket types re-exported here, and accepted by eg. `service::Connection::from_stream`, are
+//! those of the selected runtime.
+
+#[cfg(all(feature = "async-std", feature = "tokio"))]
+compile_error!("The `async-std` and `tokio` features are mutually exclusive.");
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("One of the `async-std` and `tokio` features must be enabled.");
+
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::io;
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::time::Duration;
+
+/// The reading side of a socket.
+pub type BoxRead = Box<dyn AsyncRead + Send + Unpin>;
+/// The writing side of a socket. Closing it shuts down the socket for writing.
+pub type BoxWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[cfg(feature = "async-std")]
+mod imp {
+    use super::*;
+    pub use async_std::net::{TcpListener, TcpStream};
+    pub use async_std::os::unix::net::{UnixListener, UnixStream};
+
+    pub fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_std::task::spawn(fut);
+    }
+
+    pub async fn sleep(d: Duration) {
+        async_std::task::sleep(d).await
+    }
+
+    pub fn block_on<F: Future>(fut: F) -> F::Output {
+        async_std::task::block_on(fut)
+    }
+
+    pub async fn bind_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<UnixListener> {
+        UnixListener::bind(path.as_ref()).await
+    }
+
+    pub fn split_unix(s: UnixStream) -> (BoxRead, BoxWrite) {
+        (Box::new(s.clone()), Box::new(s))
+    }
+
+    pub fn unix_listener_from_std(l: StdUnixListener) -> io::Result<UnixListener> {
+        Ok(l.into())
+    }
+
+    pub fn tcp_listener_from_std(l: StdTcpListener) -> io::Result<TcpListener> {
+        Ok(l.into())
+    }
+
+    pub fn unix_stream_from_std(s: StdUnixStream) -> io::Result<UnixStream> {
+        Ok(s.into())
+    }
+
+    pub fn tcp_stream_from_std(s: StdTcpStream) -> io::Result<TcpStream> {
+        Ok(s.into())
+    }
+
+    pub fn split_tcp(s: TcpStream) -> (BoxRead, BoxWrite) {
+        (Box::new(s.clone()), Box::new(s))
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod imp {
+    use super::*;
+    use std::sync::OnceLock;
+    pub use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    pub fn spawn<F>(fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(fut);
+    }
+
+    pub async fn sleep(d: Duration) {
+        tokio::time::sleep(d).await
+    }
+
+    /// Runs `fut` to completion on a runtime shared by all callers, so that sockets and background
+    /// tasks created by one call remain usable in the next.
+    pub fn block_on<F: Future>(fut: F) -> F::Output {
+        static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+        RUNTIME
+            .get_or_init(|| {
+                tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .thread_name("gnunet-runtime")
+                    .enable_all()
+                    .build()
+                    .expect("failed to start tokio runtime")
+            })
+            .block_on(fut)
+    }
+
+    pub async fn bind_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<UnixListener> {
+        UnixListener::bind(path)
+    }
+
+    pub fn split_unix(s: UnixStream) -> (BoxRead, BoxWrite) {
+        let (r, w) = s.into_split();
+        (Box::new(r.compat()), Box::new(w.compat_write()))
+    }
+
+    pub fn unix_listener_from_std(l: StdUnixListener) -> io::Result<UnixListener> {
+        UnixListener::from_std(l)
+    }
+
+    pub fn tcp_listener_from_std(l: StdTcpListener) -> io::Result<TcpListener> {
+        TcpListener::from_std(l)
+    }
+
+    pub fn unix_stream_from_std(s: StdUnixStream) -> io::Result<UnixStream> {
+        UnixStream::from_std(s)
+    }
+
+    pub fn tcp_stream_from_std(s: StdTcpStream) -> io::Result<TcpStream> {
+        TcpStream::from_std(s)
+    }
+
+    pub fn split_tcp(s: TcpStream) -> (BoxRead, BoxWrite) {
+        let (r, w) = s.into_split();
+        (Box::new(r.compat()), Box::new(w.compat_write()))
+    }
+}
+
+pub use imp::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Run `fut` in the background.
+pub fn spawn<F>(fut: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    imp::spawn(fut)
+}
+
+/// Wait for `d` to pass.
+pub async fn sleep(d: Duration) {
+    imp::sleep(d).await
+}
+
+/// Error returned by `timeout` when the time limit passes before the future completes.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("Timed out after {0:?}")]
+pub struct Elapsed(pub Duration);
+
+/// Wait for `fut` to complete, giving up once `d` has passed.
+///
+/// If the time limit passes first `fut` is dropped, cancelling it.
+pub async fn timeout<F: Future>(d: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    futures::pin_mut!(fut);
+    let timer = sleep(d);
+    futures::pin_mut!(timer);
+    match future::select(fut, timer).await {
+        Either::Left((out, _)) => Ok(out),
+        Either::Right(((), _)) => Err(Elapsed(d)),
+    }
+}
+
+/// Block the current thread until `fut` completes.
+///
+/// Must not be called from within an async task.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    imp::block_on(fut)
+}
+
+/// Create a listener bound to the UNIX socket at `path`.
+pub async fn bind_unix<P: AsRef<std::path::Path>>(path: P) -> io::Result<UnixListener> {
+    imp::bind_unix(path).await
+}
+
+/// Connect to the UNIX socket named `name` in Linux's abstract namespace.
+///
+/// Must be called from within an async task.
+pub fn connect_unix_abstract(name: &[u8]) -> io::Result<UnixStream> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        unix_stream_from_std(StdUnixStream::connect_addr(&addr)?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = name;
+        Err(abstract_unsupported())
+    }
+}
+
+/// Create a listener bound to the UNIX socket named `name` in Linux's abstract namespace.
+///
+/// Must be called from within an async task.
+pub fn bind_unix_abstract(name: &[u8]) -> io::Result<UnixListener> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        unix_listener_from_std(StdUnixListener::bind_addr(&addr)?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = name;
+        Err(abstract_unsupported())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract UNIX sockets are only supported on Linux",
+    )
+}
+
+/// Split a UNIX socket into its reading and writing sides.
+pub fn split_unix(s: UnixStream) -> (BoxRead, BoxWrite) {
+    imp::split_unix(s)
+}
+
+/// Split a TCP socket into its reading and writing sides.
+pub fn split_tcp(s: TcpStream) -> (BoxRead, BoxWrite) {
+    imp::split_tcp(s)
+}
+
+/// Use a listening UNIX socket created with `std`, eg. one inherited from a parent process.
+///
+/// Must be called from within an async task.
+pub fn unix_listener_from_std(l: StdUnixListener) -> io::Result<UnixListener> {
+    l.set_nonblocking(true)?;
+    imp::unix_listener_from_std(l)
+}
+
+/// Use a listening TCP socket created with `std`. See `unix_listener_from_std`.
+pub fn tcp_listener_from_std(l: StdTcpListener) -> io::Result<TcpListener> {
+    l.set_nonblocking(true)?;
+    imp::tcp_listener_from_std(l)
+}
+
+/// Use a connected UNIX socket created with `std`. See `unix_listener_from_std`.
+pub fn unix_stream_from_std(s: StdUnixStream) -> io::Result<UnixStream> {
+    s.set_nonblocking(true)?;
+    imp::unix_stream_from_std(s)
+}
+
+/// Use a connected TCP socket created with `std`. See `unix_listener_from_std`.
+pub fn tcp_stream_from_std(s: StdTcpStream) -> io::Result<TcpStream> {
+    s.set_nonblocking(true)?;
+    imp::tcp_stream_from_std(s)
+}
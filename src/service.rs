@@ -1,5 +1,21 @@
+mod access;
 pub mod connection;
 pub use connection::*;
+pub mod mux;
+pub use mux::{Events, Mux, Replies, Reply, Subscription};
+pub mod queue;
+pub use queue::{Envelope, MessageQueue, Priority};
+pub mod reconnect;
+pub use reconnect::{ConnectionEvent, ReconnectPolicy};
+pub mod request;
+pub use request::RequestError;
+pub mod server;
+pub use server::Server;
+pub mod trace;
+pub use trace::TraceWriter;
+pub mod transaction;
+pub use transaction::{Transaction, TransactionError};
+pub mod arm;
 pub mod cadet;
 // pub mod dht;
 pub mod gns;
@@ -0,0 +1,262 @@
+//! The access checks that services make on their clients, and that clients can make on services.
+//!
+//! See `service::server` and `service::connect` for the options they read.
+
+use crate::util::{config, Config};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+
+/// An option of a service's config section that couldn't be parsed.
+#[derive(Debug)]
+pub(crate) struct InvalidOption {
+    pub(crate) name: String,
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+/// Which processes and addresses are trusted, from the `UNIX_MATCH_*` and `ACCEPT_FROM*` options.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AccessPolicy {
+    pub(crate) match_uid: bool,
+    pub(crate) match_gid: bool,
+    accept_from: Option<Vec<Network>>,
+    accept_from6: Option<Vec<Network>>,
+}
+
+impl AccessPolicy {
+    pub(crate) fn from_config(cfg: &Config, name: &str) -> Result<AccessPolicy, InvalidOption> {
+        let networks = |key: &str, v6: bool| match cfg.get_string(name, key) {
+            Ok(value) => Network::parse_list(value, v6)
+                .map(Some)
+                .ok_or_else(|| invalid(name, key, value)),
+            Err(_) => Ok(None),
+        };
+        Ok(AccessPolicy {
+            accept_from: networks("ACCEPT_FROM", false)?,
+            accept_from6: networks("ACCEPT_FROM6", true)?,
+            ..AccessPolicy::unix_from_config(cfg, name)?
+        })
+    }
+
+    /// The policy of the `UNIX_MATCH_*` options alone, which trusts every address.
+    pub(crate) fn unix_from_config(
+        cfg: &Config,
+        name: &str,
+    ) -> Result<AccessPolicy, InvalidOption> {
+        let yes_no = |key: &str| match cfg.get_yes_no(name, key) {
+            Ok(b) => Ok(b),
+            Err(config::ConfigGetYesNoError::Parse { value }) => Err(invalid(name, key, &value)),
+            Err(_) => Ok(false),
+        };
+        Ok(AccessPolicy {
+            match_uid: yes_no("UNIX_MATCH_UID")?,
+            match_gid: yes_no("UNIX_MATCH_GID")?,
+            ..AccessPolicy::default()
+        })
+    }
+
+    /// Whether a UNIX client running as `ids`, a `(uid, gid)` pair, may connect. `ids` is `None`
+    /// if they couldn't be determined.
+    ///
+    /// Clients apply the same check to the process of the service they connect to.
+    pub(crate) fn allows_unix(&self, ids: Option<&(u32, u32)>) -> bool {
+        // see gnunet/src/util/service.c::check_unix_usercred
+        if !self.match_uid && !self.match_gid {
+            return true;
+        }
+        let (uid, gid) = match ids {
+            Some(&ids) => ids,
+            None => return false,
+        };
+        // SAFETY: these calls have no preconditions and can't fail.
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        (self.match_uid && uid == euid) || (self.match_gid && (gid == egid || in_groups(gid)))
+    }
+
+    /// Whether a TCP client connecting from `ip` may connect.
+    pub(crate) fn allows_ip(&self, ip: IpAddr) -> bool {
+        let allowed = match ip {
+            IpAddr::V4(_) => &self.accept_from,
+            IpAddr::V6(_) => &self.accept_from6,
+        };
+        match allowed {
+            Some(networks) => networks.iter().any(|n| n.contains(ip)),
+            None => true,
+        }
+    }
+}
+
+fn invalid(name: &str, key: &str, value: &str) -> InvalidOption {
+    InvalidOption {
+        name: name.to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// An IP network, eg. `10.0.0.0/8`, as a masked address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Network {
+    addr: u128,
+    mask: u128,
+    v6: bool,
+}
+
+impl Network {
+    /// Parse a list of networks separated by `;`, as found in `ACCEPT_FROM` (if `v6` is false) or
+    /// `ACCEPT_FROM6`.
+    fn parse_list(s: &str, v6: bool) -> Option<Vec<Network>> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(|n| {
+                if v6 {
+                    Network::parse6(n)
+                } else {
+                    Network::parse4(n)
+                }
+            })
+            .collect()
+    }
+
+    /// Parses `a.b.c.d`, `a.b.c.d/n` or `a.b.c.d/e.f.g.h`.
+    fn parse4(s: &str) -> Option<Network> {
+        let (addr, mask) = match s.split_once('/') {
+            Some((addr, mask)) => match mask.parse::<Ipv4Addr>() {
+                Ok(mask) => (addr, u32::from(mask)),
+                Err(_) => (addr, prefix_mask(mask.parse().ok()?, 32)? as u32),
+            },
+            None => (s, u32::MAX),
+        };
+        let addr = u32::from(addr.parse::<Ipv4Addr>().ok()?);
+        Some(Network {
+            addr: (addr & mask).into(),
+            mask: mask.into(),
+            v6: false,
+        })
+    }
+
+    /// Parses `addr` or `addr/n`.
+    fn parse6(s: &str) -> Option<Network> {
+        let (addr, mask) = match s.split_once('/') {
+            Some((addr, len)) => (addr, prefix_mask(len.parse().ok()?, 128)?),
+            None => (s, u128::MAX),
+        };
+        let addr = u128::from(addr.parse::<Ipv6Addr>().ok()?);
+        Some(Network {
+            addr: addr & mask,
+            mask,
+            v6: true,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) if !self.v6 => u128::from(u32::from(ip)) & self.mask == self.addr,
+            IpAddr::V6(ip) if self.v6 => u128::from(ip) & self.mask == self.addr,
+            _ => false,
+        }
+    }
+}
+
+/// The mask of the first `len` bits of a `bits`-bit address.
+fn prefix_mask(len: u32, bits: u32) -> Option<u128> {
+    if len > bits {
+        return None;
+    }
+    let all = u128::MAX >> (128 - bits);
+    Some(all & !(all.checked_shr(len).unwrap_or(0)))
+}
+
+/// The user and group IDs of the process at the other end of the UNIX socket `fd`.
+#[cfg(target_os = "linux")]
+pub(crate) fn peer_ids(fd: RawFd) -> Result<(u32, u32), io::Error> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes, and `len` is the size of `cred`.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((cred.uid, cred.gid))
+}
+
+/// The user and group IDs of the process at the other end of the UNIX socket `fd`.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn peer_ids(fd: RawFd) -> Result<(u32, u32), io::Error> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: `uid` and `gid` are valid for writes.
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((uid, gid))
+}
+
+/// Whether `gid` is one of the supplementary groups of this process.
+fn in_groups(gid: u32) -> bool {
+    // SAFETY: a null buffer of length 0 asks for the number of groups.
+    let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if n <= 0 {
+        return false;
+    }
+    let mut groups = vec![0; n as usize];
+    // SAFETY: `groups` has room for `n` groups.
+    let n = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+    n > 0 && groups[..n as usize].contains(&gid)
+}
+
+#[test]
+fn test_access_policy() {
+    let mut cfg = Config::empty();
+    cfg.set_string(
+        "dummy",
+        "ACCEPT_FROM",
+        "127.0.0.1; 10.0.0.0/8;192.168.0.0/255.255.0.0;".to_string(),
+    );
+    cfg.set_string("dummy", "ACCEPT_FROM6", "::1;fe80::/10;".to_string());
+    let policy = AccessPolicy::from_config(&cfg, "dummy").unwrap();
+    for (ip, allowed) in [
+        ("127.0.0.1", true),
+        ("127.0.0.2", false),
+        ("10.1.2.3", true),
+        ("11.0.0.1", false),
+        ("192.168.7.7", true),
+        ("192.169.0.1", false),
+        ("::1", true),
+        ("fe80::1", true),
+        ("fec0::1", false),
+        ("::ffff:127.0.0.1", false),
+    ] {
+        assert_eq!(policy.allows_ip(ip.parse().unwrap()), allowed, "{}", ip);
+    }
+    assert!(policy.allows_unix(None));
+
+    cfg.set_string("dummy", "ACCEPT_FROM", "10.0.0.0/33;".to_string());
+    assert!(matches!(
+        AccessPolicy::from_config(&cfg, "dummy"),
+        Err(InvalidOption { .. })
+    ));
+
+    let mut cfg = Config::empty();
+    cfg.set_string("dummy", "UNIX_MATCH_UID", "YES".to_string());
+    cfg.set_string("dummy", "ACCEPT_FROM", "10.0.0.0/8;".to_string());
+    let policy = AccessPolicy::unix_from_config(&cfg, "dummy").unwrap();
+    let euid = unsafe { libc::geteuid() };
+    assert!(policy.allows_ip("8.8.8.8".parse().unwrap()));
+    assert!(policy.allows_unix(Some(&(euid, u32::MAX))));
+    assert!(!policy.allows_unix(Some(&(euid.wrapping_add(1), u32::MAX))));
+    assert!(!policy.allows_unix(None));
+}
@@ -0,0 +1,419 @@
+//! Module for controlling the GNUnet Automatic Restart Manager (ARM), which starts, stops and
+//! monitors the other services of a peer.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{Stream, StreamExt};
+use tracing::debug;
+
+use crate::runtime::Elapsed;
+use crate::service::{self, request, ReconnectPolicy, Reply};
+use crate::util::serial::{try_cast_prefix, Buffer};
+use crate::util::{expect, Config, ExpectError, MessageHeader, MessageOutCompound, MessageType};
+
+pub mod msg;
+
+/// The result of a request to start or stop a service.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArmResult {
+    /// The service was stopped.
+    Stopped,
+    /// The service is being stopped.
+    Stopping,
+    /// The service was already being started.
+    IsStartingAlready,
+    /// The service was already being stopped.
+    IsStoppingAlready,
+    /// The service was already running.
+    IsStartedAlready,
+    /// The service was already stopped.
+    IsStoppedAlready,
+    /// ARM doesn't know of a service by that name.
+    IsNotKnown,
+    /// The service failed to start.
+    StartFailed,
+    /// ARM is shutting down, so won't start the service.
+    InShutdown,
+    /// The service is being started.
+    Starting,
+}
+
+impl ArmResult {
+    pub fn from_u32(code: u32) -> Option<ArmResult> {
+        use ArmResult::*;
+        Some(match code {
+            0 => Stopped,
+            1 => Stopping,
+            2 => IsStartingAlready,
+            3 => IsStoppingAlready,
+            4 => IsStartedAlready,
+            5 => IsStoppedAlready,
+            6 => IsNotKnown,
+            7 => StartFailed,
+            8 => InShutdown,
+            9 => Starting,
+            _ => return None,
+        })
+    }
+
+    /// Whether the request failed, rather than the service ending up in the requested state.
+    pub fn is_failure(self) -> bool {
+        matches!(
+            self,
+            ArmResult::IsNotKnown | ArmResult::StartFailed | ArmResult::InShutdown
+        )
+    }
+}
+
+impl fmt::Display for ArmResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ArmResult::Stopped => "stopped",
+            ArmResult::Stopping => "stopping",
+            ArmResult::IsStartingAlready => "already starting",
+            ArmResult::IsStoppingAlready => "already stopping",
+            ArmResult::IsStartedAlready => "already started",
+            ArmResult::IsStoppedAlready => "already stopped",
+            ArmResult::IsNotKnown => "not known to ARM",
+            ArmResult::StartFailed => "failed to start",
+            ArmResult::InShutdown => "ARM is shutting down",
+            ArmResult::Starting => "starting",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The status of a service, as reported by `monitor`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// Sent with the name of the ARM service itself once monitoring has begun.
+    MonitoringStarted,
+    /// The service is being stopped.
+    Stopping,
+    /// The service has stopped.
+    Stopped,
+    /// The service is being started.
+    Starting,
+}
+
+impl ServiceStatus {
+    pub fn from_u32(code: u32) -> Option<ServiceStatus> {
+        Some(match code {
+            0 => ServiceStatus::MonitoringStarted,
+            1 => ServiceStatus::Stopping,
+            2 => ServiceStatus::Stopped,
+            3 => ServiceStatus::Starting,
+            _ => return None,
+        })
+    }
+}
+
+/// A change in the status of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusEvent {
+    pub service: String,
+    pub status: ServiceStatus,
+}
+
+/// Errors returned by the ARM client.
+#[derive(Debug, Error)]
+pub enum ArmError {
+    #[error("The service name '{name}' is too long.")]
+    NameTooLong { name: String },
+    #[error("ARM responded with the unknown result code {code}.")]
+    UnknownResult { code: u32 },
+    #[error("Unexpected response. Error: {source}")]
+    UnexpectedResponse {
+        #[from]
+        source: ExpectError,
+    },
+    #[error("There was an I/O error communicating with the ARM service. Specifically: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("The service disconnected unexpectedly")]
+    Disconnected,
+    #[error("Failed to connect to the ARM service. Reason: {source}")]
+    Connect {
+        #[from]
+        source: service::ConnectError,
+    },
+    #[error("The request did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+}
+
+/// A cloneable handle to the ARM service. Requests from all clones can be in flight at once.
+#[derive(Clone)]
+pub struct Client {
+    mux: service::Mux,
+    next_id: Arc<AtomicU64>,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    /// Connect to the ARM service.
+    pub async fn connect(cfg: &Config) -> Result<Client, service::ConnectError> {
+        let conn = service::connect(cfg, "arm").await?;
+        Ok(Client {
+            mux: service::Mux::new(conn),
+            next_id: Arc::new(AtomicU64::new(0)),
+            timeout: None,
+        })
+    }
+
+    /// Limit how long each later request through this clone of the client may take. `None`, the
+    /// default, means requests may wait forever. A request that times out is cancelled.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// The limit set by `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns `false` once the connection to the service has been lost.
+    pub fn is_connected(&self) -> bool {
+        self.mux.is_connected()
+    }
+
+    /// Close the connection for every clone of the client. Requests in flight fail.
+    pub fn close(&self) {
+        self.mux.close()
+    }
+
+    /// Ask ARM to start the service `name`.
+    ///
+    /// A successful request returns `Starting`, or one of the `Is...Already` results.
+    pub async fn start(&self, name: &str) -> Result<ArmResult, ArmError> {
+        let id = self.next_id();
+        let msg = msg::Start::new(id, name).ok_or_else(|| ArmError::NameTooLong {
+            name: name.to_string(),
+        })?;
+        self.request_result(id, &msg).await
+    }
+
+    /// Ask ARM to stop the service `name`.
+    ///
+    /// A successful request returns `Stopped`, `Stopping`, or one of the `Is...Already` results.
+    /// Stopping `arm` itself shuts down the whole peer, after which this client is disconnected.
+    pub async fn stop(&self, name: &str) -> Result<ArmResult, ArmError> {
+        let id = self.next_id();
+        let msg = msg::Stop::new(id, name).ok_or_else(|| ArmError::NameTooLong {
+            name: name.to_string(),
+        })?;
+        self.request_result(id, &msg).await
+    }
+
+    /// List the services that ARM is currently running.
+    ///
+    /// Each entry is the name of a service, followed by the binary running it in parentheses.
+    pub async fn list(&self) -> Result<Vec<String>, ArmError> {
+        let id = self.next_id();
+        let (typ, buf) = self
+            .request(&msg::List::new(id), MessageType::ARM_LIST_RESULT, id)
+            .await?;
+        Ok(expect::<msg::ListResult>(typ, &buf)?.services)
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    async fn request_result<M>(&self, id: u64, msg: M) -> Result<ArmResult, ArmError>
+    where
+        M: MessageOutCompound,
+    {
+        let (typ, buf) = self.request(msg, MessageType::ARM_RESULT, id).await?;
+        let code = expect::<msg::ArmResultMessage>(typ, &buf)?
+            .prefix
+            .result
+            .get();
+        ArmResult::from_u32(code).ok_or(ArmError::UnknownResult { code })
+    }
+
+    async fn request<M>(
+        &self,
+        msg: M,
+        reply_type: MessageType,
+        id: u64,
+    ) -> Result<(u16, Buffer), ArmError>
+    where
+        M: MessageOutCompound,
+    {
+        let reply = Reply::one_of(&[reply_type]).filter(move |buf| {
+            try_cast_prefix::<msg::ArmMessage>(buf).map(|(p, _)| p.request_id.get()) == Some(id)
+        });
+        request::with_timeout(self.timeout, self.mux.request_one(msg, reply))
+            .await
+            .map_err(|Elapsed(timeout)| ArmError::TimedOut { timeout })?
+            .map_err(|e| {
+                if self.mux.is_connected() {
+                    ArmError::Io { source: e }
+                } else {
+                    ArmError::Disconnected
+                }
+            })
+    }
+}
+
+/// Watch the status of the services run by ARM.
+///
+/// If the connection is lost it is re-established as described by `policy`. ARM then reports
+/// `MonitoringStarted` again, but doesn't repeat the changes that happened while disconnected.
+pub async fn monitor(cfg: &Config, policy: ReconnectPolicy) -> Result<Monitor, ArmError> {
+    let mux = service::Mux::reconnecting(cfg, "arm", policy).await?;
+    let statuses = mux.subscribe(&[MessageType::ARM_STATUS])?;
+    mux.register(&MessageHeader::new(4, MessageType::ARM_MONITOR))?;
+    Ok(Monitor { mux, statuses })
+}
+
+/// A stream of service status changes from ARM, created by `monitor`.
+pub struct Monitor {
+    mux: service::Mux,
+    statuses: service::Subscription,
+}
+
+impl Monitor {
+    /// Receive events about the state of the connection to the service.
+    pub fn events(&self) -> Result<service::Events, io::Error> {
+        self.mux.events()
+    }
+}
+
+impl Stream for Monitor {
+    type Item = StatusEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StatusEvent>> {
+        loop {
+            let (typ, buf) = match self.statuses.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let status = match expect::<msg::Status<&str>>(typ, &buf) {
+                Ok(status) => status,
+                Err(e) => {
+                    debug!("ignoring status message: {}", e);
+                    continue;
+                }
+            };
+            match ServiceStatus::from_u32(status.prefix.status.get()) {
+                Some(s) => {
+                    return Poll::Ready(Some(StatusEvent {
+                        service: status.service.to_string(),
+                        status: s,
+                    }))
+                }
+                None => debug!(
+                    service = status.service,
+                    "ignoring unknown status {}",
+                    status.prefix.status.get()
+                ),
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_arm() {
+    use crate::testing::MockService;
+
+    fn result(req: &[u8], code: u32) -> Vec<Vec<u8>> {
+        let mut b = vec![0, 20, 0, 10]; // header: len 20, ARM_RESULT
+        b.extend_from_slice(&req[4..16]); // reserved, request_id
+        b.extend_from_slice(&code.to_be_bytes());
+        vec![b]
+    }
+
+    fn status(code: u8, name: &[u8]) -> Vec<u8> {
+        let mut b = vec![0, 8 + name.len() as u8, 0, 11, 0, 0, 0, code]; // ARM_STATUS
+        b.extend_from_slice(name);
+        b
+    }
+
+    let mut mock = MockService::bind("arm").await.unwrap();
+    mock.expect_with(MessageType::ARM_START, |req| {
+        assert_eq!(&req[16..], b"gns\0");
+        result(req, 9)
+    })
+    .expect_with(MessageType::ARM_STOP, |req| result(req, 6))
+    .expect_with(MessageType::ARM_LIST, |req| {
+        let mut b = vec![0, 0, 0, 13]; // ARM_LIST_RESULT
+        b.extend_from_slice(&req[4..16]);
+        b.extend_from_slice(&[0, 2, 0, 0]); // count, reserved
+        b.extend_from_slice(b"arm (gnunet-service-arm)\0gns (gnunet-service-gns)\0");
+        b[1] = b.len() as u8;
+        vec![b]
+    })
+    .expect(
+        MessageType::ARM_MONITOR,
+        vec![status(0, b"arm\0"), status(3, b"gns\0")],
+    );
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let client = Client::connect(&cfg).await.unwrap();
+    assert_eq!(client.start("gns").await.unwrap(), ArmResult::Starting);
+    assert_eq!(client.stop("nope").await.unwrap(), ArmResult::IsNotKnown);
+    assert_eq!(
+        client.list().await.unwrap(),
+        ["arm (gnunet-service-arm)", "gns (gnunet-service-gns)"]
+    );
+
+    let monitor = monitor(&cfg, ReconnectPolicy::default()).await.unwrap();
+    let events: Vec<_> = monitor.take(2).collect().await;
+    assert_eq!(
+        events,
+        [
+            StatusEvent {
+                service: "arm".to_string(),
+                status: ServiceStatus::MonitoringStarted,
+            },
+            StatusEvent {
+                service: "gns".to_string(),
+                status: ServiceStatus::Starting,
+            },
+        ]
+    );
+    mock.finish().await.unwrap();
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_timeout() {
+    use crate::testing::MockService;
+
+    // The first request is never answered.
+    let mut mock = MockService::bind("arm").await.unwrap();
+    mock.expect(MessageType::ARM_START, vec![])
+        .expect_with(MessageType::ARM_START, |req| {
+            let mut b = vec![0, 20, 0, 10]; // header: len 20, ARM_RESULT
+            b.extend_from_slice(&req[4..16]); // reserved, request_id
+            b.extend_from_slice(&4u32.to_be_bytes()); // IsStartedAlready
+            vec![b]
+        });
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut client = Client::connect(&cfg).await.unwrap();
+    client.set_timeout(Some(Duration::from_millis(50)));
+    match client.start("gns").await {
+        Err(ArmError::TimedOut { timeout }) => assert_eq!(timeout, Duration::from_millis(50)),
+        r => panic!("expected TimedOut, got {:?}", r),
+    }
+    // The client is still usable.
+    assert_eq!(
+        client.start("gns").await.unwrap(),
+        ArmResult::IsStartedAlready
+    );
+    mock.finish().await.unwrap();
+}
@@ -0,0 +1,166 @@
+use super::ArmResult;
+use crate::util::serial::*;
+use crate::util::{GnunetMessage, MessageHeader, MessageIn, MessageType};
+
+/// The start of every request to ARM, and of its replies.
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct ArmMessage {
+    pub header: MessageHeader,
+    reserved: u32be, // always zero
+    pub request_id: u64be,
+}
+
+impl ArmMessage {
+    pub fn new(msg_type: MessageType, request_id: u64) -> ArmMessage {
+        ArmMessage {
+            header: MessageHeader::new(0, msg_type),
+            reserved: U32::ZERO,
+            request_id: U64::new(request_id),
+        }
+    }
+}
+
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = ARM_START)]
+pub struct Start<S> {
+    #[gnunet(prefix)]
+    pub prefix: ArmMessage,
+    #[gnunet(cstr)]
+    pub service: S,
+}
+
+impl<S: AsRef<str>> Start<S> {
+    pub fn new(request_id: u64, service: S) -> Option<Self> {
+        Start {
+            prefix: ArmMessage::new(MessageType::ARM_START, request_id),
+            service,
+        }
+        .fill_header()
+    }
+}
+
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = ARM_STOP)]
+pub struct Stop<S> {
+    #[gnunet(prefix)]
+    pub prefix: ArmMessage,
+    #[gnunet(cstr)]
+    pub service: S,
+}
+
+impl<S: AsRef<str>> Stop<S> {
+    pub fn new(request_id: u64, service: S) -> Option<Self> {
+        Stop {
+            prefix: ArmMessage::new(MessageType::ARM_STOP, request_id),
+            service,
+        }
+        .fill_header()
+    }
+}
+
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = ARM_LIST)]
+pub struct List {
+    #[gnunet(prefix)]
+    pub prefix: ArmMessage,
+}
+
+impl List {
+    pub fn new(request_id: u64) -> List {
+        List {
+            prefix: ArmMessage::new(MessageType::ARM_LIST, request_id),
+        }
+        .fill_header()
+        .unwrap()
+    }
+}
+
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct ResultPrefix {
+    pub header: MessageHeader,
+    reserved: u32be,
+    pub request_id: u64be,
+    pub result: u32be,
+}
+
+/// The reply to `Start` and `Stop`.
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = ARM_RESULT)]
+pub struct ArmResultMessage {
+    #[gnunet(prefix)]
+    pub prefix: ResultPrefix,
+}
+
+impl ArmResultMessage {
+    pub fn new(request_id: u64, result: ArmResult) -> ArmResultMessage {
+        ArmResultMessage {
+            prefix: ResultPrefix {
+                header: MessageHeader::new(0, MessageType::ARM_RESULT),
+                reserved: U32::ZERO,
+                request_id: U64::new(request_id),
+                result: U32::new(result as u32),
+            },
+        }
+        .fill_header()
+        .unwrap()
+    }
+}
+
+/// Followed by `count` null-terminated strings.
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct ListResultPrefix {
+    pub header: MessageHeader,
+    reserved: u32be,
+    pub request_id: u64be,
+    pub count: u16be,
+    reserved2: u16be,
+}
+
+/// The reply to `List`.
+pub struct ListResult {
+    pub prefix: ListResultPrefix,
+    pub services: Vec<String>,
+}
+
+impl MessageIn<'_> for ListResult {
+    fn msg_type() -> MessageType {
+        MessageType::ARM_LIST_RESULT
+    }
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        let (prefix, mut rest) = try_cast_prefix::<ListResultPrefix>(b)?;
+        let mut services = Vec::with_capacity(prefix.count.get() as usize);
+        for _ in 0..prefix.count.get() {
+            let (s, r) = parse_leading_cstr(rest)?;
+            services.push(s.to_string());
+            rest = r;
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(ListResult {
+            prefix: *prefix,
+            services,
+        })
+    }
+}
+
+/// Followed by the null-terminated name of the service.
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct StatusPrefix {
+    pub header: MessageHeader,
+    pub status: u32be,
+}
+
+/// A change in the status of a service, sent to clients that sent `ARM_MONITOR`.
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = ARM_STATUS)]
+pub struct Status<S> {
+    #[gnunet(prefix)]
+    pub prefix: StatusPrefix,
+    #[gnunet(cstr)]
+    pub service: S,
+}
@@ -1,14 +1,15 @@
 use std::io;
 
-use crate::service;
-use crate::util::{Config, PeerIdentity};
+use crate::service::{self, ReconnectPolicy};
+use crate::util::{Config, PeerIdentity, ProtocolVersion};
 
 pub mod msg;
 use msg::*;
 
 pub struct Client {
-    conn: service::Connection,
+    mux: service::Mux,
     next_id: u32,
+    version: ProtocolVersion,
 }
 
 pub struct Channel {
@@ -16,33 +17,100 @@ pub struct Channel {
 }
 
 impl Client {
+    /// Connect to the CADET service, and listen for channels on `listen_ports`. The ports are
+    /// opened in the layout of `ProtocolVersion::default()`; see `open_port`.
     pub async fn connect(
         cfg: &Config,
-        _listen_ports: Vec<u32>,
+        listen_ports: Vec<Port>,
     ) -> Result<Client, service::ConnectError> {
         let conn = service::connect(cfg, "cadet").await?;
-        Ok(Client { conn, next_id: 0 })
+        Client::listen(service::Mux::new(conn), listen_ports)
+    }
+
+    /// Connect like `connect`, and re-establish the connection whenever it is lost, as described
+    /// by `policy`. Every port opened, by `connect` or `open_port`, is opened again on each new
+    /// connection.
+    pub async fn connect_reconnecting(
+        cfg: &Config,
+        listen_ports: Vec<Port>,
+        policy: ReconnectPolicy,
+    ) -> Result<Client, service::ConnectError> {
+        let mux = service::Mux::reconnecting(cfg, "cadet", policy).await?;
+        Client::listen(mux, listen_ports)
+    }
+
+    fn listen(mux: service::Mux, listen_ports: Vec<Port>) -> Result<Client, service::ConnectError> {
+        let client = Client {
+            mux,
+            next_id: 0,
+            version: ProtocolVersion::default(),
+        };
+        for port in listen_ports {
+            client.open_port(port)?;
+        }
+        Ok(client)
+    }
+
+    /// Speak the protocol of GNUnet `version` from now on. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// The version set by `set_version`.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Receive events about the state of the connection to the service.
+    pub fn events(&self) -> Result<service::Events, io::Error> {
+        self.mux.events()
+    }
+
+    /// Listen for channels opened to `port`. As with `open_channel`, the kind of port must match
+    /// the version.
+    pub fn open_port(&self, port: Port) -> Result<(), io::Error> {
+        match (self.version, port) {
+            (ProtocolVersion::V0_10, Port::Number(port)) => {
+                self.mux.register(&LocalPortOpen::new(port))
+            }
+            (ProtocolVersion::V0_11, Port::Hash(port))
+            | (ProtocolVersion::V0_14, Port::Hash(port)) => {
+                self.mux.register(&LocalPortOpenV0_11::new(port))
+            }
+            (version, port) => Err(unsupported_port(version, port)),
+        }
     }
 
     // TODO: incoming message loop
-    // {
-    //   let msg_length: u16 = 4 + 4 * listen_ports.len() as u16; // TODO: check for overflow
-    //   let mut mw = service_writer.write_message(msg_length, MessageType::CADET_LOCAL_CONNECT);
-    //   for port in listen_ports.iter() {
-    //     mw.write_u32::<BigEndian>(*port).unwrap();
-    //   }
-    //   mw.send()?;
-    // }
 
     pub async fn connect_to_peer(
         &mut self,
         peer: &PeerIdentity,
         port: u32,
         opt: ChannelOptions,
+    ) -> Result<Channel, io::Error> {
+        self.open_channel(peer, Port::Number(port), opt).await
+    }
+
+    /// Open a channel to `port` on `peer`. GNUnet 0.10 only has numbered ports, and later
+    /// versions only have hashed ones; the wrong kind fails with an error of kind `InvalidInput`.
+    pub async fn open_channel(
+        &mut self,
+        peer: &PeerIdentity,
+        port: Port,
+        opt: ChannelOptions,
     ) -> Result<Channel, io::Error> {
         let id = self.next_channel_id();
-        let msg = LocalChannelCreate::new(id, *peer, port, opt);
-        self.conn.send(&msg).await?;
+        match (self.version, port) {
+            (ProtocolVersion::V0_10, Port::Number(port)) => self
+                .mux
+                .send(&LocalChannelCreate::new(id, *peer, port, opt))?,
+            (ProtocolVersion::V0_11, Port::Hash(port))
+            | (ProtocolVersion::V0_14, Port::Hash(port)) => self
+                .mux
+                .send(&LocalChannelCreateV0_11::new(id, *peer, port, opt))?,
+            (version, port) => return Err(unsupported_port(version, port)),
+        }
         // TODO: service response?
         Ok(Channel { id })
     }
@@ -53,3 +121,47 @@ impl Client {
         id
     }
 }
+
+fn unsupported_port(version: ProtocolVersion, port: Port) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("GNUnet {} doesn't support port {:?}", version, port),
+    )
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_reconnect() {
+    use crate::runtime;
+    use crate::testing::MockService;
+    use crate::util::MessageType;
+    use std::time::Duration;
+
+    let (first, second) = match ProtocolVersion::default() {
+        ProtocolVersion::V0_10 => (Port::Number(80), Port::Number(443)),
+        _ => (Port::from_name("http"), Port::from_name("https")),
+    };
+
+    let mut mock = MockService::bind("cadet").await.unwrap();
+    mock.expect(MessageType::CADET_LOCAL_PORT_OPEN, vec![])
+        .expect(MessageType::CADET_LOCAL_PORT_OPEN, vec![]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let policy = ReconnectPolicy::default();
+    let client = Client::connect_reconnecting(&cfg, vec![first], policy)
+        .await
+        .unwrap();
+    client.open_port(second).unwrap();
+    while mock.received().len() < 2 {
+        runtime::sleep(Duration::from_millis(1)).await;
+    }
+    let opened = mock.received();
+
+    // Both ports are opened again once the client has reconnected to the restarted service.
+    let mut mock = mock.restart().await.unwrap();
+    mock.expect(MessageType::CADET_LOCAL_PORT_OPEN, vec![])
+        .expect(MessageType::CADET_LOCAL_PORT_OPEN, vec![]);
+    let mock = mock.spawn();
+    assert_eq!(mock.finish().await.unwrap(), opened);
+}
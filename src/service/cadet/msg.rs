@@ -1,7 +1,36 @@
+use crate::crypto::HashCode;
 use crate::util::serial::*;
 use crate::util::{MessageHeader, MessageType, PeerIdentity};
 use std::convert::TryInto;
 
+/// A port on a peer that channels can be opened to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Port {
+    /// A port number, as used by GNUnet 0.10.
+    Number(u32),
+    /// A hash code, as used by GNUnet 0.11 and later.
+    Hash(HashCode),
+}
+
+impl Port {
+    /// The port that GNUnet 0.11 and later applications call `name`, which is its hash.
+    pub fn from_name(name: &str) -> Port {
+        Port::Hash(HashCode::from_buffer(name.as_bytes()))
+    }
+}
+
+impl From<u32> for Port {
+    fn from(port: u32) -> Port {
+        Port::Number(port)
+    }
+}
+
+impl From<HashCode> for Port {
+    fn from(port: HashCode) -> Port {
+        Port::Hash(port)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ChannelId(pub u32);
 
@@ -52,3 +81,116 @@ impl LocalChannelCreate {
         }
     }
 }
+
+/// `LocalChannelCreate` as of GNUnet 0.11, where the port is a hash code.
+#[derive(AsBytes)]
+#[repr(C)]
+pub struct LocalChannelCreateV0_11 {
+    header: MessageHeader,
+    id: u32be,
+    peer_id: PeerIdentity,
+    port: HashCode,
+    options: u32be,
+}
+
+impl LocalChannelCreateV0_11 {
+    pub fn new(
+        id: ChannelId,
+        peer_id: PeerIdentity,
+        port: HashCode,
+        options: ChannelOptions,
+    ) -> Self {
+        Self {
+            header: MessageHeader::new(
+                std::mem::size_of::<Self>().try_into().unwrap(),
+                MessageType::CADET_LOCAL_CHANNEL_CREATE,
+            ),
+            id: u32be::new(id.0),
+            peer_id,
+            port,
+            options: u32be::new(options.as_u32()),
+        }
+    }
+}
+
+/// Start listening for channels on a port numbered `port`, as in GNUnet 0.10.
+#[derive(AsBytes)]
+#[repr(C)]
+pub struct LocalPortOpen {
+    header: MessageHeader,
+    port: u32be,
+}
+
+impl LocalPortOpen {
+    pub fn new(port: u32) -> Self {
+        Self {
+            header: MessageHeader::new(
+                std::mem::size_of::<Self>().try_into().unwrap(),
+                MessageType::CADET_LOCAL_PORT_OPEN,
+            ),
+            port: u32be::new(port),
+        }
+    }
+}
+
+/// `LocalPortOpen` as of GNUnet 0.11, where the port is a hash code.
+#[derive(AsBytes)]
+#[repr(C)]
+pub struct LocalPortOpenV0_11 {
+    header: MessageHeader,
+    port: HashCode,
+}
+
+impl LocalPortOpenV0_11 {
+    pub fn new(port: HashCode) -> Self {
+        Self {
+            header: MessageHeader::new(
+                std::mem::size_of::<Self>().try_into().unwrap(),
+                MessageType::CADET_LOCAL_PORT_OPEN,
+            ),
+            port,
+        }
+    }
+}
+
+#[test]
+fn test_port_open_layout() {
+    let msg = LocalPortOpen::new(80);
+    let mut b = vec![0, 8, 3, 254]; // header: len 8, CADET_LOCAL_PORT_OPEN
+    b.extend_from_slice(&80u32.to_be_bytes());
+    assert_eq!(msg.as_bytes(), &b[..]);
+
+    let port = HashCode::from_buffer(b"http");
+    let msg = LocalPortOpenV0_11::new(port.clone());
+    let mut b = vec![0, 68, 3, 254]; // header: len 68, CADET_LOCAL_PORT_OPEN
+    b.extend_from_slice(port.as_bytes());
+    assert_eq!(msg.as_bytes(), &b[..]);
+}
+
+#[test]
+fn test_channel_create_layout() {
+    let peer = *cast::<PeerIdentity>(&[5; 32]);
+    let options = || ChannelOptions {
+        no_buffer: false,
+        reliable: true,
+        out_of_order: false,
+    };
+
+    let msg = LocalChannelCreate::new(ChannelId(3), peer, 80, options());
+    let mut b = vec![0, 48, 4, 0]; // header: len 48, CADET_LOCAL_CHANNEL_CREATE
+    b.extend_from_slice(&3u32.to_be_bytes()); // id
+    b.extend_from_slice(&[5; 32]); // peer_id
+    b.extend_from_slice(&80u32.to_be_bytes()); // port
+    b.extend_from_slice(&2u32.to_be_bytes()); // options: reliable
+    assert_eq!(msg.as_bytes(), &b[..]);
+
+    let port = HashCode::from_buffer(b"http");
+    let msg = LocalChannelCreateV0_11::new(ChannelId(3), peer, port.clone(), options());
+    let mut b = vec![0, 108, 4, 0]; // header: len 108, CADET_LOCAL_CHANNEL_CREATE
+    b.extend_from_slice(&3u32.to_be_bytes()); // id
+    b.extend_from_slice(&[5; 32]); // peer_id
+    b.extend_from_slice(port.as_bytes()); // port
+    b.extend_from_slice(&2u32.to_be_bytes()); // options: reliable
+    assert_eq!(msg.as_bytes(), &b[..]);
+    assert_eq!(Port::from_name("http"), Port::Hash(port));
+}
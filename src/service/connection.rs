@@ -1,50 +1,295 @@
 //! Module for communicating with GNUnet services. Implements the parts of the GNUnet IPC protocols
 //! that are common to all services.
 
+use super::access::{peer_ids, AccessPolicy, InvalidOption};
+use super::arm::{self, ArmError, ArmResult};
+use super::queue::MessageQueue;
+use super::trace::{Direction, TraceWriter};
+use super::transaction::{self, Request, Single, Transaction, TransactionError, Updates};
+use crate::runtime::{self, BoxRead, BoxWrite, TcpStream, UnixStream};
+use crate::util::codec::Framed;
 use crate::util::serial::*;
-use crate::util::{config, Config, MessageHeader, MessageOut, MessageOutCompound};
-use async_std::io;
-use async_std::os::unix::net::UnixStream;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use crate::util::{config, Config, MessageIn, MessageOut, MessageOutCompound};
+use futures::{future, SinkExt, Stream};
+use std::convert::TryInto;
 use std::fmt;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{debug, instrument};
 
 /// Attempt to connect to the local GNUnet service named `name`.
 ///
 /// eg. `connect(&cfg, "arm")` will attempt to connect to the locally-running `gnunet-arm` service
 /// using the congfiguration details (eg. socket address, port etc.) in `cfg`.
+///
+/// The service's `UNIXPATH` is preferred if it is configured. If it isn't, or the socket can't be
+/// connected to, the service's `HOSTNAME` and `PORT` are tried instead. On Linux, a `UNIXPATH`
+/// starting with `@` names a socket in the abstract namespace.
+///
+/// If the `GNUNET_RS_TRACE` environment variable is set, the connection is traced to the file it
+/// names. See `service::trace`.
 pub async fn connect(cfg: &Config, name: &str) -> Result<Connection, ConnectError> {
-    let path = cfg.get_filename(name, "UNIXPATH")?;
-    let sock = UnixStream::connect(&path).await?;
+    let mut conn = connect_untraced(cfg, name, None).await?;
+    conn.set_trace(TraceWriter::from_env());
+    Ok(conn)
+}
+
+/// Like `connect`, but before using the connection, check the service the way it checks its UNIX
+/// clients.
+///
+/// Anyone can create a socket in the abstract namespace, or one at a path in a directory they can
+/// write to. So the process behind the service's UNIX socket, as found with `SO_PEERCRED`, must
+/// match the `UNIX_MATCH_UID` or `UNIX_MATCH_GID` option of its config section; if neither is
+/// set, any process is trusted. A service that fails the check is reported as
+/// `ConnectError::Untrusted`. The process behind a TCP socket can't be identified, so the
+/// service is only connected to over UNIX, and a service without a `UNIXPATH` is reported as
+/// `ConnectError::Unverifiable`.
+pub async fn connect_verified(cfg: &Config, name: &str) -> Result<Connection, ConnectError> {
+    let policy = AccessPolicy::unix_from_config(cfg, name)?;
+    let mut conn = connect_untraced(cfg, name, Some(policy)).await?;
+    conn.set_trace(TraceWriter::from_env());
+    Ok(conn)
+}
 
+/// Connect to the service `name`. If `policy` is given, only over UNIX, to a process it allows.
+async fn connect_untraced(
+    cfg: &Config,
+    name: &str,
+    policy: Option<AccessPolicy>,
+) -> Result<Connection, ConnectError> {
     // see gnunet/src/util/client.c::start_connect
-    // TODO: tcp
+    let unixpath = UnixPath::from_config(cfg, name)?;
+    let tcp = match policy {
+        Some(_) if unixpath.is_none() => {
+            return Err(ConnectError::Unverifiable {
+                name: name.to_string(),
+            })
+        }
+        Some(_) => None,
+        None => tcp_address(cfg, name)?,
+    };
 
-    Ok(Connection {
+    let mut res = Err(ConnectError::NotConfigured {
         name: name.to_string(),
-        inner: sock,
+    });
+    if let Some(path) = unixpath {
+        match path.connect().await {
+            Ok(sock) => {
+                if let Some(policy) = &policy {
+                    let ids = peer_ids(sock.as_raw_fd());
+                    if !policy.allows_unix(ids.as_ref().ok()) {
+                        let found = match ids {
+                            Ok((uid, gid)) => format!("a process with uid {} and gid {}", uid, gid),
+                            Err(e) => format!("a process that couldn't be identified ({})", e),
+                        };
+                        return Err(ConnectError::Untrusted {
+                            name: name.to_string(),
+                            found,
+                        });
+                    }
+                }
+                return Ok(Connection::from_stream(name.to_string(), sock));
+            }
+            Err(e) => {
+                debug!(service = name, "failed to connect to {:?}: {}", path, e);
+                res = Err(e.into());
+            }
+        }
+    }
+    if let Some((hostname, port)) = tcp {
+        match TcpStream::connect((&*hostname, port)).await {
+            Ok(sock) => return Ok(Connection::from_tcp_stream(name.to_string(), sock)),
+            Err(e) => {
+                debug!(
+                    service = name,
+                    "failed to connect to {}:{}: {}", hostname, port, e
+                );
+                res = Err(e.into());
+            }
+        }
+    }
+    res
+}
+
+/// Like `connect`, but if the service isn't running, ask ARM to start it.
+///
+/// This is done if the service's socket doesn't exist or refuses the connection. Once ARM has
+/// agreed to start the service, the socket is retried until it accepts the connection. Gives up
+/// with `ConnectError::TimedOut` if the service can't be connected to within `timeout`.
+pub async fn connect_auto_start(
+    cfg: &Config,
+    name: &str,
+    timeout: Duration,
+) -> Result<Connection, ConnectError> {
+    let res = runtime::timeout(timeout, async {
+        match connect(cfg, name).await {
+            Err(ConnectError::Io { source }) if name != "arm" && is_not_running(&source) => {
+                debug!(
+                    service = name,
+                    "service not running, asking ARM to start it"
+                );
+            }
+            res => return res,
+        }
+        let arm = arm::Client::connect(cfg)
+            .await
+            .map_err(|e| ConnectError::AutoStart {
+                name: name.to_string(),
+                source: Box::new(e.into()),
+            })?;
+        let result = arm.start(name).await.map_err(|e| ConnectError::AutoStart {
+            name: name.to_string(),
+            source: Box::new(e),
+        })?;
+        if result.is_failure() {
+            return Err(ConnectError::StartFailed {
+                name: name.to_string(),
+                result,
+            });
+        }
+
+        let mut delay = Duration::from_millis(5);
+        loop {
+            match connect(cfg, name).await {
+                Err(ConnectError::Io { source }) if is_not_running(&source) => {
+                    runtime::sleep(delay).await;
+                    delay = (delay * 2).min(Duration::from_millis(500));
+                }
+                res => return res,
+            }
+        }
+    })
+    .await;
+    res.unwrap_or_else(|_| {
+        Err(ConnectError::TimedOut {
+            name: name.to_string(),
+            timeout,
+        })
     })
 }
 
+/// Whether `e`, from connecting to a service's socket, means that the service isn't running.
+fn is_not_running(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Where a service's UNIX socket is, from its `UNIXPATH` option.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum UnixPath {
+    File(PathBuf),
+    /// A name in Linux's abstract namespace, written in the config with a leading `@`.
+    Abstract(String),
+}
+
+impl UnixPath {
+    /// The service's `UNIXPATH`, if it is configured.
+    pub(crate) fn from_config(
+        cfg: &Config,
+        name: &str,
+    ) -> Result<Option<UnixPath>, config::ConfigGetFilenameError> {
+        let path = match cfg.get_filename(name, "UNIXPATH") {
+            Ok(path) => path,
+            Err(config::ConfigGetFilenameError::NoSection)
+            | Err(config::ConfigGetFilenameError::NoKey) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if path.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(abstract_name) => Ok(Some(UnixPath::Abstract(abstract_name.to_string()))),
+            None => Ok(Some(UnixPath::File(path))),
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> Result<UnixStream, io::Error> {
+        match self {
+            UnixPath::File(path) => UnixStream::connect(path).await,
+            UnixPath::Abstract(name) => runtime::connect_unix_abstract(name.as_bytes()),
+        }
+    }
+}
+
+/// The service's `HOSTNAME` and `PORT`, if both are configured.
+fn tcp_address(cfg: &Config, name: &str) -> Result<Option<(String, u16)>, ConnectError> {
+    let port = match cfg.get_int(name, "PORT") {
+        Ok(0)
+        | Err(config::ConfigGetIntError::NoSection)
+        | Err(config::ConfigGetIntError::NoKey) => return Ok(None),
+        Ok(port) => port,
+        Err(e) => return Err(e.into()),
+    };
+    let port = port
+        .try_into()
+        .map_err(|_| ConnectError::PortOutOfRange { port })?;
+    match cfg.get_string(name, "HOSTNAME") {
+        Ok(hostname) if !hostname.is_empty() => Ok(Some((hostname.to_string(), port))),
+        _ => Ok(None),
+    }
+}
+
 /// Error that can be generated when attempting to connect to a service.
 #[derive(Debug, Error)]
 pub enum ConnectError {
-    #[error("The configuration does not describe how to connect to the service.\nConfig does not contain an entry for UNIXPATH in the service's section: {source}")]
-    NotConfigured {
+    #[error("The configuration does not describe how to connect to the service.\nConfig section [{name}] contains neither UNIXPATH nor PORT and HOSTNAME")]
+    NotConfigured { name: String },
+    #[error("The service's UNIXPATH could not be determined. Reason: {source}")]
+    InvalidUnixPath {
         #[from]
         source: config::ConfigGetFilenameError,
     },
+    #[error("The service's PORT is not a valid port number. Reason: {source}")]
+    InvalidPort {
+        #[from]
+        source: config::ConfigGetIntError,
+    },
+    #[error("The service's PORT ({port}) is out of range")]
+    PortOutOfRange { port: u64 },
     #[error("There was an I/O error communicating with the service. Specifically {source}")]
     Io {
         #[from]
         source: io::Error,
     },
+    #[error("Failed to ask ARM to start the service '{name}'. Reason: {source}")]
+    AutoStart {
+        name: String,
+        #[source]
+        source: Box<ArmError>,
+    },
+    #[error("ARM could not start the service '{name}': {result}")]
+    StartFailed { name: String, result: ArmResult },
+    #[error("The service '{name}' could not be connected to within {timeout:?}")]
+    TimedOut { name: String, timeout: Duration },
+    #[error("The option {key} in config section [{name}] has an invalid value: '{value}'")]
+    InvalidOption {
+        name: String,
+        key: String,
+        value: String,
+    },
+    #[error("The service '{name}' was found at {found}, which its config section doesn't trust")]
+    Untrusted { name: String, found: String },
+    #[error("The service '{name}' can't be verified, since its config section has no UNIXPATH")]
+    Unverifiable { name: String },
+}
+
+impl From<InvalidOption> for ConnectError {
+    fn from(e: InvalidOption) -> ConnectError {
+        let InvalidOption { name, key, value } = e;
+        ConnectError::InvalidOption { name, key, value }
+    }
 }
 
 /// Created by `service::connect`. Used to read messages from a GNUnet service.
 pub struct Connection {
     name: String,
-    inner: UnixStream,
+    recv: Framed<BoxRead>,
+    send: Framed<BoxWrite>,
+    trace: Option<TraceWriter>,
 }
 
 impl Connection {
@@ -52,48 +297,262 @@ impl Connection {
     ///
     /// The message should not have a null-terminated string, otherwise use `send_with_str`.
     pub async fn send<M: MessageOut>(&mut self, msg: M) -> Result<(), io::Error> {
-        self.inner.write_all(&msg.as_bytes().as_ref()).await
+        send(&self.name, &mut self.send, &self.trace, msg).await
     }
 
     pub async fn send_compound<M: MessageOutCompound>(&mut self, msg: M) -> Result<(), io::Error> {
-        for chunk in msg.as_byte_chunks() {
-            self.inner.write_all(chunk.as_ref()).await?
-        }
-        Ok(())
+        send(&self.name, &mut self.send, &self.trace, msg).await
+    }
+
+    /// Buffer `msg` to be written by the next `flush` or send. Once this completes the whole
+    /// message will be written, even if a later flush is interrupted and has to be retried.
+    pub(crate) async fn feed<M: MessageOutCompound>(&mut self, msg: M) -> Result<(), io::Error> {
+        feed(&self.name, &mut self.send, &self.trace, msg).await
+    }
+
+    /// Write any buffered messages.
+    pub(crate) async fn flush(&mut self) -> Result<(), io::Error> {
+        SinkExt::<&[u8]>::flush(&mut self.send).await
     }
 
     /// Returns `(header, buffer)`, where `buffer` contains entire message payload
     /// (including the header), for ease of deserializing message structs.
+    ///
+    /// Messages with a malformed header, or that are longer than the maximum message size, are
+    /// rejected with an error of kind `InvalidData` (see `util::codec::FrameError`).
     #[instrument]
     pub async fn recv(&mut self) -> Result<(u16, Buffer), io::Error> {
-        let mut buf = Buffer::default();
-        buf.resize(4, 0u8);
+        let (typ, buf) = recv(&self.name, &mut self.recv, &self.trace).await?;
+        Ok((typ, Buffer::from_slice(buf)))
+    }
 
-        let head: MessageHeader = {
-            let mut head_bytes = &mut buf[0..4];
-            self.inner.read_exact(&mut head_bytes).await?;
-            *cast(head_bytes)
-        };
+    /// Like `recv`, but lends out the message from the connection's receive buffer instead of
+    /// copying it. The buffer is reused for every message, so receiving many messages this way
+    /// doesn't allocate. Messages can be parsed into borrowing types such as
+    /// `HelloMessage<&str, &[u8]>`.
+    #[instrument]
+    pub async fn recv_borrowed(&mut self) -> Result<(u16, &[u8]), io::Error> {
+        recv(&self.name, &mut self.recv, &self.trace).await
+    }
+
+    /// Send `req`, and receive its reply through the returned `Transaction`. See
+    /// `service::transaction`.
+    pub async fn transaction<R>(&mut self, req: &R) -> Result<Transaction<'_, R::Reply>, io::Error>
+    where
+        R: Request,
+        for<'r> &'r R: MessageOutCompound,
+    {
+        transaction::start(self, req).await
+    }
 
-        debug!(
-            typ = head.msg_type_u16(),
-            len = head.length(),
-            "type: {:?}",
-            head.msg_type(),
-        );
-
-        if head.length() > 4 {
-            buf.resize(head.length() as usize, 0u8);
-            let rest = &mut buf[4..];
-            self.inner.read_exact(rest).await?;
+    /// Send `req`, and receive its reply of a single message.
+    pub async fn query<R, M>(&mut self, req: &R) -> Result<M, TransactionError>
+    where
+        R: Request<Reply = Single<M>>,
+        for<'r> &'r R: MessageOutCompound,
+        M: for<'a> MessageIn<'a>,
+    {
+        let mut transaction = self.transaction(req).await?;
+        match transaction.next().await? {
+            Some(reply) => Ok(reply),
+            None => unreachable!("a single reply is never empty"),
         }
+    }
 
-        Ok((head.msg_type_u16(), buf))
+    /// Send `req`, and hand the connection to the returned stream of the updates it is answered
+    /// with. The stream ends after an I/O error.
+    pub async fn subscribe<R, M>(
+        mut self,
+        req: &R,
+    ) -> Result<impl Stream<Item = Result<M, TransactionError>>, io::Error>
+    where
+        R: Request<Reply = Updates<M>>,
+        for<'r> &'r R: MessageOutCompound,
+        M: for<'a> MessageIn<'a>,
+    {
+        self.send_compound(req).await?;
+        Ok(transaction::subscribe(self))
     }
 
     pub fn from_stream(name: String, inner: UnixStream) -> Self {
-        Connection { name, inner }
+        let (r, w) = runtime::split_unix(inner);
+        Connection::from_parts(name, r, w)
+    }
+
+    pub fn from_tcp_stream(name: String, inner: TcpStream) -> Self {
+        let (r, w) = runtime::split_tcp(inner);
+        Connection::from_parts(name, r, w)
+    }
+
+    /// Create a connection that reads messages from `r` and writes messages to `w`.
+    pub fn from_parts(name: String, r: BoxRead, w: BoxWrite) -> Self {
+        Connection {
+            name,
+            recv: Framed::new(r),
+            send: Framed::new(w),
+            trace: None,
+        }
     }
+
+    /// Record every message sent and received from now on to `trace`, or stop recording if it is
+    /// `None`. The records are marked with a connection number of their own, which is kept by both
+    /// halves if the connection is split.
+    pub fn set_trace(&mut self, trace: Option<TraceWriter>) {
+        self.trace = trace.as_ref().map(TraceWriter::new_connection);
+    }
+
+    /// The name of the service this connection is connected to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reject messages longer than `max` bytes, in either direction. Defaults to
+    /// `util::codec::MAX_MESSAGE_SIZE`.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.recv.set_max_message_size(max);
+        self.send.set_max_message_size(max);
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.send.max_message_size()
+    }
+
+    /// Split the connection into a receiving and a sending half, so that messages can be received
+    /// in one task while other tasks send requests.
+    pub fn split(self) -> (RecvHalf, SendHalf) {
+        (
+            RecvHalf {
+                name: self.name.clone(),
+                inner: self.recv,
+                trace: self.trace.clone(),
+            },
+            SendHalf {
+                name: self.name,
+                inner: self.send,
+                trace: self.trace,
+            },
+        )
+    }
+}
+
+/// The receiving half of a `Connection`, created by `Connection::split`.
+pub struct RecvHalf {
+    name: String,
+    inner: Framed<BoxRead>,
+    trace: Option<TraceWriter>,
+}
+
+impl RecvHalf {
+    /// See `Connection::recv`.
+    #[instrument]
+    pub async fn recv(&mut self) -> Result<(u16, Buffer), io::Error> {
+        let (typ, buf) = recv(&self.name, &mut self.inner, &self.trace).await?;
+        Ok((typ, Buffer::from_slice(buf)))
+    }
+
+    /// See `Connection::recv_borrowed`.
+    #[instrument]
+    pub async fn recv_borrowed(&mut self) -> Result<(u16, &[u8]), io::Error> {
+        recv(&self.name, &mut self.inner, &self.trace).await
+    }
+}
+
+impl fmt::Debug for RecvHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvHalf")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// The sending half of a `Connection`, created by `Connection::split`.
+pub struct SendHalf {
+    name: String,
+    inner: Framed<BoxWrite>,
+    trace: Option<TraceWriter>,
+}
+
+impl SendHalf {
+    /// See `Connection::send`.
+    pub async fn send<M: MessageOut>(&mut self, msg: M) -> Result<(), io::Error> {
+        send(&self.name, &mut self.inner, &self.trace, msg).await
+    }
+
+    /// See `Connection::send_compound`.
+    pub async fn send_compound<M: MessageOutCompound>(&mut self, msg: M) -> Result<(), io::Error> {
+        send(&self.name, &mut self.inner, &self.trace, msg).await
+    }
+
+    /// Shut down the sending side of the connection. The service will see the end of the stream
+    /// and close the connection, which ends any pending `RecvHalf::recv`.
+    pub async fn close(&mut self) -> Result<(), io::Error> {
+        SinkExt::<&[u8]>::close(&mut self.inner).await
+    }
+
+    /// The longest message that can be sent. See `Connection::set_max_message_size`.
+    pub fn max_message_size(&self) -> usize {
+        self.inner.max_message_size()
+    }
+
+    /// Hand the connection to a background task that sends the messages put in the returned
+    /// queue, which holds at most `capacity` of them. See `service::queue`.
+    pub fn into_queue(self, capacity: usize) -> MessageQueue {
+        MessageQueue::spawn(self, capacity)
+    }
+}
+
+impl fmt::Debug for SendHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendHalf")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+async fn recv<'a>(
+    name: &str,
+    framed: &'a mut Framed<BoxRead>,
+    trace: &Option<TraceWriter>,
+) -> Result<(u16, &'a [u8]), io::Error> {
+    let typ = match future::poll_fn(|cx| framed.poll_read_frame(cx)).await? {
+        Some(typ) => typ,
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+    };
+    let buf = framed.frame();
+    if let Some(trace) = trace {
+        trace.record(name, Direction::Received, buf);
+    }
+    Ok((typ, buf))
+}
+
+async fn send<M: MessageOutCompound>(
+    name: &str,
+    framed: &mut Framed<BoxWrite>,
+    trace: &Option<TraceWriter>,
+    msg: M,
+) -> Result<(), io::Error> {
+    feed(name, framed, trace, msg).await?;
+    SinkExt::<&[u8]>::flush(framed).await
+}
+
+async fn feed<M: MessageOutCompound>(
+    name: &str,
+    framed: &mut Framed<BoxWrite>,
+    trace: &Option<TraceWriter>,
+    msg: M,
+) -> Result<(), io::Error> {
+    let frame = trace.as_ref().map(|_| {
+        let mut frame = vec![];
+        for chunk in msg.as_byte_chunks() {
+            frame.extend_from_slice(chunk.as_ref());
+        }
+        frame
+    });
+    future::poll_fn(|cx| framed.poll_write_message(cx, &msg)).await?;
+    if let (Some(trace), Some(frame)) = (trace, frame) {
+        trace.record(name, Direction::Sent, &frame);
+    }
+    Ok(())
 }
 
 impl fmt::Debug for Connection {
@@ -104,11 +563,12 @@ impl fmt::Debug for Connection {
     }
 }
 
-#[async_std::test]
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
 async fn test_service() {
+    use crate::runtime::UnixStream;
     use crate::util::serial::*;
     use crate::util::{expect, MessageHeader, MessageIn, MessageType};
-    use async_std::os::unix::net::UnixStream;
     use std::mem::size_of;
 
     #[derive(AsBytes, FromBytes, Copy, Clone, PartialEq, Debug)]
@@ -150,3 +610,118 @@ async fn test_service() {
     let inmsg = expect::<DummyMsg>(typ, &buf).unwrap();
     assert_eq!(&inmsg, &outmsg);
 }
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_connect_tcp() {
+    use crate::runtime::TcpListener;
+    use crate::util::{MessageHeader, MessageType};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let cfg = format!(
+        "[dummy]\nUNIXPATH = /nonexistent/gnunet-service-dummy.sock\nPORT = {}\nHOSTNAME = 127.0.0.1\n",
+        port
+    );
+    let cfg = Config::deserialize(cfg.as_bytes(), false).unwrap();
+
+    let mut conn = connect(&cfg, "dummy").await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut service = Connection::from_tcp_stream("service".to_string(), sock);
+
+    conn.send(&MessageHeader::new(4, MessageType::DUMMY))
+        .await
+        .unwrap();
+    let (typ, buf) = service.recv().await.unwrap();
+    assert_eq!(typ, MessageType::DUMMY.to_u16());
+    assert_eq!(buf.len(), 4);
+
+    let cfg = Config::deserialize(&b"[dummy]\nPORT = 2086\n"[..], false).unwrap();
+    match connect(&cfg, "dummy").await {
+        Err(ConnectError::NotConfigured { name }) => assert_eq!(name, "dummy"),
+        r => panic!("expected NotConfigured, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_connect_verified() {
+    use crate::runtime::TcpListener;
+    use crate::service::Server;
+
+    // A service in the abstract namespace, running as this process's user.
+    let mut cfg = Config::empty();
+    let unixpath = format!("@gnunet-rs-test-verified-{}", std::process::id());
+    cfg.set_string("dummy", "UNIXPATH", unixpath);
+    cfg.set_string("dummy", "UNIX_MATCH_UID", "YES".to_string());
+    let listening = Server::new("dummy").bind(&cfg).await.unwrap();
+    assert!(listening.unix_path().is_none());
+    let _server = listening.spawn();
+    assert!(connect_verified(&cfg, "dummy").await.is_ok());
+
+    cfg.set_string("dummy", "UNIX_MATCH_UID", "maybe".to_string());
+    match connect_verified(&cfg, "dummy").await {
+        Err(ConnectError::InvalidOption { key, .. }) => assert_eq!(key, "UNIX_MATCH_UID"),
+        r => panic!("expected InvalidOption, got {:?}", r.map(|_| ())),
+    }
+
+    // A TCP service is connected to, but can't be verified.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut cfg = Config::empty();
+    cfg.set_string("dummy", "PORT", port.to_string());
+    cfg.set_string("dummy", "HOSTNAME", "127.0.0.1".to_string());
+    assert!(connect(&cfg, "dummy").await.is_ok());
+    match connect_verified(&cfg, "dummy").await {
+        Err(ConnectError::Unverifiable { name }) => assert_eq!(name, "dummy"),
+        r => panic!("expected Unverifiable, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_connect_auto_start() {
+    use crate::testing::MockService;
+    use crate::util::MessageType;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+
+    fn result(req: &[u8], code: u32) -> Vec<Vec<u8>> {
+        let mut b = vec![0, 20, 0, 10]; // header: len 20, ARM_RESULT
+        b.extend_from_slice(&req[4..16]); // reserved, request_id
+        b.extend_from_slice(&code.to_be_bytes());
+        vec![b]
+    }
+
+    let path =
+        std::env::temp_dir().join(format!("gnunet-rs-test-auto-start-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let started = Arc::new(Mutex::new(None));
+
+    let mut arm = MockService::bind("arm").await.unwrap();
+    let (p, s) = (path.clone(), started.clone());
+    arm.expect_with(MessageType::ARM_START, move |req| {
+        assert_eq!(&req[16..], b"dummy\0");
+        *s.lock().unwrap() = Some(UnixListener::bind(&p).unwrap());
+        result(req, ArmResult::Starting as u32)
+    })
+    .expect_with(MessageType::ARM_START, |req| {
+        result(req, ArmResult::IsNotKnown as u32)
+    });
+    let mut cfg = arm.config();
+    cfg.set_string("dummy", "UNIXPATH", path.to_string_lossy().into_owned());
+    let arm = arm.spawn();
+
+    let timeout = Duration::from_secs(5);
+    connect_auto_start(&cfg, "dummy", timeout).await.unwrap();
+    assert!(started.lock().unwrap().is_some());
+
+    drop(started.lock().unwrap().take());
+    std::fs::remove_file(&path).unwrap();
+    match connect_auto_start(&cfg, "dummy", timeout).await {
+        Err(ConnectError::StartFailed { result, .. }) => assert_eq!(result, ArmResult::IsNotKnown),
+        r => panic!("expected StartFailed, got {:?}", r.map(|_| ())),
+    }
+    arm.finish().await.unwrap();
+}
@@ -1,12 +1,19 @@
 //! Module for connecting to and querying the GNUnet GNS service.
 
 use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 pub use self::record::*;
 use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey};
-use crate::service;
-use crate::util::Config;
+use crate::runtime::Elapsed;
+use crate::service::request::{self, Exclusive, Resync};
+use crate::service::{self, Reply, RequestError};
+use crate::util::serial::try_cast_prefix;
+use crate::util::{expect, Config, ExpectError, MessageType, ProtocolVersion};
+use tracing::debug;
 
 pub mod msg;
 pub mod record;
@@ -16,8 +23,9 @@ pub const GNUNET_DNSPARSER_MAX_NAME_LENGTH: u16 = 253;
 
 /// A handle to a locally-running instance of the GNS daemon.
 pub struct Client {
-    conn: service::Connection,
+    conn: Exclusive,
     lookup_id: u32,
+    version: ProtocolVersion,
 }
 
 /// Possible errors returned by the GNS lookup functions.
@@ -27,11 +35,33 @@ pub enum LookupError {
     InvalidType { typ: u16 },
     #[error("The domain name '{name}' is too long to lookup.")]
     NameTooLong { name: String },
+    #[error("GNUnet {version} has no shorten zone")]
+    ShortenUnsupported { version: ProtocolVersion },
+    #[error("Unexpected response. Error: {source}")]
+    UnexpectedResponse {
+        #[from]
+        source: ExpectError,
+    },
     #[error("There was an I/O error communicating with the service. Specifically {source}")]
     Io {
         #[from]
         source: io::Error,
     },
+    #[error("The service disconnected unexpectedly")]
+    Disconnected,
+    #[error("The lookup did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("An earlier lookup failed part way through, so the client can no longer be used")]
+    Poisoned,
+}
+
+impl From<RequestError> for LookupError {
+    fn from(e: RequestError) -> LookupError {
+        match e {
+            RequestError::TimedOut { timeout } => LookupError::TimedOut { timeout },
+            RequestError::Poisoned => LookupError::Poisoned,
+        }
+    }
 }
 
 impl Client {
@@ -41,53 +71,316 @@ impl Client {
     /// configuration to use to connect to the service.
     pub async fn connect(cfg: &Config) -> Result<Client, service::ConnectError> {
         let conn = service::connect(cfg, "gns").await?;
-        Ok(Client { conn, lookup_id: 0 })
+        Ok(Client {
+            conn: Exclusive::new(conn, Resync::Discard),
+            lookup_id: 0,
+            version: ProtocolVersion::default(),
+        })
+    }
+
+    /// Speak the protocol of GNUnet `version` in later lookups. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// The version set by `set_version`.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Limit how long each later lookup may take. `None`, the default, means lookups may wait
+    /// forever. Results of a lookup that timed out are discarded by later lookups; see
+    /// `service::request`. Use a `LookupHandle` to have them cancelled instead.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.conn.set_timeout(timeout);
+    }
+
+    /// The limit set by `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.conn.timeout()
     }
 
     /// Lookup a vector of GNS records.
-    /// A promise of the result is returned.
-    ///
-    /// If `shorten` is not `None` then the result is added to the given shorten zone.
-    ///
-    /// # Example
     ///
+    /// `zone` is the public key of the zone to start the lookup in, typically the key of the
+    /// `gns-master` ego. If `shorten` is not `None` then the result is added to the given shorten
+    /// zone. Only GNUnet 0.10 has shorten zones; later versions fail with `ShortenUnsupported`.
     pub async fn lookup(
         &mut self,
         name: &str,
-        _zone: EcdsaPublicKey,
-        _record_type: RecordType,
-        _options: LocalOptions,
-        _shorten: Option<EcdsaPrivateKey>,
+        zone: EcdsaPublicKey,
+        record_type: RecordType,
+        options: LocalOptions,
+        shorten: Option<EcdsaPrivateKey>,
     ) -> Result<Vec<Record>, LookupError> {
-        if name.len() > GNUNET_DNSPARSER_MAX_NAME_LENGTH as usize {
-            return Err(LookupError::NameTooLong {
-                name: name.to_string(),
-            });
-        };
-        let _id = self.lookup_id;
-        self.lookup_id += 1;
-        // let msg = msg::Lookup::new(id, zone, options, shorten, record_type, &name);
+        let id = self.lookup_id;
+        let msg = lookup_msg(self.version, id, name, zone, record_type, options, shorten)?;
+        self.lookup_id = self.lookup_id.wrapping_add(1);
 
-        todo!()
+        // Service response:
+        //   One GNS_LOOKUP_RESULT msg with the same id as the request,
+        //   containing rd_count serialized records.
+        self.conn
+            .request(|mut conn| async move {
+                conn.send(&msg).await?;
+
+                loop {
+                    let (typ, buf) = conn.recv().await?;
+                    let res = expect::<msg::LookupResult>(typ, &buf)?;
+                    if res.id() == id {
+                        return Ok(res.records);
+                    }
+                    debug!(id = res.id(), "discarding result of an earlier lookup");
+                }
+            })
+            .await?
+    }
+
+    /// Turn this client into a `LookupHandle`, which allows many lookups to be in flight at once
+    /// over this connection.
+    pub fn into_handle(self) -> LookupHandle {
+        let timeout = self.conn.timeout();
+        let mut handle = LookupHandle::from_connection(self.conn.into_inner(), self.lookup_id);
+        handle.set_timeout(timeout);
+        handle.set_version(self.version);
+        handle
     }
 }
 
-/// Errors returned by `gns::lookup`.
-#[derive(Debug, Error)]
-pub enum ConnectLookupError {
-    #[error("Failed to connect to the GNS service. Reason: {source}")]
-    Connect {
-        #[from]
-        source: service::ConnectError,
-    },
-    #[error("Failed to perform the lookup.. Reason: {source}")]
-    Lookup {
-        #[from]
-        source: LookupError,
-    },
-    #[error("There was an I/O error communicating with the service. Specifically {source}")]
-    Io {
-        #[from]
-        source: io::Error,
-    },
+/// A cloneable handle to the GNS service that can have many lookups in flight at once.
+///
+/// Requests from all clones are sent over a single connection, and each `GNS_LOOKUP_RESULT` is
+/// routed back to its lookup by request id. Dropping a lookup future cancels the request; a result
+/// that arrives for it later is discarded. The connection is closed once every clone of the handle
+/// has been dropped.
+#[derive(Clone)]
+pub struct LookupHandle {
+    mux: service::Mux,
+    next_id: Arc<AtomicU32>,
+    timeout: Option<Duration>,
+    version: ProtocolVersion,
+}
+
+impl LookupHandle {
+    /// Connect to the GNS service.
+    pub async fn connect(cfg: &Config) -> Result<LookupHandle, service::ConnectError> {
+        Ok(Client::connect(cfg).await?.into_handle())
+    }
+
+    pub(crate) fn from_connection(conn: service::Connection, next_id: u32) -> LookupHandle {
+        LookupHandle {
+            mux: service::Mux::new(conn),
+            next_id: Arc::new(AtomicU32::new(next_id)),
+            timeout: None,
+            version: ProtocolVersion::default(),
+        }
+    }
+
+    /// Speak the protocol of GNUnet `version` in later lookups through this clone of the handle.
+    /// See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// The version set by `set_version`.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Limit how long each later lookup through this clone of the handle may take. `None`, the
+    /// default, means lookups may wait forever. A lookup that times out is cancelled.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// The limit set by `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Returns `false` once the connection to the service has been lost.
+    pub fn is_connected(&self) -> bool {
+        self.mux.is_connected()
+    }
+
+    /// Close the connection for every clone of the handle. Lookups in flight fail.
+    pub fn close(&self) {
+        self.mux.close()
+    }
+
+    /// Lookup a vector of GNS records. See `Client::lookup`.
+    ///
+    /// Dropping the returned future before it completes cancels the lookup.
+    pub async fn lookup(
+        &self,
+        name: &str,
+        zone: EcdsaPublicKey,
+        record_type: RecordType,
+        options: LocalOptions,
+        shorten: Option<EcdsaPrivateKey>,
+    ) -> Result<Vec<Record>, LookupError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let msg = lookup_msg(self.version, id, name, zone, record_type, options, shorten)?;
+
+        let reply = Reply::one_of(&[MessageType::GNS_LOOKUP_RESULT]).filter(move |buf| {
+            try_cast_prefix::<msg::LookupResultPrefix>(buf).map(|(p, _)| p.id.get()) == Some(id)
+        });
+        let (typ, buf) = request::with_timeout(self.timeout, self.mux.request_one(&msg, reply))
+            .await
+            .map_err(|Elapsed(timeout)| LookupError::TimedOut { timeout })?
+            .map_err(|e| {
+                if self.mux.is_connected() {
+                    LookupError::Io { source: e }
+                } else {
+                    LookupError::Disconnected
+                }
+            })?;
+        Ok(expect::<msg::LookupResult>(typ, &buf)?.records)
+    }
+}
+
+fn lookup_msg(
+    version: ProtocolVersion,
+    id: u32,
+    name: &str,
+    zone: EcdsaPublicKey,
+    record_type: RecordType,
+    options: LocalOptions,
+    shorten: Option<EcdsaPrivateKey>,
+) -> Result<msg::Lookup<&str>, LookupError> {
+    if name.len() > GNUNET_DNSPARSER_MAX_NAME_LENGTH as usize {
+        return Err(LookupError::NameTooLong {
+            name: name.to_string(),
+        });
+    };
+    if shorten.is_some() && version != ProtocolVersion::V0_10 {
+        return Err(LookupError::ShortenUnsupported { version });
+    }
+    Ok(msg::Lookup::with_version(version, id, zone, options, shorten, record_type, name).unwrap())
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_concurrent_lookups() {
+    use crate::runtime::{self, UnixStream};
+    use crate::util::serial::*;
+    use futures::FutureExt;
+    use std::str::FromStr;
+
+    fn lookup_result(id: u32, addr: [u8; 4]) -> Vec<u8> {
+        let mut b = vec![0, 36, 1, 245]; // header: len 36, GNS_LOOKUP_RESULT
+        b.extend_from_slice(&id.to_be_bytes());
+        b.extend_from_slice(&1u32.to_be_bytes()); // rd_count
+        b.extend_from_slice(&u64::MAX.to_be_bytes());
+        b.extend_from_slice(&4u32.to_be_bytes());
+        b.extend_from_slice(&(RecordType::A as u32).to_be_bytes());
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&addr);
+        b
+    }
+
+    fn request_id(buf: &[u8]) -> u32 {
+        let (_, rest) = try_split_at(buf, 4).unwrap();
+        cast::<u32be>(&rest[..4]).get()
+    }
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let handle = Client {
+        conn: Exclusive::new(
+            service::Connection::from_stream("gns".to_string(), client),
+            Resync::Discard,
+        ),
+        lookup_id: 0,
+        version: ProtocolVersion::default(),
+    }
+    .into_handle();
+    let mut service = service::Connection::from_stream("service".to_string(), service);
+
+    let zone =
+        EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+    let lookup = |name: &'static str| {
+        let handle = handle.clone();
+        let (fut, res) = async move {
+            handle
+                .lookup(name, zone, RecordType::A, LocalOptions::Default, None)
+                .await
+        }
+        .remote_handle();
+        runtime::spawn(fut);
+        res
+    };
+
+    // A cancelled lookup doesn't disturb the others.
+    let cancelled = lookup("cancelled.gnu");
+    let (_, buf) = service.recv().await.unwrap();
+    let cancelled_id = request_id(&buf);
+    drop(cancelled);
+
+    let a = lookup("a.gnu");
+    let (_, buf) = service.recv().await.unwrap();
+    let a_id = request_id(&buf);
+    let b = lookup("b.gnu");
+    let (_, buf) = service.recv().await.unwrap();
+    let b_id = request_id(&buf);
+
+    // Answer out of order.
+    for (id, addr) in &[(b_id, [2; 4]), (cancelled_id, [0; 4]), (a_id, [1; 4])] {
+        service.send(&lookup_result(*id, *addr)[..]).await.unwrap();
+    }
+    assert_eq!(a.await.unwrap()[0].data, [1; 4]);
+    assert_eq!(b.await.unwrap()[0].data, [2; 4]);
+
+    drop(service);
+    match handle
+        .lookup("c.gnu", zone, RecordType::A, LocalOptions::Default, None)
+        .await
+    {
+        Err(LookupError::Disconnected) => (),
+        r => panic!("expected Disconnected, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_lookup() {
+    use crate::testing::MockService;
+    use std::str::FromStr;
+
+    let mut mock = MockService::bind("gns").await.unwrap();
+    mock.expect_with(MessageType::GNS_LOOKUP, |req| {
+        let mut res = vec![0, 12, 1, 245]; // header: len 12, GNS_LOOKUP_RESULT
+        res.extend_from_slice(&req[4..8]); // id
+        res.extend_from_slice(&0u32.to_be_bytes()); // rd_count
+        vec![res]
+    });
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let zone =
+        EcdsaPublicKey::from_str("JK55QA8J1A164MB08VM209KE93M9JBB07M2VB8M3M03FKRFSV0MG").unwrap();
+    let mut client = Client::connect(&cfg).await.unwrap();
+    let records = client
+        .lookup("gnu.org", zone, RecordType::A, LocalOptions::Default, None)
+        .await
+        .unwrap();
+    assert!(records.is_empty());
+
+    client.set_version(ProtocolVersion::V0_11);
+    let shorten = EcdsaPrivateKey::from_bytes(&[9; 32]);
+    match client
+        .lookup(
+            "gnu.org",
+            zone,
+            RecordType::A,
+            LocalOptions::Default,
+            shorten,
+        )
+        .await
+    {
+        Err(LookupError::ShortenUnsupported { version }) => {
+            assert_eq!(version, ProtocolVersion::V0_11)
+        }
+        r => panic!("expected ShortenUnsupported, got {:?}", r.map(|_| ())),
+    }
+    mock.finish().await.unwrap();
 }
@@ -1,10 +1,11 @@
-use super::RecordType;
-use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey};
+use super::{Record, RecordType};
+use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey, KEY_TYPE_ECDSA};
 
 use crate::util::serial::*;
-use crate::util::{MessageHeader, MessageType};
+use crate::util::{MessageHeader, MessageIn, MessageOutCompound, MessageType, ProtocolVersion};
 
-use std::convert::TryInto;
+use num::ToPrimitive;
+use smallvec::{smallvec, SmallVec};
 
 /// Options for GNS lookups.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -18,10 +19,14 @@ pub enum LocalOptions {
     LocalMaster = 2,
 }
 
-/// Packed struct representing GNUNET_GNS_ClientLookupMessage.
+/// The recursion limit GNUnet 0.11 and later use for lookups, as `GNUNET_GNS_lookup` does.
+pub const DEFAULT_RECURSION_LIMIT: i16 = 128;
+
+/// Packed struct representing GNUNET_GNS_ClientLookupMessage, as of GNUnet 0.10.
+/// Followed by 0-terminated name to look up.
 #[derive(AsBytes)]
 #[repr(C)]
-pub struct Lookup {
+pub struct LookupPrefix {
     header: MessageHeader,
     id: u32,
     zone: EcdsaPublicKey,
@@ -29,32 +34,268 @@ pub struct Lookup {
     have_key: i16,    // 0 or 1
     record_type: i32, // RecordType
     shorten_key: EcdsaPrivateKey,
-    // followed by 0-terminated name to look up
 }
 
-impl Lookup {
+/// Packed struct representing LookupMessage, as of GNUnet 0.11.
+/// Followed by 0-terminated name to look up.
+#[derive(AsBytes)]
+#[repr(C)]
+pub struct LookupPrefixV0_11 {
+    header: MessageHeader,
+    id: u32be,
+    zone: EcdsaPublicKey,
+    options: i16be,
+    recursion_depth_limit: i16be,
+    record_type: i32be,
+}
+
+/// Packed struct representing LookupMessage, as of GNUnet 0.14, where the zone key is preceded by
+/// its type. Followed by 0-terminated name to look up.
+#[derive(AsBytes)]
+#[repr(C)]
+pub struct LookupPrefixV0_14 {
+    header: MessageHeader,
+    id: u32be,
+    zone_type: u32be,
+    zone: EcdsaPublicKey,
+    options: i16be,
+    recursion_depth_limit: i16be,
+    record_type: i32be,
+}
+
+enum AnyLookupPrefix {
+    V0_10(LookupPrefix),
+    V0_11(LookupPrefixV0_11),
+    V0_14(LookupPrefixV0_14),
+}
+
+pub struct Lookup<S> {
+    prefix: AnyLookupPrefix,
+    name: S,
+}
+
+impl<S> Lookup<S>
+where
+    S: AsRef<str>,
+{
+    /// Build a lookup laid out for `ProtocolVersion::default()`. See `with_version`.
     pub fn new(
         id: u32,
         zone: EcdsaPublicKey,
         options: LocalOptions,
         shorten: Option<EcdsaPrivateKey>,
         record_type: RecordType,
-        name: &str,
-    ) -> Self {
-        let msg_len = (std::mem::size_of::<Self>() + name.len() + 1)
-            .try_into()
-            .unwrap();
-        Lookup {
-            header: MessageHeader::new(msg_len, MessageType::GNS_LOOKUP),
-            id: id.to_be(),
+        name: S,
+    ) -> Option<Self> {
+        Lookup::with_version(
+            ProtocolVersion::default(),
+            id,
             zone,
-            options: (options as i16).to_be(),
-            have_key: (shorten.is_some() as i16).to_be(),
-            record_type: (record_type as i32).to_be(),
-            shorten_key: match shorten {
-                Some(x) => x,
-                None => EcdsaPrivateKey::zeros(),
-            },
+            options,
+            shorten,
+            record_type,
+            name,
+        )
+    }
+
+    /// Build a lookup laid out for `version`. Returns `None` if the name is too long, or if
+    /// `shorten` is given to a version after 0.10, which has no shorten zone.
+    pub fn with_version(
+        version: ProtocolVersion,
+        id: u32,
+        zone: EcdsaPublicKey,
+        options: LocalOptions,
+        shorten: Option<EcdsaPrivateKey>,
+        record_type: RecordType,
+        name: S,
+    ) -> Option<Self> {
+        let prefix_len = match version {
+            ProtocolVersion::V0_10 => std::mem::size_of::<LookupPrefix>(),
+            ProtocolVersion::V0_11 => std::mem::size_of::<LookupPrefixV0_11>(),
+            ProtocolVersion::V0_14 => std::mem::size_of::<LookupPrefixV0_14>(),
+        };
+        let msg_len = (prefix_len + name.as_ref().len() + 1).to_u16()?;
+        let header = MessageHeader::new(msg_len, MessageType::GNS_LOOKUP);
+        let prefix = match version {
+            ProtocolVersion::V0_10 => AnyLookupPrefix::V0_10(LookupPrefix {
+                header,
+                id: id.to_be(),
+                zone,
+                options: (options as i16).to_be(),
+                have_key: (shorten.is_some() as i16).to_be(),
+                record_type: (record_type as i32).to_be(),
+                shorten_key: match shorten {
+                    Some(x) => x,
+                    None => EcdsaPrivateKey::zeros(),
+                },
+            }),
+            _ if shorten.is_some() => return None,
+            ProtocolVersion::V0_11 => AnyLookupPrefix::V0_11(LookupPrefixV0_11 {
+                header,
+                id: U32::new(id),
+                zone,
+                options: I16::new(options as i16),
+                recursion_depth_limit: I16::new(DEFAULT_RECURSION_LIMIT),
+                record_type: I32::new(record_type as i32),
+            }),
+            ProtocolVersion::V0_14 => AnyLookupPrefix::V0_14(LookupPrefixV0_14 {
+                header,
+                id: U32::new(id),
+                zone_type: U32::new(KEY_TYPE_ECDSA),
+                zone,
+                options: I16::new(options as i16),
+                recursion_depth_limit: I16::new(DEFAULT_RECURSION_LIMIT),
+                record_type: I32::new(record_type as i32),
+            }),
+        };
+        Some(Lookup { prefix, name })
+    }
+}
+
+impl<'a, S> MessageOutCompound for &'a Lookup<S>
+where
+    S: AsRef<str>,
+{
+    type Bytes = &'a [u8];
+    type Chunks = SmallVec<[&'a [u8]; 3]>;
+
+    fn as_byte_chunks(&self) -> Self::Chunks {
+        let prefix = match &self.prefix {
+            AnyLookupPrefix::V0_10(p) => p.as_bytes(),
+            AnyLookupPrefix::V0_11(p) => p.as_bytes(),
+            AnyLookupPrefix::V0_14(p) => p.as_bytes(),
+        };
+        smallvec![prefix, self.name.as_ref().as_bytes(), &[0][..]]
+    }
+}
+
+/// Followed by `rd_count` serialized records.
+#[derive(Copy, Clone, Debug, FromBytes)]
+#[repr(C)]
+pub struct LookupResultPrefix {
+    pub header: MessageHeader,
+    pub id: u32be,
+    pub rd_count: u32be,
+}
+
+pub struct LookupResult {
+    pub prefix: LookupResultPrefix,
+    pub records: Vec<Record>,
+}
+
+impl LookupResult {
+    pub fn id(&self) -> u32 {
+        self.prefix.id.get()
+    }
+}
+
+impl MessageIn<'_> for LookupResult {
+    fn msg_type() -> MessageType {
+        MessageType::GNS_LOOKUP_RESULT
+    }
+    fn from_bytes(b: &[u8]) -> Option<Self> {
+        let (prefix, mut rest) = try_cast_prefix::<LookupResultPrefix>(b)?;
+        if prefix.header.length() as usize != b.len() {
+            return None;
+        }
+        let records = (0..prefix.rd_count.get())
+            .map(|_| Record::deserialize(&mut rest).ok())
+            .collect::<Option<Vec<_>>>()?;
+        if !rest.is_empty() {
+            return None;
         }
+        Some(LookupResult {
+            prefix: *prefix,
+            records,
+        })
     }
 }
+
+#[test]
+fn test_lookup_result() {
+    let mut b = vec![];
+    b.extend_from_slice(&[0, 36, 1, 245]); // header: len 36, GNS_LOOKUP_RESULT
+    b.extend_from_slice(&7u32.to_be_bytes()); // id
+    b.extend_from_slice(&1u32.to_be_bytes()); // rd_count
+    b.extend_from_slice(&u64::MAX.to_be_bytes()); // expiration_time
+    b.extend_from_slice(&4u32.to_be_bytes()); // data_size
+    b.extend_from_slice(&(RecordType::A as u32).to_be_bytes());
+    b.extend_from_slice(&0u32.to_be_bytes()); // flags
+    b.extend_from_slice(&[10, 0, 0, 1]);
+
+    let res = LookupResult::from_bytes(&b).unwrap();
+    assert_eq!(res.id(), 7);
+    assert_eq!(res.records.len(), 1);
+    assert_eq!(res.records[0].record_type(), Some(RecordType::A));
+    assert_eq!(res.records[0].data, [10, 0, 0, 1]);
+
+    // truncated record data
+    assert!(LookupResult::from_bytes(&b[..b.len() - 1]).is_none());
+    // the header length must match the message
+    b[1] = 41;
+    assert!(LookupResult::from_bytes(&b).is_none());
+
+    // records of types this crate doesn't know are kept
+    b[1] = 36;
+    b[24..28].copy_from_slice(&65555u32.to_be_bytes());
+    let res = LookupResult::from_bytes(&b).unwrap();
+    assert_eq!(res.records[0].record_type(), None);
+    assert_eq!(res.records[0].type_number, 65555);
+    assert_eq!(res.records[0].data, [10, 0, 0, 1]);
+}
+
+#[test]
+fn test_lookup_layout() {
+    use crate::util::MessageOutCompound;
+
+    let zone = EcdsaPublicKey::from_bytes(&[3; 32]).unwrap();
+    let bytes = |version, shorten| {
+        let msg = Lookup::with_version(
+            version,
+            7,
+            zone,
+            LocalOptions::NoDHT,
+            shorten,
+            RecordType::A,
+            "gnu",
+        )?;
+        Some(
+            (&msg)
+                .as_byte_chunks()
+                .into_iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>(),
+        )
+    };
+
+    let mut b = vec![0, 84, 1, 244]; // header: len 84, GNS_LOOKUP
+    b.extend_from_slice(&7u32.to_be_bytes()); // id
+    b.extend_from_slice(&[3; 32]); // zone
+    b.extend_from_slice(&[0, 1, 0, 1]); // options, have_key
+    b.extend_from_slice(&1i32.to_be_bytes()); // record_type
+    b.extend_from_slice(&[9; 32]); // shorten_key
+    b.extend_from_slice(b"gnu\0");
+    let shorten = EcdsaPrivateKey::from_bytes(&[9; 32]);
+    assert_eq!(bytes(ProtocolVersion::V0_10, shorten), Some(b));
+
+    let mut b = vec![0, 52, 1, 244]; // header: len 52, GNS_LOOKUP
+    b.extend_from_slice(&7u32.to_be_bytes()); // id
+    b.extend_from_slice(&[3; 32]); // zone
+    b.extend_from_slice(&[0, 1, 0, 128]); // options, recursion_depth_limit
+    b.extend_from_slice(&1i32.to_be_bytes()); // record_type
+    b.extend_from_slice(b"gnu\0");
+    assert_eq!(bytes(ProtocolVersion::V0_11, None), Some(b));
+
+    let mut b = vec![0, 56, 1, 244]; // header: len 56, GNS_LOOKUP
+    b.extend_from_slice(&7u32.to_be_bytes()); // id
+    b.extend_from_slice(&65536u32.to_be_bytes()); // zone_type: ECDSA
+    b.extend_from_slice(&[3; 32]); // zone
+    b.extend_from_slice(&[0, 1, 0, 128]); // options, recursion_depth_limit
+    b.extend_from_slice(&1i32.to_be_bytes()); // record_type
+    b.extend_from_slice(b"gnu\0");
+    assert_eq!(bytes(ProtocolVersion::V0_14, None), Some(b));
+
+    // Only 0.10 has a shorten zone.
+    assert_eq!(bytes(ProtocolVersion::V0_11, shorten), None);
+}
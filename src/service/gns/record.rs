@@ -98,7 +98,8 @@ pub struct Record {
     pub data: Vec<u8>,
     pub expiration_time: u64,
     pub data_size: usize,
-    pub record_type: RecordType,
+    /// The number of the record's type, which may be one this crate doesn't know.
+    pub type_number: u32,
     pub flags: RecordFlags,
 }
 
@@ -110,36 +111,53 @@ impl Record {
     {
         let expiration_time = reader.read_u64::<BigEndian>()?;
         let data_size = reader.read_u32::<BigEndian>()? as usize;
-        // TODO: handle invalid recordtype
-        let record_type = RecordType::from_u32(reader.read_u32::<BigEndian>()?).unwrap();
+        let type_number = reader.read_u32::<BigEndian>()?;
         // TODO: handle invalid flags
         let flags = RecordFlags::from_bits_truncate(reader.read_u32::<BigEndian>()?);
 
-        let mut data = vec![0; data_size as usize];
-        reader.read_exact(&mut data)?;
+        let mut data = Vec::new();
+        reader.take(data_size as u64).read_to_end(&mut data)?;
+        if data.len() != data_size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
 
         Ok(Record {
             data,
             expiration_time,
             data_size,
-            record_type,
+            type_number,
             flags,
         })
     }
 
-    /// Get the type of a record.
-    pub fn record_type(&self) -> RecordType {
-        self.record_type
+    /// Get the type of a record, or `None` if it is a type this crate doesn't know. Its number is
+    /// then in `type_number`.
+    pub fn record_type(&self) -> Option<RecordType> {
+        RecordType::from_u32(self.type_number)
     }
 }
 
 impl Debug for Record {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         // TODO properly implement ll::GNUNET_GNSRECORD_value_to_string
-        assert!(self.data.len() == 4);
-        let addr =
-            ::std::net::Ipv4Addr::new(self.data[0], self.data[1], self.data[2], self.data[3]);
-        addr.fmt(f)
+        match (self.record_type(), self.data.len()) {
+            (Some(A), 4) => {
+                let addr = ::std::net::Ipv4Addr::new(
+                    self.data[0],
+                    self.data[1],
+                    self.data[2],
+                    self.data[3],
+                );
+                addr.fmt(f)
+            }
+            (Some(t), _) => write!(f, "{} record ({} bytes)", t, self.data.len()),
+            (None, _) => write!(
+                f,
+                "record of unknown type {} ({} bytes)",
+                self.type_number,
+                self.data.len()
+            ),
+        }
     }
 }
 
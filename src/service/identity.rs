@@ -1,13 +1,20 @@
 //! Module for connecting to and querying the GNUnet identity service.
 
-use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey, HashCode};
-use crate::service;
-use crate::util::message::{expect_either, Left, Right};
-use crate::util::Config;
+use crate::crypto::{EcdsaPrivateKey, EcdsaPublicKey, HashCode, KEY_TYPE_ECDSA};
+use crate::service::request::{Exclusive, Resync};
+use crate::service::transaction::Response;
+use crate::service::{self, ReconnectPolicy, RequestError, TransactionError};
+use crate::util::{expect, Config, Dispatch, ExpectError, MessageType, ProtocolVersion};
 
+use futures::stream::{Stream, StreamExt};
 use std::collections::HashMap;
 use std::fmt;
 use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::debug;
+
 mod msg;
 pub use msg::*;
 
@@ -22,6 +29,15 @@ pub struct Ego {
 }
 
 impl Ego {
+    fn new((name, sk): (String, EcdsaPrivateKey)) -> Ego {
+        let id = sk.get_public().hash();
+        Ego {
+            sk,
+            name: Some(name),
+            id,
+        }
+    }
+
     /// Get a copy of the global, anonymous ego.
     pub fn anonymous() -> Ego {
         let sk = EcdsaPrivateKey::anonymous();
@@ -62,7 +78,8 @@ impl fmt::Display for Ego {
 
 /// A handle to the identity service.
 pub struct Client {
-    conn: service::Connection,
+    conn: Exclusive,
+    version: ProtocolVersion,
 }
 
 /// Errors returned by `Client::connect`
@@ -100,6 +117,35 @@ pub enum UpdateStreamError {
         #[from]
         source: io::Error,
     },
+    #[error("Unexpected response. Error: {source}")]
+    UnexpectedResponse {
+        #[from]
+        source: ExpectError,
+    },
+    #[error("The request did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("An earlier request failed part way through, so the client can no longer be used")]
+    Poisoned,
+}
+
+impl From<TransactionError> for UpdateStreamError {
+    fn from(e: TransactionError) -> UpdateStreamError {
+        match e {
+            TransactionError::Io { source } => UpdateStreamError::Io { source },
+            TransactionError::UnexpectedResponse { source } => {
+                UpdateStreamError::UnexpectedResponse { source }
+            }
+        }
+    }
+}
+
+impl From<RequestError> for UpdateStreamError {
+    fn from(e: RequestError) -> UpdateStreamError {
+        match e {
+            RequestError::TimedOut { timeout } => UpdateStreamError::TimedOut { timeout },
+            RequestError::Poisoned => UpdateStreamError::Poisoned,
+        }
+    }
 }
 
 /// Errors returned by `Client::get_default_ego`
@@ -132,8 +178,35 @@ pub enum GetDefaultEgoError {
     },
     #[error("The service response was incoherent. You should file a bug-report if you encounter this error.")]
     InvalidResponse,
+    #[error("The ego has a key of type {key_type}, which isn't supported")]
+    UnsupportedKeyType { key_type: u32 },
+    #[error("Unexpected response. Error: {source}")]
+    UnexpectedResponse {
+        #[from]
+        source: ExpectError,
+    },
     #[error("The service disconnected unexpectedly")]
     Disconnected,
+    #[error("The request did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("An earlier request failed part way through, so the client can no longer be used")]
+    Poisoned,
+}
+
+impl From<RequestError> for GetDefaultEgoError {
+    fn from(e: RequestError) -> GetDefaultEgoError {
+        match e {
+            RequestError::TimedOut { timeout } => GetDefaultEgoError::TimedOut { timeout },
+            RequestError::Poisoned => GetDefaultEgoError::Poisoned,
+        }
+    }
+}
+
+/// Whether a message ends the reply to any request, so that an unfinished reply can be drained.
+fn ends_reply(typ: u16, msg: &[u8]) -> bool {
+    typ == MessageType::IDENTITY_RESULT_CODE.to_u16()
+        || typ == MessageType::IDENTITY_SET_DEFAULT.to_u16()
+        || Egos::ends(typ, msg)
 }
 
 impl Client {
@@ -143,18 +216,83 @@ impl Client {
     /// `cfg` contains the configuration to use to connect to the service.
     pub async fn connect(cfg: &Config) -> Result<Client, ConnectError> {
         let conn = service::connect(cfg, "identity").await?;
-        Ok(Client { conn })
+        Ok(Client {
+            conn: Exclusive::new(conn, Resync::DrainUntil(ends_reply)),
+            version: ProtocolVersion::default(),
+        })
+    }
+
+    /// Speak the protocol of GNUnet `version` in later requests. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    /// The version set by `set_version`.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
     }
 
-    // TODO: return Stream
+    /// Limit how long each later request may take. `None`, the default, means requests may wait
+    /// forever. The reply to a request that times out is discarded by the next request; see
+    /// `service::request`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.conn.set_timeout(timeout);
+    }
+
+    /// The limit set by `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.conn.timeout()
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.conn.is_poisoned()
+    }
+
+    /// Get every ego the service knows about, keyed by their ids.
+    ///
+    /// With GNUnet 0.14 and later, egos whose keys aren't ECDSA keys are left out.
+    ///
+    /// The service goes on sending an update whenever an ego changes, which later requests on this
+    /// client skip. Use `notify` to receive them.
     pub async fn get_update_stream(&mut self) -> Result<HashMap<HashCode, Ego>, UpdateStreamError> {
         // Service response:
         //   N IDENTITY_UPDATE msgs.
         //   Last message in initial N have end_of_list == true, name_len == 0.
         //   Service will continue to send IDENTITY_UPDATE msgs periodically.
-
-        // self.conn.send(&Lookup::new()).await?;
-        todo!();
+        let version = self.version;
+        self.conn
+            .request(|mut conn| async move {
+                let mut egos = HashMap::new();
+                let mut add = |name: &str, sk| {
+                    let ego = Ego::new((name.to_string(), sk));
+                    egos.insert(ego.get_id().clone(), ego);
+                };
+                match version {
+                    ProtocolVersion::V0_10 | ProtocolVersion::V0_11 => {
+                        let updates = conn.transaction(&Lookup::new()).await?;
+                        updates
+                            .for_each(|update| {
+                                let (name, sk) = update.into_name_and_key();
+                                add(name, sk)
+                            })
+                            .await?;
+                    }
+                    ProtocolVersion::V0_14 => {
+                        let updates = conn.transaction(&LookupV0_14::new()).await?;
+                        updates
+                            .for_each(|update| match update.key_type() {
+                                KEY_TYPE_ECDSA => {
+                                    let (name, sk) = update.into_name_and_key();
+                                    add(name, sk)
+                                }
+                                key_type => debug!(key_type, "skipping ego with unsupported key"),
+                            })
+                            .await?;
+                    }
+                }
+                Ok(egos)
+            })
+            .await?
     }
 
     /// Get the default identity associated with a service.
@@ -166,23 +304,299 @@ impl Client {
 
         // TODO: check name len here
         let msg = GetDefault::new(name).unwrap();
-        self.conn.send_compound(&msg).await?;
+        let version = self.version;
+        self.conn
+            .request(|mut conn| async move {
+                conn.send(&msg).await?;
+
+                // Skip updates the service sends after `get_update_stream` as egos change.
+                let (typ, buf) = loop {
+                    let (typ, buf) = conn.recv().await?;
+                    if typ != MessageType::IDENTITY_UPDATE.to_u16() {
+                        break (typ, buf);
+                    }
+                };
+                let replies = Dispatch::new().on(|res: ResultCode<String>| {
+                    Err(GetDefaultEgoError::ServiceResponse {
+                        response: res.err_msg,
+                    })
+                });
+                let mut replies = match version {
+                    ProtocolVersion::V0_10 | ProtocolVersion::V0_11 => {
+                        replies.on(|s: SetDefault<String>| Ok(Ego::new(s.into_name_and_key())))
+                    }
+                    ProtocolVersion::V0_14 => {
+                        replies.on(|s: SetDefaultV0_14<String>| match s.key_type() {
+                            KEY_TYPE_ECDSA => Ok(Ego::new(s.into_name_and_key())),
+                            key_type => Err(GetDefaultEgoError::UnsupportedKeyType { key_type }),
+                        })
+                    }
+                };
+                replies.dispatch(typ, &buf)?
+            })
+            .await?
+    }
+}
+
+/// Watch the egos of the identity service.
+///
+/// The service first reports every ego, followed by `EgoUpdate::EndOfList`, and then reports each
+/// ego that is created, renamed or deleted. If the connection is lost it is re-established as
+/// described by `policy`, and the service then reports every ego again.
+pub async fn notify(cfg: &Config, policy: ReconnectPolicy) -> Result<Notifications, ConnectError> {
+    let mux = service::Mux::reconnecting(cfg, "identity", policy).await?;
+    let updates = mux.subscribe(&[MessageType::IDENTITY_UPDATE])?;
+    mux.register(&Lookup::new())?;
+    Ok(Notifications {
+        mux,
+        updates,
+        version: ProtocolVersion::default(),
+    })
+}
 
-        let (typ, buf) = self.conn.recv().await?;
-        match expect_either::<ResultCode<String>, SetDefault<String>>(typ, &buf).unwrap() // XXX
-        {
-            Left(ResultCode { err_msg, .. }) => {
-                Err(GetDefaultEgoError::ServiceResponse { response: err_msg })
+/// A change to the egos of the identity service, received from `notify`.
+#[derive(Clone)]
+pub enum EgoUpdate {
+    /// The ego was created or renamed, or existed when the connection was established.
+    Changed(Ego),
+    /// The ego with this id was deleted.
+    Deleted(HashCode),
+    /// Every ego that existed when the connection was established has been reported.
+    EndOfList,
+}
+
+/// A stream of changes to the egos of the identity service, created by `notify`.
+///
+/// With GNUnet 0.14 and later, egos whose keys aren't ECDSA keys are left out.
+pub struct Notifications {
+    mux: service::Mux,
+    updates: service::Subscription,
+    version: ProtocolVersion,
+}
+
+impl Notifications {
+    /// Receive events about the state of the connection to the service.
+    pub fn events(&self) -> Result<service::Events, io::Error> {
+        self.mux.events()
+    }
+
+    /// Read updates in the layout of GNUnet `version` from now on. See `util::version`.
+    pub fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    fn parse(&self, typ: u16, buf: &[u8]) -> Result<Option<EgoUpdate>, ExpectError> {
+        let (end_of_list, name, sk) = match self.version {
+            ProtocolVersion::V0_10 | ProtocolVersion::V0_11 => {
+                let update = expect::<Update<&str>>(typ, buf)?;
+                let end_of_list = update.prefix.end_of_list();
+                let (name, sk) = update.into_name_and_key();
+                (end_of_list, name, sk)
+            }
+            ProtocolVersion::V0_14 => {
+                let update = expect::<UpdateV0_14<&str>>(typ, buf)?;
+                let end_of_list = update.prefix.end_of_list.get() != 0;
+                let key_type = update.key_type();
+                if !end_of_list && key_type != KEY_TYPE_ECDSA {
+                    debug!(key_type, "skipping ego with unsupported key");
+                    return Ok(None);
+                }
+                let (name, sk) = update.into_name_and_key();
+                (end_of_list, name, sk)
+            }
+        };
+        Ok(Some(if end_of_list {
+            EgoUpdate::EndOfList
+        } else if name.is_empty() {
+            EgoUpdate::Deleted(sk.get_public().hash())
+        } else {
+            EgoUpdate::Changed(Ego::new((name.to_string(), sk)))
+        }))
+    }
+}
+
+impl Stream for Notifications {
+    type Item = EgoUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<EgoUpdate>> {
+        loop {
+            let (typ, buf) = match self.updates.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match self.parse(typ, &buf) {
+                Ok(Some(update)) => return Poll::Ready(Some(update)),
+                Ok(None) => (),
+                Err(e) => debug!("ignoring notification: {}", e),
             }
-            Right(s) => {
-		let (name, sk) = s.into_name_and_key();
-		let id = sk.get_public().hash();
-		Ok(Ego {
-		    sk,
-		    name: Some(name),
-		    id
-		})
-	    }
         }
     }
 }
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_get_default_ego() {
+    use crate::testing::MockService;
+    use crate::util::MessageType;
+
+    let sk = EcdsaPrivateKey::from_bytes(&[7; 32]).unwrap();
+    let mut set_default = vec![0, 47];
+    set_default.extend_from_slice(&MessageType::IDENTITY_SET_DEFAULT.to_u16().to_be_bytes());
+    set_default.extend_from_slice(&[0, 7, 0, 0]); // name_len, reserved
+    set_default.extend_from_slice(&[7; 32]);
+    set_default.extend_from_slice(b"my-ego\0");
+
+    let mut mock = MockService::bind("identity").await.unwrap();
+    mock.expect(MessageType::IDENTITY_GET_DEFAULT, vec![set_default]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut client = Client::connect(&cfg).await.unwrap();
+    client.set_version(ProtocolVersion::V0_10);
+    let ego = client.get_default_ego("gns-master").await.unwrap();
+    assert_eq!(ego.get_public_key().to_string(), sk.get_public().to_string());
+    assert_eq!(ego.get_name().as_deref(), Some("my-ego"));
+
+    let received = mock.finish().await.unwrap();
+    assert_eq!(&received[0].1[8..], b"gns-master\0");
+}
+
+#[cfg(test)]
+fn update(name: &str, key: u8, end_of_list: bool) -> Vec<u8> {
+    let name_len = if name.is_empty() { 0 } else { name.len() + 1 };
+    let mut b = ((40 + name_len) as u16).to_be_bytes().to_vec();
+    b.extend_from_slice(&MessageType::IDENTITY_UPDATE.to_u16().to_be_bytes());
+    b.extend_from_slice(&(name_len as u16).to_be_bytes());
+    b.extend_from_slice(&(end_of_list as u16).to_be_bytes());
+    b.extend_from_slice(&[key; 32]);
+    if !name.is_empty() {
+        b.extend_from_slice(name.as_bytes());
+        b.push(0);
+    }
+    b
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_get_update_stream() {
+    use crate::testing::MockService;
+
+    let mut mock = MockService::bind("identity").await.unwrap();
+    mock.expect(
+        MessageType::IDENTITY_START,
+        vec![
+            update("alice", 1, false),
+            update("bob", 2, false),
+            update("", 0, true),
+        ],
+    );
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut client = Client::connect(&cfg).await.unwrap();
+    client.set_version(ProtocolVersion::V0_10);
+    let egos = client.get_update_stream().await.unwrap();
+    let mut names: Vec<_> = egos.values().filter_map(Ego::get_name).collect();
+    names.sort();
+    assert_eq!(names, ["alice", "bob"]);
+    let alice = EcdsaPrivateKey::from_bytes(&[1; 32]).unwrap();
+    assert!(egos.contains_key(&alice.get_public().hash()));
+    mock.finish().await.unwrap();
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_resync() {
+    use crate::runtime::UnixStream;
+    use crate::service::Connection;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let mut client = Client {
+        conn: Exclusive::new(
+            Connection::from_stream("identity".to_string(), client),
+            Resync::DrainUntil(ends_reply),
+        ),
+        version: ProtocolVersion::V0_10,
+    };
+
+    // Give up on the list of egos part way through.
+    client.set_timeout(Some(Duration::from_millis(50)));
+    assert!(client.get_update_stream().await.is_err());
+    let (typ, _) = service.recv().await.unwrap();
+    assert_eq!(typ, MessageType::IDENTITY_START.to_u16());
+
+    // The rest of the list arrives late, followed by an update about a changed ego and the reply
+    // to the next request.
+    let mut set_default = vec![0, 43];
+    set_default.extend_from_slice(&MessageType::IDENTITY_SET_DEFAULT.to_u16().to_be_bytes());
+    set_default.extend_from_slice(&[0, 3, 0, 0]); // name_len, reserved
+    set_default.extend_from_slice(&[3; 32]);
+    set_default.extend_from_slice(b"me\0");
+    for b in &[
+        update("alice", 1, false),
+        update("bob", 2, false),
+        update("", 0, true),
+        update("carol", 4, false),
+        set_default,
+    ] {
+        service.send(&b[..]).await.unwrap();
+    }
+    client.set_timeout(None);
+    let ego = client.get_default_ego("gns-master").await.unwrap();
+    assert_eq!(ego.get_name().as_deref(), Some("me"));
+    let (typ, _) = service.recv().await.unwrap();
+    assert_eq!(typ, MessageType::IDENTITY_GET_DEFAULT.to_u16());
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_notify() {
+    use crate::service::ConnectionEvent;
+    use crate::testing::MockService;
+
+    let names = |updates: Vec<EgoUpdate>| -> Vec<String> {
+        updates
+            .into_iter()
+            .map(|u| match u {
+                EgoUpdate::Changed(ego) => ego.get_name().unwrap(),
+                EgoUpdate::Deleted(_) => "<deleted>".to_string(),
+                EgoUpdate::EndOfList => "<end>".to_string(),
+            })
+            .collect()
+    };
+
+    let mut mock = MockService::bind("identity").await.unwrap();
+    mock.expect(
+        MessageType::IDENTITY_START,
+        vec![
+            update("alice", 1, false),
+            update("", 0, true),
+            update("", 1, false),
+        ],
+    );
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut updates = notify(&cfg, ReconnectPolicy::default()).await.unwrap();
+    updates.set_version(ProtocolVersion::V0_10);
+    let mut events = updates.events().unwrap();
+    let first: Vec<_> = (&mut updates).take(3).collect().await;
+    assert_eq!(names(first), ["alice", "<end>", "<deleted>"]);
+
+    // The egos are listed again once the client has reconnected to the restarted service.
+    let mut mock = mock.restart().await.unwrap();
+    mock.expect(
+        MessageType::IDENTITY_START,
+        vec![update("bob", 2, false), update("", 0, true)],
+    );
+    let mock = mock.spawn();
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { .. }) => (),
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    let second: Vec<_> = (&mut updates).take(2).collect().await;
+    assert_eq!(names(second), ["bob", "<end>"]);
+    mock.finish().await.unwrap();
+}
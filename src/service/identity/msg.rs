@@ -1,8 +1,7 @@
 use crate::crypto::EcdsaPrivateKey;
+use crate::service::transaction::{Request, Response};
 use crate::util::serial::*;
-use crate::util::{MessageHeader, MessageIn, MessageOutCompound, MessageType};
-use num::ToPrimitive;
-use smallvec::{smallvec, SmallVec};
+use crate::util::{GnunetMessage, MessageHeader, MessageType};
 
 #[derive(Debug, AsBytes)]
 #[repr(C)]
@@ -18,17 +17,50 @@ impl Lookup {
     }
 }
 
-/// Followed by `name_len` bytes (null-terminated string).
+impl Request for Lookup {
+    type Reply = Egos;
+}
+
+/// `Lookup` as of GNUnet 0.14, which is the same message answered by `UpdateV0_14`s.
 #[derive(Debug, AsBytes)]
 #[repr(C)]
+pub struct LookupV0_14 {
+    header: MessageHeader,
+}
+
+impl LookupV0_14 {
+    pub fn new() -> Self {
+        Self {
+            header: MessageHeader::new(4, MessageType::IDENTITY_START),
+        }
+    }
+}
+
+impl Default for LookupV0_14 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Request for LookupV0_14 {
+    type Reply = EgosV0_14;
+}
+
+/// Followed by `name_len` bytes (null-terminated string).
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
 pub struct GetDefaultPrefix {
     header: MessageHeader,
     name_len: u16be,
     reserved: u16be, // always zero
 }
 
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_GET_DEFAULT)]
 pub struct GetDefault<S> {
+    #[gnunet(prefix)]
     prefix: GetDefaultPrefix,
+    #[gnunet(cstr, len = name_len)]
     name: S,
 }
 
@@ -37,40 +69,20 @@ where
     S: AsRef<str>,
 {
     pub fn new(name: S) -> Option<Self> {
-        let name_len = name.as_ref().len() + 1; // trailing null
-        if let Some(msg_len) = (std::mem::size_of::<GetDefaultPrefix>() + name_len).to_u16() {
-            Some(Self {
-                prefix: GetDefaultPrefix {
-                    header: MessageHeader::new(msg_len, MessageType::IDENTITY_GET_DEFAULT),
-                    name_len: U16::new(name_len as u16),
-                    reserved: U16::ZERO,
-                },
-                name,
-            })
-        } else {
-            None
+        GetDefault {
+            prefix: GetDefaultPrefix {
+                header: MessageHeader::new(0, MessageType::IDENTITY_GET_DEFAULT),
+                name_len: U16::ZERO,
+                reserved: U16::ZERO,
+            },
+            name,
         }
-    }
-}
-
-impl<'a, S> MessageOutCompound for &'a GetDefault<S>
-where
-    S: AsRef<str>,
-{
-    type Bytes = &'a [u8];
-    type Chunks = SmallVec<[&'a [u8]; 3]>;
-
-    fn as_byte_chunks(&self) -> Self::Chunks {
-        smallvec![
-            self.prefix.as_bytes(),
-            self.name.as_ref().as_bytes(),
-            &[0][..]
-        ]
+        .fill_header()
     }
 }
 
 /// Followed by `name_len` bytes (null-terminated string).
-#[derive(Copy, Clone, FromBytes)]
+#[derive(Copy, Clone, AsBytes, FromBytes)]
 #[repr(C)]
 pub struct SetDefaultPrefix {
     pub header: MessageHeader,
@@ -79,8 +91,12 @@ pub struct SetDefaultPrefix {
     pub private_key: EcdsaPrivateKey,
 }
 
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_SET_DEFAULT)]
 pub struct SetDefault<S> {
+    #[gnunet(prefix)]
     pub prefix: SetDefaultPrefix,
+    #[gnunet(cstr, len = name_len)]
     name: S,
 }
 
@@ -90,66 +106,153 @@ impl<S> SetDefault<S> {
     }
 }
 
-impl<'a, S> MessageIn<'a> for SetDefault<S>
-where
-    S: From<&'a str>,
-{
-    fn msg_type() -> MessageType {
-        MessageType::IDENTITY_SET_DEFAULT
+/// `SetDefaultPrefix` as of GNUnet 0.14, where the private key is preceded by its type.
+/// Followed by `name_len` bytes (null-terminated string).
+#[derive(Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct SetDefaultPrefixV0_14 {
+    pub header: MessageHeader,
+    pub name_len: u16be,
+    pub reserved: u16be,
+    pub key_type: u32be,
+    pub private_key: EcdsaPrivateKey,
+}
+
+/// `SetDefault` as of GNUnet 0.14.
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_SET_DEFAULT)]
+pub struct SetDefaultV0_14<S> {
+    #[gnunet(prefix)]
+    pub prefix: SetDefaultPrefixV0_14,
+    #[gnunet(cstr, len = name_len)]
+    name: S,
+}
+
+impl<S> SetDefaultV0_14<S> {
+    /// The type of the key, eg. `crypto::KEY_TYPE_ECDSA`.
+    pub fn key_type(&self) -> u32 {
+        self.prefix.key_type.get()
     }
-    fn from_bytes(b: &'a [u8]) -> Option<Self> {
-        let (prefix, name) = try_parse_prefix_and_str(b)?;
-        Some(Self {
-            prefix: *prefix,
-            name: S::from(name),
-        })
+
+    /// The key is only an `EcdsaPrivateKey` if `key_type` is `crypto::KEY_TYPE_ECDSA`.
+    pub fn into_name_and_key(self) -> (S, EcdsaPrivateKey) {
+        (self.name, self.prefix.private_key)
     }
 }
 
-#[derive(FromBytes)]
+/// Followed by `name_len` bytes (null-terminated string).
+#[derive(Copy, Clone, AsBytes, FromBytes)]
 #[repr(C)]
-pub struct Update {
+pub struct UpdatePrefix {
     pub header: MessageHeader,
     pub name_len: u16be,
     pub end_of_list: u16be,
     pub private_key: EcdsaPrivateKey,
 }
 
-impl Update {
+impl UpdatePrefix {
     pub fn end_of_list(&self) -> bool {
         self.end_of_list.get() != 0
     }
 }
 
-#[derive(Copy, Clone, FromBytes)]
+/// An ego, sent in reply to `Lookup` and whenever one changes. The list sent in reply to `Lookup`
+/// is ended by an update without a name whose `end_of_list` is set.
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_UPDATE)]
+pub struct Update<S> {
+    #[gnunet(prefix)]
+    pub prefix: UpdatePrefix,
+    #[gnunet(cstr, len = name_len)]
+    name: S,
+}
+
+impl<S> Update<S> {
+    pub fn into_name_and_key(self) -> (S, EcdsaPrivateKey) {
+        (self.name, self.prefix.private_key)
+    }
+}
+
+/// `UpdatePrefix` as of GNUnet 0.14, where the private key is preceded by its type.
+/// Followed by `name_len` bytes (null-terminated string).
+#[derive(Copy, Clone, AsBytes, FromBytes)]
 #[repr(C)]
-pub struct ResultCodePrefix {
+pub struct UpdatePrefixV0_14 {
     pub header: MessageHeader,
-    pub result_code: u32be,
+    pub name_len: u16be,
+    pub end_of_list: u16be,
+    pub key_type: u32be,
+    pub private_key: EcdsaPrivateKey,
 }
 
-pub struct ResultCode<S> {
-    pub prefix: ResultCodePrefix,
-    pub err_msg: S,
+/// `Update` as of GNUnet 0.14.
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_UPDATE)]
+pub struct UpdateV0_14<S> {
+    #[gnunet(prefix)]
+    pub prefix: UpdatePrefixV0_14,
+    #[gnunet(cstr, len = name_len)]
+    name: S,
 }
 
-impl<'a, S> MessageIn<'a> for ResultCode<S>
-where
-    S: From<&'a str>,
-{
-    fn msg_type() -> MessageType {
-        MessageType::IDENTITY_RESULT_CODE
+impl<S> UpdateV0_14<S> {
+    /// The type of the key, eg. `crypto::KEY_TYPE_ECDSA`.
+    pub fn key_type(&self) -> u32 {
+        self.prefix.key_type.get()
+    }
+
+    /// The key is only an `EcdsaPrivateKey` if `key_type` is `crypto::KEY_TYPE_ECDSA`.
+    pub fn into_name_and_key(self) -> (S, EcdsaPrivateKey) {
+        (self.name, self.prefix.private_key)
     }
+}
 
-    fn from_bytes(b: &'a [u8]) -> Option<Self> {
-        let (prefix, err_msg) = try_parse_prefix_and_str(b)?;
-        Some(Self {
-            prefix: *prefix,
-            err_msg: S::from(err_msg),
-        })
+/// Whether `msg` is the update that ends the list of egos. `end_of_list` is at the same offset
+/// in every version's layout.
+fn ends_ego_list(typ: u16, msg: &[u8]) -> bool {
+    typ == MessageType::IDENTITY_UPDATE.to_u16()
+        && try_cast_prefix::<UpdatePrefix>(msg).is_some_and(|(p, _)| p.end_of_list())
+}
+
+/// The reply to `Lookup`: an `Update` for each ego, followed by the end of the list.
+pub struct Egos;
+
+impl Response for Egos {
+    type Item<'a> = Update<&'a str>;
+
+    fn ends(typ: u16, msg: &[u8]) -> bool {
+        ends_ego_list(typ, msg)
     }
 }
 
+/// `Egos` as of GNUnet 0.14.
+pub struct EgosV0_14;
+
+impl Response for EgosV0_14 {
+    type Item<'a> = UpdateV0_14<&'a str>;
+
+    fn ends(typ: u16, msg: &[u8]) -> bool {
+        ends_ego_list(typ, msg)
+    }
+}
+
+/// Followed by an optional error message (null-terminated string).
+#[derive(Copy, Clone, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct ResultCodePrefix {
+    pub header: MessageHeader,
+    pub result_code: u32be,
+}
+
+#[derive(GnunetMessage)]
+#[gnunet(msg_type = IDENTITY_RESULT_CODE)]
+pub struct ResultCode<S> {
+    #[gnunet(prefix)]
+    pub prefix: ResultCodePrefix,
+    #[gnunet(cstr)]
+    pub err_msg: S,
+}
+
 #[derive(FromBytes)]
 #[repr(C)]
 pub struct CreateRequest {
@@ -174,3 +277,60 @@ pub struct Delete {
     pub name_len: u16be,
     pub reserved: u16be,
 }
+
+#[test]
+fn test_set_default_layout() {
+    use crate::util::MessageIn;
+
+    let mut b = vec![0, 43, 2, 116]; // header: len 43, IDENTITY_SET_DEFAULT
+    b.extend_from_slice(&[0, 3, 0, 0]); // name_len, reserved
+    b.extend_from_slice(&[7; 32]); // private_key
+    b.extend_from_slice(b"me\0");
+    let (name, key) = SetDefault::<String>::from_bytes(&b)
+        .unwrap()
+        .into_name_and_key();
+    assert_eq!(name, "me");
+    assert_eq!(key.as_bytes(), &[7; 32][..]);
+
+    let mut b = vec![0, 47, 2, 116]; // header: len 47, IDENTITY_SET_DEFAULT
+    b.extend_from_slice(&[0, 3, 0, 0]); // name_len, reserved
+    b.extend_from_slice(&65536u32.to_be_bytes()); // key_type: ECDSA
+    b.extend_from_slice(&[7; 32]); // private_key
+    b.extend_from_slice(b"me\0");
+    let msg = SetDefaultV0_14::<String>::from_bytes(&b).unwrap();
+    assert_eq!(msg.key_type(), crate::crypto::KEY_TYPE_ECDSA);
+    let (name, key) = msg.into_name_and_key();
+    assert_eq!(name, "me");
+    assert_eq!(key.as_bytes(), &[7; 32][..]);
+
+    // Each layout rejects the other.
+    assert!(SetDefault::<String>::from_bytes(&b).is_none());
+}
+
+#[test]
+fn test_update_layout() {
+    use crate::util::MessageIn;
+
+    let mut b = vec![0, 43, 2, 114]; // header: len 43, IDENTITY_UPDATE
+    b.extend_from_slice(&[0, 3, 0, 0]); // name_len, end_of_list
+    b.extend_from_slice(&[7; 32]); // private_key
+    b.extend_from_slice(b"me\0");
+    assert!(!Egos::ends(626, &b));
+    let (name, key) = Update::<String>::from_bytes(&b)
+        .unwrap()
+        .into_name_and_key();
+    assert_eq!(name, "me");
+    assert_eq!(key.as_bytes(), &[7; 32][..]);
+
+    let mut b = vec![0, 44, 2, 114]; // header: len 44, IDENTITY_UPDATE
+    b.extend_from_slice(&[0, 0, 0, 1]); // name_len, end_of_list
+    b.extend_from_slice(&65536u32.to_be_bytes()); // key_type: ECDSA
+    b.extend_from_slice(&[0; 32]); // private_key
+    assert!(EgosV0_14::ends(626, &b));
+    let msg = UpdateV0_14::<String>::from_bytes(&b).unwrap();
+    assert_eq!(msg.key_type(), crate::crypto::KEY_TYPE_ECDSA);
+    assert_eq!(msg.into_name_and_key().0, "");
+
+    // Each layout rejects the other.
+    assert!(Update::<String>::from_bytes(&b).is_none());
+}
@@ -0,0 +1,867 @@
+//! A connection to a service that is driven by background tasks, so that requests, their replies
+//! and unsolicited notifications can be interleaved on a single socket.
+//!
+//! A `Mux` owns the connection. A writer task sends the messages in its `MessageQueue` (see
+//! `Mux::queue`), and a reader task hands each incoming message to the first pending request that
+//! accepts it (see `Reply`). Messages that no request accepts are copied to every `Subscription`
+//! for that message type, and are otherwise dropped.
+
+use crate::runtime;
+use crate::service::queue::Writer;
+use crate::service::{self, ConnectError, Connection, RecvHalf};
+use crate::service::{ConnectionEvent, MessageQueue, ReconnectPolicy};
+use crate::util::serial::Buffer;
+use crate::util::{time, AnyMessageType, Config, MessageOutCompound, MessageType};
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use futures::{pin_mut, select, FutureExt};
+use smallvec::SmallVec;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tracing::{debug, warn};
+
+/// A cloneable handle to a service connection that is driven by background tasks.
+///
+/// The connection is closed once every clone of the `Mux`, and of its `MessageQueue`, has been
+/// dropped.
+#[derive(Clone)]
+pub struct Mux {
+    name: Arc<str>,
+    shared: Shared,
+    queue: MessageQueue,
+}
+
+/// How many messages `MessageQueue::send` lets wait on a `Mux`'s queue before it waits for space.
+const QUEUE_CAPACITY: usize = 32;
+
+/// Routing state, or `None` once the connection has been lost.
+type Shared = Arc<Mutex<Option<Routes>>>;
+
+type Item = (u16, Buffer);
+
+struct Routes {
+    /// Pending requests, in the order they were sent.
+    waiters: Vec<Waiter>,
+    subscribers: Vec<Subscriber>,
+    /// Messages to send again after reconnecting.
+    registrations: Vec<Buffer>,
+    /// Whether messages can be queued for the current connection. While a reconnecting `Mux` is
+    /// disconnected, registrations are only sent when they are replayed.
+    connected: bool,
+    events: Vec<mpsc::UnboundedSender<ConnectionEvent>>,
+    next_key: u64,
+}
+
+impl Routes {
+    fn emit(&mut self, event: ConnectionEvent) {
+        self.events
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+struct Waiter {
+    key: u64,
+    reply: Reply,
+    /// `None` once the `Replies` stream was dropped; the replies are still consumed so that they
+    /// aren't handed to a later request.
+    tx: Option<mpsc::UnboundedSender<Item>>,
+}
+
+struct Subscriber {
+    types: SmallVec<[u16; 2]>,
+    tx: mpsc::UnboundedSender<Item>,
+}
+
+/// Describes which incoming messages answer a request.
+pub struct Reply {
+    items: SmallVec<[u16; 2]>,
+    last: SmallVec<[u16; 2]>,
+    filter: Option<Filter>,
+}
+
+type Filter = Box<dyn Fn(&[u8]) -> bool + Send>;
+
+impl Reply {
+    /// The request is answered by a single message of one of the given types.
+    pub fn one_of(types: &[MessageType]) -> Reply {
+        Reply {
+            items: SmallVec::new(),
+            last: types.iter().map(MessageType::to_u16).collect(),
+            filter: None,
+        }
+    }
+
+    /// The request is answered by any number of `items` messages, followed by a message of one of
+    /// the `end` types.
+    pub fn until(items: &[MessageType], end: &[MessageType]) -> Reply {
+        Reply {
+            items: items.iter().map(MessageType::to_u16).collect(),
+            last: end.iter().map(MessageType::to_u16).collect(),
+            filter: None,
+        }
+    }
+
+    /// Only accept messages for which `f` returns `true`, eg. because they carry this request's id.
+    ///
+    /// Requests without a filter are answered in the order they were sent.
+    pub fn filter<F>(mut self, f: F) -> Reply
+    where
+        F: Fn(&[u8]) -> bool + Send + 'static,
+    {
+        self.filter = Some(Box::new(f));
+        self
+    }
+
+    fn accepts(&self, typ: u16, buf: &[u8]) -> bool {
+        let matches = match &self.filter {
+            Some(f) => f(buf),
+            None => true,
+        };
+        (self.items.contains(&typ) || self.last.contains(&typ)) && matches
+    }
+}
+
+impl Mux {
+    /// Take ownership of `conn` and spawn the tasks that drive it.
+    pub fn new(conn: Connection) -> Mux {
+        Mux::spawn(conn, None)
+    }
+
+    /// Connect to the service named `name`, and re-establish the connection whenever it is lost.
+    ///
+    /// After the connection is lost, reconnection is attempted with exponential backoff as
+    /// described by `policy`. Once reconnected, every message passed to `Mux::register` is sent
+    /// again. Requests that were pending when the connection was lost fail, while subscriptions
+    /// carry on receiving messages from the new connection. `Mux::events` reports each of these
+    /// steps.
+    pub async fn reconnecting(
+        cfg: &Config,
+        name: &str,
+        policy: ReconnectPolicy,
+    ) -> Result<Mux, ConnectError> {
+        let reconnect = Reconnect {
+            cfg: cfg.clone(),
+            policy,
+            verified: false,
+        };
+        let conn = reconnect.connect(name).await?;
+        Ok(Mux::spawn(conn, Some(reconnect)))
+    }
+
+    /// Like `reconnecting`, but the connection, and every later one, is made with
+    /// `service::connect_verified`.
+    pub async fn reconnecting_verified(
+        cfg: &Config,
+        name: &str,
+        policy: ReconnectPolicy,
+    ) -> Result<Mux, ConnectError> {
+        let reconnect = Reconnect {
+            cfg: cfg.clone(),
+            policy,
+            verified: true,
+        };
+        let conn = reconnect.connect(name).await?;
+        Ok(Mux::spawn(conn, Some(reconnect)))
+    }
+
+    fn spawn(conn: Connection, reconnect: Option<Reconnect>) -> Mux {
+        let name: Arc<str> = Arc::from(conn.name());
+        let queue = MessageQueue::new(QUEUE_CAPACITY, conn.max_message_size());
+        let shared: Shared = Arc::new(Mutex::new(Some(Routes {
+            waiters: Vec::new(),
+            subscribers: Vec::new(),
+            registrations: Vec::new(),
+            connected: true,
+            events: Vec::new(),
+            next_key: 0,
+        })));
+        runtime::spawn(drive(conn, queue.writer(), shared.clone(), reconnect));
+        Mux {
+            name,
+            shared,
+            queue,
+        }
+    }
+
+    /// Send a message that sets up state on the service, such as a request for notifications.
+    ///
+    /// The message is sent again whenever a reconnecting `Mux` re-establishes its connection.
+    pub fn register<M: MessageOutCompound>(&self, msg: M) -> Result<(), io::Error> {
+        let mut guard = self.shared.lock().unwrap();
+        let routes = guard.as_mut().ok_or_else(disconnected)?;
+        let buf = to_buffer(msg);
+        if routes.connected {
+            self.queue.push(buf.clone()).map_err(closed)?;
+        } else {
+            self.queue.check(&buf)?;
+        }
+        routes.registrations.push(buf);
+        Ok(())
+    }
+
+    /// Receive events about the state of the connection.
+    ///
+    /// The stream ends once the connection is lost for good.
+    pub fn events(&self) -> Result<Events, io::Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut guard = self.shared.lock().unwrap();
+        let routes = guard.as_mut().ok_or_else(disconnected)?;
+        routes.events.push(tx);
+        Ok(Events { rx })
+    }
+
+    /// Send a message that the service doesn't reply to.
+    pub fn send<M: MessageOutCompound>(&self, msg: M) -> Result<(), io::Error> {
+        let guard = self.shared.lock().unwrap();
+        if guard.is_none() {
+            return Err(disconnected());
+        }
+        self.queue.push(to_buffer(msg)).map_err(closed)
+    }
+
+    /// The queue that every message sent by this `Mux` goes through.
+    ///
+    /// Messages can be queued on it directly to choose their `Priority`, to cancel them before
+    /// they are sent, or to wait for space when many are waiting. The messages sent through the
+    /// `Mux` itself are queued even when it is full. Messages still waiting when the connection is
+    /// lost are dropped.
+    pub fn queue(&self) -> MessageQueue {
+        self.queue.clone()
+    }
+
+    /// Send a request and return the stream of messages that answer it, as described by `reply`.
+    ///
+    /// Dropping the stream cancels the request.
+    pub fn request<M: MessageOutCompound>(
+        &self,
+        msg: M,
+        reply: Reply,
+    ) -> Result<Replies, io::Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut guard = self.shared.lock().unwrap();
+        let routes = guard.as_mut().ok_or_else(disconnected)?;
+        let key = routes.next_key;
+        routes.next_key += 1;
+        let last = reply.last.clone();
+
+        // Registering and queueing under the same lock keeps the waiters in sending order.
+        self.queue.push(to_buffer(msg)).map_err(closed)?;
+        routes.waiters.push(Waiter {
+            key,
+            reply,
+            tx: Some(tx),
+        });
+
+        Ok(Replies {
+            shared: self.shared.clone(),
+            key,
+            last,
+            rx,
+            done: false,
+        })
+    }
+
+    /// Send a request that is answered by a single message. See `Reply::one_of`.
+    pub async fn request_one<M: MessageOutCompound>(
+        &self,
+        msg: M,
+        reply: Reply,
+    ) -> Result<(u16, Buffer), io::Error> {
+        match self.request(msg, reply)?.next().await {
+            Some(res) => res,
+            None => Err(disconnected()),
+        }
+    }
+
+    /// Receive all messages of the given types that don't answer a request.
+    pub fn subscribe(&self, types: &[MessageType]) -> Result<Subscription, io::Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut guard = self.shared.lock().unwrap();
+        let routes = guard.as_mut().ok_or_else(disconnected)?;
+        routes.subscribers.push(Subscriber {
+            types: types.iter().map(MessageType::to_u16).collect(),
+            tx,
+        });
+        Ok(Subscription { rx })
+    }
+
+    /// Returns `false` once the connection to the service has been lost.
+    pub fn is_connected(&self) -> bool {
+        self.shared.lock().unwrap().is_some()
+    }
+
+    /// Close the connection for every clone of the `Mux`, after sending the messages already
+    /// queued. Pending requests and subscriptions end as if the connection was lost, and a
+    /// reconnecting `Mux` doesn't reconnect.
+    pub fn close(&self) {
+        self.shared.lock().unwrap().take();
+        self.queue.close();
+    }
+}
+
+impl fmt::Debug for Mux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mux").field("name", &self.name).finish()
+    }
+}
+
+struct Reconnect {
+    cfg: Config,
+    policy: ReconnectPolicy,
+    /// Whether to connect with `service::connect_verified`.
+    verified: bool,
+}
+
+impl Reconnect {
+    async fn connect(&self, name: &str) -> Result<Connection, ConnectError> {
+        if self.verified {
+            service::connect_verified(&self.cfg, name).await
+        } else {
+            service::connect(&self.cfg, name).await
+        }
+    }
+}
+
+/// Drives the connection until every `Mux` is dropped, or the connection is lost for good.
+async fn drive(mut conn: Connection, queue: Writer, shared: Shared, reconnect: Option<Reconnect>) {
+    loop {
+        let name = conn.name().to_string();
+        let (recv, send) = conn.split();
+        let error = {
+            let reader = read_loop(recv, &shared).fuse();
+            // Returns `Ok` once every `Mux` is gone and the sending side has been closed.
+            let writer = queue.write(send).fuse();
+            pin_mut!(reader, writer);
+
+            select! {
+                e = reader => e,
+                res = writer => match res {
+                    Ok(()) => {
+                        // Every `Mux` is gone. Deliver what the service still sends until it closes
+                        // the connection.
+                        let e = reader.await;
+                        debug!(service = &*name, "connection closed: {}", e);
+                        shared.lock().unwrap().take();
+                        return;
+                    }
+                    Err(e) => e,
+                },
+            }
+        };
+        debug!(service = &*name, "connection lost: {}", error);
+
+        let reconnect = match &reconnect {
+            Some(r) => r,
+            None => {
+                // Dropping the senders ends every `Replies` and `Subscription` stream.
+                shared.lock().unwrap().take();
+                queue.abort();
+                return;
+            }
+        };
+
+        let error = Arc::new(error);
+        let mut delay = reconnect.policy.initial_delay;
+        let mut attempts = 0;
+        let mut failure: Option<Arc<ConnectError>> = None;
+        conn = loop {
+            {
+                let mut guard = shared.lock().unwrap();
+                let routes = match guard.as_mut() {
+                    Some(r) => r,
+                    None => {
+                        queue.abort();
+                        return;
+                    }
+                };
+                // The replies to pending requests, and messages queued for the old connection,
+                // are lost with it. Registrations are replayed once reconnected.
+                routes.waiters.clear();
+                routes.connected = false;
+                queue.clear();
+                if reconnect.policy.max_attempts == Some(attempts) {
+                    warn!(service = &*name, "giving up reconnecting");
+                    routes.emit(ConnectionEvent::GaveUp);
+                    guard.take();
+                    queue.abort();
+                    return;
+                }
+                routes.emit(match &failure {
+                    None => ConnectionEvent::Disconnected {
+                        error: error.clone(),
+                        retry_in: delay,
+                    },
+                    Some(e) => ConnectionEvent::AttemptFailed {
+                        error: e.clone(),
+                        attempts,
+                        retry_in: delay,
+                    },
+                });
+            }
+            runtime::sleep(delay).await;
+            attempts += 1;
+            delay = time::std_backoff(delay).min(reconnect.policy.max_delay);
+            match reconnect.connect(&name).await {
+                Ok(conn) => break conn,
+                Err(e) => {
+                    debug!(service = &*name, attempts, "failed to reconnect: {}", e);
+                    failure = Some(Arc::new(e));
+                }
+            }
+        };
+
+        // Registrations made from now on are queued behind the replayed ones, and the others
+        // weren't queued while disconnected, so each is sent once.
+        let registrations = match shared.lock().unwrap().as_mut() {
+            Some(routes) => {
+                routes.connected = true;
+                routes.registrations.clone()
+            }
+            None => {
+                queue.abort();
+                return;
+            }
+        };
+        for buf in registrations {
+            if let Err(e) = conn.send(&buf[..]).await {
+                debug!(service = &*name, "failed to replay registration: {}", e);
+                break;
+            }
+        }
+        debug!(service = &*name, attempts, "reconnected");
+        if let Some(routes) = shared.lock().unwrap().as_mut() {
+            routes.emit(ConnectionEvent::Reconnected { attempts });
+        }
+    }
+}
+
+/// Returns the error that ended the connection.
+async fn read_loop(mut recv: RecvHalf, shared: &Shared) -> io::Error {
+    loop {
+        match recv.recv().await {
+            Ok((typ, buf)) => dispatch(shared, typ, buf),
+            Err(e) => return e,
+        }
+    }
+}
+
+fn dispatch(shared: &Shared, typ: u16, buf: Buffer) {
+    let mut guard = shared.lock().unwrap();
+    let routes = match guard.as_mut() {
+        Some(r) => r,
+        None => return,
+    };
+
+    if let Some(i) = routes
+        .waiters
+        .iter()
+        .position(|w| w.reply.accepts(typ, &buf))
+    {
+        let waiter = &routes.waiters[i];
+        if let Some(tx) = &waiter.tx {
+            let _ = tx.unbounded_send((typ, buf));
+        }
+        if waiter.reply.last.contains(&typ) {
+            routes.waiters.remove(i);
+        }
+        return;
+    }
+
+    routes.subscribers.retain(|s| !s.tx.is_closed());
+    let mut delivered = false;
+    for s in routes.subscribers.iter().filter(|s| s.types.contains(&typ)) {
+        delivered |= s.tx.unbounded_send((typ, buf.clone())).is_ok();
+    }
+    if !delivered {
+        debug!(typ = %AnyMessageType::from_u16(typ), "no receiver for message, discarding");
+    }
+}
+
+pub(crate) fn to_buffer<M: MessageOutCompound>(msg: M) -> Buffer {
+    let mut buf = Buffer::new();
+    for chunk in msg.as_byte_chunks() {
+        buf.extend_from_slice(chunk.as_ref());
+    }
+    buf
+}
+
+/// A message can't be queued once the queue is closed, which only happens when the connection has
+/// been lost or closed. Other errors, such as for a message that is too long, are passed on.
+fn closed(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::NotConnected => disconnected(),
+        _ => e,
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the connection to the service was lost",
+    )
+}
+
+/// The messages answering a request, created by `Mux::request`.
+///
+/// Yields an error if the connection is lost before the request is complete.
+pub struct Replies {
+    shared: Shared,
+    key: u64,
+    last: SmallVec<[u16; 2]>,
+    rx: mpsc::UnboundedReceiver<Item>,
+    done: bool,
+}
+
+impl Stream for Replies {
+    type Item = Result<(u16, Buffer), io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match self.rx.poll_next_unpin(cx) {
+            Poll::Ready(Some((typ, buf))) => {
+                self.done = self.last.contains(&typ);
+                Poll::Ready(Some(Ok((typ, buf))))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(Some(Err(disconnected())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Replies {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(routes) = self.shared.lock().unwrap().as_mut() {
+            if let Some(i) = routes.waiters.iter().position(|w| w.key == self.key) {
+                if routes.waiters[i].reply.filter.is_some() {
+                    routes.waiters.remove(i);
+                } else {
+                    // Replies are matched by order, so the remaining ones must still be consumed.
+                    routes.waiters[i].tx = None;
+                }
+            }
+        }
+    }
+}
+
+/// Events about the state of the connection, created by `Mux::events`.
+pub struct Events {
+    rx: mpsc::UnboundedReceiver<ConnectionEvent>,
+}
+
+impl Stream for Events {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// Messages that don't answer a request, created by `Mux::subscribe`.
+///
+/// The stream ends when the connection is lost.
+pub struct Subscription {
+    rx: mpsc::UnboundedReceiver<Item>,
+}
+
+impl Stream for Subscription {
+    type Item = (u16, Buffer);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_mux() {
+    use crate::runtime::UnixStream;
+    use crate::util::MessageHeader;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mux = Mux::new(Connection::from_stream("client".to_string(), client));
+    let mut service = Connection::from_stream("service".to_string(), service);
+
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let dummy2 = MessageHeader::new(4, MessageType::DUMMY2);
+    let test = MessageHeader::new(4, MessageType::TEST);
+
+    let mut notifications = mux.subscribe(&[MessageType::DUMMY2]).unwrap();
+
+    // The first request is cancelled, but its reply must not be handed to the second.
+    let cancelled = mux
+        .request(&dummy, Reply::one_of(&[MessageType::DUMMY]))
+        .unwrap();
+    let many = mux
+        .request(
+            &dummy,
+            Reply::until(&[MessageType::DUMMY], &[MessageType::TEST]),
+        )
+        .unwrap();
+    drop(cancelled);
+    for _ in 0..2 {
+        service.recv().await.unwrap();
+    }
+
+    for msg in &[dummy, dummy2, dummy, dummy, test, dummy2] {
+        service.send(msg).await.unwrap();
+    }
+
+    let replies: Vec<u16> = many.map(|r| r.unwrap().0).collect().await;
+    assert_eq!(replies, [2, 2, 1]);
+    assert_eq!(notifications.next().await.unwrap().0, 3);
+    assert_eq!(notifications.next().await.unwrap().0, 3);
+
+    drop(service);
+    assert!(notifications.next().await.is_none());
+    assert!(!mux.is_connected());
+    assert!(mux.send(&dummy).is_err());
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_mux_queue() {
+    use crate::runtime::UnixStream;
+    use crate::service::{Envelope, Priority};
+    use crate::util::MessageHeader;
+    use zerocopy::AsBytes;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut client = Connection::from_stream("client".to_string(), client);
+    client.set_max_message_size(8);
+    let mux = Mux::new(client);
+    let mut service = Connection::from_stream("service".to_string(), service);
+
+    // A message that is too long is refused without losing the connection.
+    let mut long = MessageHeader::new(12, MessageType::DUMMY)
+        .as_bytes()
+        .to_vec();
+    long.extend_from_slice(&[0u8; 8]);
+    let e = mux.send(&long[..]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    // Messages queued directly are sent alongside the `Mux`'s own.
+    let queue = mux.queue();
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let ticket = queue
+        .send(Envelope::new(&dummy).with_priority(Priority::Urgent))
+        .await
+        .unwrap();
+    mux.send(&MessageHeader::new(4, MessageType::DUMMY2))
+        .unwrap();
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    assert_eq!(
+        service.recv().await.unwrap().0,
+        MessageType::DUMMY2.to_u16()
+    );
+    ticket.sent().await.unwrap();
+
+    // Closing the `Mux` closes its queue.
+    mux.close();
+    let e = queue.send(Envelope::new(&dummy)).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    assert!(mux.send(&dummy).is_err());
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_reconnect() {
+    use crate::util::MessageHeader;
+    use std::time::Duration;
+
+    let path =
+        std::env::temp_dir().join(format!("gnunet-rs-test-reconnect-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = crate::runtime::bind_unix(&path).await.unwrap();
+    let cfg = format!("[dummy]\nUNIXPATH = {}\n", path.display());
+    let cfg = Config::deserialize(cfg.as_bytes(), false).unwrap();
+
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        max_attempts: Some(3),
+    };
+    let mux = Mux::reconnecting(&cfg, "dummy", policy).await.unwrap();
+    let mut events = mux.events().unwrap();
+    let mut notifications = mux.subscribe(&[MessageType::DUMMY2]).unwrap();
+
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let dummy2 = MessageHeader::new(4, MessageType::DUMMY2);
+    mux.register(&dummy).unwrap();
+
+    let mut services = vec![];
+    for _ in 0..2 {
+        // Dropping the previous connection makes the client reconnect, and the registration is
+        // replayed on the new connection.
+        services.clear();
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut service = Connection::from_stream("service".to_string(), sock);
+        let (typ, _) = service.recv().await.unwrap();
+        assert_eq!(typ, MessageType::DUMMY.to_u16());
+        service.send(&dummy2).await.unwrap();
+        assert_eq!(notifications.next().await.unwrap().0, 3);
+        services.push(service);
+    }
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { .. }) => (),
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    match events.next().await {
+        Some(ConnectionEvent::Reconnected { attempts: 1 }) => (),
+        e => panic!("expected Reconnected, got {:?}", e),
+    }
+
+    // Nothing is listening any more, so reconnecting fails.
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+    services.clear();
+    // The lost connection is reported first, then each failed attempt but the last, which is
+    // followed by giving up.
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { error, .. }) => {
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof)
+        }
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    for n in 1..3 {
+        match events.next().await {
+            Some(ConnectionEvent::AttemptFailed {
+                error, attempts, ..
+            }) => {
+                assert_eq!(attempts, n);
+                assert!(matches!(*error, ConnectError::Io { .. }), "{:?}", error);
+            }
+            e => panic!("expected AttemptFailed, got {:?}", e),
+        }
+    }
+    match events.next().await {
+        Some(ConnectionEvent::GaveUp) => (),
+        e => panic!("expected GaveUp, got {:?}", e),
+    }
+    assert!(events.next().await.is_none());
+    assert!(notifications.next().await.is_none());
+    assert!(!mux.is_connected());
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_reconnect_verified() {
+    use crate::runtime::TcpListener;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!(
+        "gnunet-rs-test-reconnect-verified-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = crate::runtime::bind_unix(&path).await.unwrap();
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let cfg = format!(
+        "[dummy]\nUNIXPATH = {}\nHOSTNAME = 127.0.0.1\nPORT = {}\n",
+        path.display(),
+        tcp.local_addr().unwrap().port()
+    );
+    let cfg = Config::deserialize(cfg.as_bytes(), false).unwrap();
+
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(1),
+        max_attempts: Some(1),
+    };
+    let mux = Mux::reconnecting_verified(&cfg, "dummy", policy)
+        .await
+        .unwrap();
+    let mut events = mux.events().unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+
+    // Once the UNIX socket is gone, a verified connection can't fall back to TCP.
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+    drop(sock);
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { .. }) => (),
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    match events.next().await {
+        Some(ConnectionEvent::GaveUp) => (),
+        e => panic!("expected GaveUp, got {:?}", e),
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_reconnect_registrations() {
+    use crate::util::MessageHeader;
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!(
+        "gnunet-rs-test-reconnect-registrations-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = crate::runtime::bind_unix(&path).await.unwrap();
+    let cfg = format!("[dummy]\nUNIXPATH = {}\n", path.display());
+    let cfg = Config::deserialize(cfg.as_bytes(), false).unwrap();
+
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(50),
+        max_attempts: Some(1),
+    };
+    let mux = Mux::reconnecting(&cfg, "dummy", policy).await.unwrap();
+    let mut events = mux.events().unwrap();
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let dummy2 = MessageHeader::new(4, MessageType::DUMMY2);
+    let test = MessageHeader::new(4, MessageType::TEST);
+    mux.register(&dummy).unwrap();
+
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut service = Connection::from_stream("service".to_string(), sock);
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    drop(service);
+
+    // Register while the client waits to reconnect. The registration is only sent by the replay.
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { retry_in, .. }) => {
+            assert_eq!(retry_in, Duration::from_millis(50))
+        }
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    mux.register(&dummy2).unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut service = Connection::from_stream("service".to_string(), sock);
+    mux.send(&test).unwrap();
+    let mut received = vec![];
+    for _ in 0..3 {
+        received.push(service.recv().await.unwrap().0);
+    }
+    assert_eq!(received, [2, 3, 1]);
+
+    // The last attempt fails, and isn't followed by a retry.
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+    drop(service);
+    match events.next().await {
+        Some(ConnectionEvent::Reconnected { attempts: 1 }) => (),
+        e => panic!("expected Reconnected, got {:?}", e),
+    }
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { .. }) => (),
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    match events.next().await {
+        Some(ConnectionEvent::GaveUp) => (),
+        e => panic!("expected GaveUp, got {:?}", e),
+    }
+    assert!(events.next().await.is_none());
+}
@@ -1,49 +1,79 @@
-use crate::service;
-use crate::util::{expect_either, Config, ExpectError, Hello, Left, PeerIdentity, Right};
+use crate::service::request::{Exclusive, Resync};
+use crate::service::transaction::Response;
+use crate::service::{self, ReconnectPolicy, RequestError, TransactionError};
+use crate::util::{expect, Config, ExpectError, Hello, HelloMessage, MessageType, PeerIdentity};
+use futures::stream::{Stream, StreamExt};
 use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::debug;
 
 pub mod msg;
-use msg::{Info, InfoEnd};
+use msg::Info;
 
 // The peerinfo service can be sent the following message types:
 // HELLO - Hello struct - TODO add_peer
 // PEERINFO_GET - ListPeer struct
 // PEERINFO_GET_ALL - ListAllPeers struct
-// PEERINFO_NOTIFY - Notify struct - TODO
+// PEERINFO_NOTIFY - Notify struct - see `notify`
 
 // See https://docs.gnunet.org/handbook/gnunet.html#PEERINFO-Subsystem
 
 pub struct Client {
-    conn: service::Connection,
+    conn: Exclusive,
 }
 
 impl Client {
     pub async fn connect(cfg: &Config) -> Result<Client, PeerInfoError> {
         let conn = service::connect(cfg, "peerinfo").await?;
-        Ok(Client { conn })
+        Ok(Client {
+            conn: Exclusive::new(conn, Resync::DrainUntil(msg::Infos::ends)),
+        })
     }
 
-    pub async fn get_peer(&mut self, id: &PeerIdentity) -> Result<Option<Hello>, PeerInfoError> {
-        self.conn.send(&msg::ListPeer::new(false, *id)).await?;
+    /// Limit how long each later request may take. `None`, the default, means requests may wait
+    /// forever. The rest of the reply to a request that times out is discarded by the next
+    /// request; see `service::request`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.conn.set_timeout(timeout);
+    }
 
-        let (typ, buf) = self.conn.recv().await?;
-        match expect_either::<Info, InfoEnd>(typ, &buf)? {
-            Left(info) => Ok(Some(info.hello)),
-            Right(_) => Ok(None),
-        }
+    /// The limit set by `set_timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.conn.timeout()
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.conn.is_poisoned()
+    }
+
+    pub async fn get_peer(&mut self, id: &PeerIdentity) -> Result<Option<Hello>, PeerInfoError> {
+        let id = *id;
+        self.conn
+            .request(|mut conn| async move {
+                // The service sends the peer's HELLO if it has one, and then ends the list.
+                let mut hello = None;
+                let mut infos = conn.transaction(&msg::ListPeer::new(false, id)).await?;
+                while let Some(info) = infos.next().await? {
+                    hello = Some(info.hello.into_owned());
+                }
+                Ok(hello)
+            })
+            .await?
     }
 
     /// Returns a vector of all connected peers.
     ///
     /// # Example
     ///
-    /// ```rust
-    /// use async_std::task;
+    /// ```rust,no_run
+    /// use gnunet::runtime;
     /// use gnunet::util::Config;
     /// use gnunet::service::peerinfo;
     ///
     /// let config = Config::default().unwrap();
-    /// let peers = task::block_on(async {
+    /// let peers = runtime::block_on(async {
     ///     let mut pi = peerinfo::Client::connect(&config).await.unwrap();
     ///     pi.all_peers().await.unwrap()
     /// });
@@ -52,14 +82,72 @@ impl Client {
     /// }
     /// ```
     pub async fn all_peers(&mut self) -> Result<Vec<Hello>, PeerInfoError> {
-        self.conn.send(&msg::ListAllPeers::new(false)).await?;
         let mut v = Vec::new();
+        self.for_each_peer(|hello| v.push(hello.into_owned()))
+            .await?;
+        Ok(v)
+    }
+
+    /// Call `f` with the HELLO of every known peer, in turn.
+    ///
+    /// Each HELLO is borrowed from the connection's receive buffer rather than copied, which
+    /// saves allocating for every peer when all that's needed is eg. a count, or a few fields.
+    pub async fn for_each_peer<F>(&mut self, mut f: F) -> Result<(), PeerInfoError>
+    where
+        F: FnMut(HelloMessage<&str, &[u8]>) + Send,
+    {
+        self.conn
+            .request(|mut conn| async move {
+                let infos = conn.transaction(&msg::ListAllPeers::new(false)).await?;
+                infos.for_each(|info| f(info.hello)).await?;
+                Ok(())
+            })
+            .await?
+    }
+}
 
+/// Watch the peers known to the peerinfo service.
+///
+/// The service first sends the HELLO of every known peer, and then a HELLO whenever one changes.
+/// If the connection is lost it is re-established as described by `policy`, and the service then
+/// sends every known HELLO again.
+pub async fn notify(
+    cfg: &Config,
+    include_friend_only: bool,
+    policy: ReconnectPolicy,
+) -> Result<Notifications, PeerInfoError> {
+    let mux = service::Mux::reconnecting(cfg, "peerinfo", policy).await?;
+    let infos = mux.subscribe(&[MessageType::PEERINFO_INFO])?;
+    mux.register(&msg::Notify::new(include_friend_only))?;
+    Ok(Notifications { mux, infos })
+}
+
+/// A stream of HELLOs from the peerinfo service, created by `notify`.
+pub struct Notifications {
+    mux: service::Mux,
+    infos: service::Subscription,
+}
+
+impl Notifications {
+    /// Receive events about the state of the connection to the service.
+    pub fn events(&self) -> Result<service::Events, io::Error> {
+        self.mux.events()
+    }
+}
+
+impl Stream for Notifications {
+    type Item = Hello;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Hello>> {
         loop {
-            let (typ, buf) = self.conn.recv().await?;
-            match expect_either::<Info, InfoEnd>(typ, &buf)? {
-                Left(info) => v.push(info.hello),
-                Right(_) => return Ok(v),
+            let (typ, buf) = match self.infos.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match expect::<Info>(typ, &buf) {
+                Ok(info) => return Poll::Ready(Some(info.hello)),
+                Err(e) => debug!("ignoring notification: {}", e),
             }
         }
     }
@@ -86,4 +174,140 @@ pub enum PeerInfoError {
         #[from]
         source: service::ConnectError,
     },
+    #[error("The request did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("An earlier request failed part way through, so the client can no longer be used")]
+    Poisoned,
+}
+
+impl From<TransactionError> for PeerInfoError {
+    fn from(e: TransactionError) -> PeerInfoError {
+        match e {
+            TransactionError::Io { source } => PeerInfoError::Io { source },
+            TransactionError::UnexpectedResponse { source } => {
+                PeerInfoError::UnexpectedResponse { source }
+            }
+        }
+    }
+}
+
+impl From<RequestError> for PeerInfoError {
+    fn from(e: RequestError) -> PeerInfoError {
+        match e {
+            RequestError::TimedOut { timeout } => PeerInfoError::TimedOut { timeout },
+            RequestError::Poisoned => PeerInfoError::Poisoned,
+        }
+    }
+}
+
+#[cfg(test)]
+fn info(id: u8) -> Vec<u8> {
+    let mut b = vec![0, 80];
+    b.extend_from_slice(&MessageType::PEERINFO_INFO.to_u16().to_be_bytes());
+    b.extend_from_slice(&[0; 4]); // reserved
+    b.extend_from_slice(&[id; 32]);
+    // HELLO without addresses
+    b.extend_from_slice(&[0, 40]);
+    b.extend_from_slice(&MessageType::HELLO.to_u16().to_be_bytes());
+    b.extend_from_slice(&[0; 4]); // friend_only
+    b.extend_from_slice(&[id; 32]);
+    b
+}
+
+#[cfg(test)]
+fn info_end() -> Vec<u8> {
+    let mut b = vec![0, 4];
+    b.extend_from_slice(&MessageType::PEERINFO_INFO_END.to_u16().to_be_bytes());
+    b
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_all_peers() {
+    use crate::testing::MockService;
+    use crate::util::serial::AsBytes;
+
+    let mut mock = MockService::bind("peerinfo").await.unwrap();
+    mock.on(
+        MessageType::PEERINFO_GET_ALL,
+        vec![info(1), info(2), info_end()],
+    );
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut client = Client::connect(&cfg).await.unwrap();
+    let peers = client.all_peers().await.unwrap();
+    let ids: Vec<_> = peers.iter().map(|h| h.peer_id().as_bytes()[0]).collect();
+    assert_eq!(ids, [1, 2]);
+
+    let mut ids = vec![];
+    client
+        .for_each_peer(|hello| ids.push(hello.peer_id().as_bytes()[0]))
+        .await
+        .unwrap();
+    assert_eq!(ids, [1, 2]);
+    assert_eq!(mock.finish().await.unwrap().len(), 2);
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_timeout() {
+    use crate::testing::MockService;
+
+    // The service never finishes the list.
+    let mut mock = MockService::bind("peerinfo").await.unwrap();
+    mock.expect(MessageType::PEERINFO_GET_ALL, vec![]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut client = Client::connect(&cfg).await.unwrap();
+    client.set_timeout(Some(Duration::from_millis(50)));
+    match client.all_peers().await {
+        Err(PeerInfoError::TimedOut { timeout }) => {
+            assert_eq!(timeout, Duration::from_millis(50))
+        }
+        r => panic!("expected TimedOut, got {:?}", r.map(|_| ())),
+    }
+    // The next request waits for the end of the list, which never comes.
+    match client.get_peer(&PeerIdentity::default()).await {
+        Err(PeerInfoError::TimedOut { .. }) => (),
+        r => panic!("expected TimedOut, got {:?}", r.map(|_| ())),
+    }
+    mock.finish().await.unwrap();
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_resync() {
+    use crate::runtime::UnixStream;
+    use crate::service::Connection;
+    use crate::util::serial::AsBytes;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let mut client = Client {
+        conn: Exclusive::new(
+            Connection::from_stream("peerinfo".to_string(), client),
+            Resync::DrainUntil(msg::Infos::ends),
+        ),
+    };
+
+    client.set_timeout(Some(Duration::from_millis(50)));
+    assert!(client.all_peers().await.is_err());
+    let (typ, _) = service.recv().await.unwrap();
+    assert_eq!(typ, MessageType::PEERINFO_GET_ALL.to_u16());
+
+    // The rest of the list arrives late, followed by the reply to the next request.
+    for b in &[info(1), info(2), info_end(), info(3), info_end()] {
+        service.send(&b[..]).await.unwrap();
+    }
+    client.set_timeout(None);
+    let hello = client
+        .get_peer(&PeerIdentity::default())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(hello.peer_id().as_bytes()[0], 3);
+    let (typ, _) = service.recv().await.unwrap();
+    assert_eq!(typ, MessageType::PEERINFO_GET.to_u16());
 }
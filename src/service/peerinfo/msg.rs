@@ -1,5 +1,6 @@
+use crate::service::transaction::{Request, Response, Updates};
 use crate::util::serial::*;
-use crate::util::{Hello, MessageHeader, MessageIn, MessageType, PeerIdentity};
+use crate::util::{HelloMessage, MessageHeader, MessageIn, MessageType, PeerIdentity};
 use std::convert::TryInto;
 use std::mem::size_of;
 
@@ -40,32 +41,72 @@ impl ListAllPeers {
     }
 }
 
-#[derive(Debug, FromBytes)]
+impl Request for ListPeer {
+    type Reply = Infos;
+}
+
+impl Request for ListAllPeers {
+    type Reply = Infos;
+}
+
+#[derive(Debug, AsBytes, FromBytes)]
 #[repr(C)]
 pub struct Notify {
     pub header: MessageHeader,
     pub include_friend_only: u32be,
 }
 
-pub struct Info {
+impl Notify {
+    pub fn new(include_friend_only: bool) -> Notify {
+        Notify {
+            header: MessageHeader::new(
+                size_of::<Notify>().try_into().unwrap(),
+                MessageType::PEERINFO_NOTIFY,
+            ),
+            include_friend_only: U32::new(include_friend_only as u32),
+        }
+    }
+}
+
+impl Request for Notify {
+    type Reply = Updates<Info>;
+}
+
+/// The reply to `ListPeer` and `ListAllPeers`: an `Info` for each peer, borrowed from the
+/// connection, followed by an `InfoEnd`.
+pub struct Infos;
+
+impl Response for Infos {
+    type Item<'a> = Info<&'a str, &'a [u8]>;
+
+    fn ends(typ: u16, _msg: &[u8]) -> bool {
+        typ == MessageType::PEERINFO_INFO_END.to_u16()
+    }
+}
+
+/// Information about a peer. `Info<&str, &[u8]>` borrows the HELLO from the received message.
+pub struct Info<S = String, B = Vec<u8>> {
     prefix: InfoPrefix,
-    pub hello: Hello,
+    pub hello: HelloMessage<S, B>,
 }
 
-impl Info {
-    pub fn into_id_and_hello(self) -> (PeerIdentity, Hello) {
+impl<S, B> Info<S, B> {
+    pub fn into_id_and_hello(self) -> (PeerIdentity, HelloMessage<S, B>) {
         (self.prefix.peer_id, self.hello)
     }
 }
 
-impl MessageIn<'_> for Info {
+impl<'a, S, B> MessageIn<'a> for Info<S, B>
+where
+    S: From<&'a str>,
+    B: From<&'a [u8]>,
+{
     fn msg_type() -> MessageType {
         MessageType::PEERINFO_INFO
     }
-    fn from_bytes(b: &[u8]) -> Option<Self> {
+    fn from_bytes(b: &'a [u8]) -> Option<Self> {
         let (prefix, rest) = try_cast_prefix(b)?;
-        dbg!(prefix);
-        let hello = Hello::from_bytes(rest)?;
+        let hello = HelloMessage::from_bytes(rest)?;
         Some(Info {
             prefix: *prefix,
             hello,
@@ -0,0 +1,526 @@
+//! An outgoing message queue for a connection, modelled on GNUnet's MQ API.
+//!
+//! `SendHalf::into_queue` hands the sending side of a connection to a background task, which
+//! writes the messages put in the returned `MessageQueue` one at a time. A `Mux` sends everything
+//! through a queue of its own, which `Mux::queue` returns. Each message is written
+//! whole before the next is started. `Priority::Urgent` messages are written before any
+//! `Priority::Background` ones that are still waiting.
+//!
+//! The queue holds a bounded number of messages. `MessageQueue::send` waits for space, so that a
+//! producer can't get far ahead of the connection, while `MessageQueue::try_send` hands the
+//! message back if the queue is full. Each queued message has a `Ticket`, which can cancel it if
+//! it hasn't been written yet, or wait until it has been. A message longer than the connection's
+//! maximum message size is rejected when it is queued, so it can't fail the connection.
+
+use crate::runtime;
+use crate::service::mux::to_buffer;
+use crate::service::SendHalf;
+use crate::util::codec::FrameError;
+use crate::util::serial::Buffer;
+use crate::util::MessageOutCompound;
+use futures::channel::oneshot;
+use futures::future::poll_fn;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use tracing::debug;
+
+/// How urgently a queued message should be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent in the order queued, after any urgent messages.
+    Background,
+    /// Sent before any background messages that are waiting.
+    Urgent,
+}
+
+/// A message to be queued, along with how to send it.
+pub struct Envelope {
+    buf: Buffer,
+    priority: Priority,
+}
+
+impl Envelope {
+    /// Wrap `msg` to be sent with `Priority::Background`.
+    pub fn new<M: MessageOutCompound>(msg: M) -> Envelope {
+        Envelope::from_buffer(to_buffer(msg))
+    }
+
+    fn from_buffer(buf: Buffer) -> Envelope {
+        Envelope {
+            buf,
+            priority: Priority::Background,
+        }
+    }
+
+    /// Send the message with `priority`.
+    pub fn with_priority(mut self, priority: Priority) -> Envelope {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+impl fmt::Debug for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Envelope")
+            .field("len", &self.buf.len())
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Errors returned by `MessageQueue::try_send`.
+#[derive(Debug, Error)]
+pub enum TrySendError {
+    #[error("The queue is full")]
+    Full { envelope: Envelope },
+    #[error("The queue is closed")]
+    Closed { envelope: Envelope },
+    #[error("The message exceeds the maximum message size of {max}")]
+    TooLong { envelope: Envelope, max: usize },
+}
+
+impl TrySendError {
+    /// The message that couldn't be queued.
+    pub fn into_envelope(self) -> Envelope {
+        match self {
+            TrySendError::Full { envelope }
+            | TrySendError::Closed { envelope }
+            | TrySendError::TooLong { envelope, .. } => envelope,
+        }
+    }
+}
+
+/// A cloneable handle for queueing messages on a connection, created by `SendHalf::into_queue`.
+///
+/// Once every clone is dropped, or `close` is called, the messages still queued are sent and the
+/// sending side of the connection is closed.
+pub struct MessageQueue {
+    shared: Arc<Mutex<State>>,
+}
+
+struct State {
+    urgent: VecDeque<Queued>,
+    background: VecDeque<Queued>,
+    capacity: usize,
+    max_message_size: usize,
+    next_id: u64,
+    handles: usize,
+    /// No more messages will be accepted.
+    closed: bool,
+    writer: Option<Waker>,
+    senders: Vec<Waker>,
+}
+
+struct Queued {
+    id: u64,
+    buf: Buffer,
+    sent: oneshot::Sender<()>,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.urgent.len() + self.background.len()
+    }
+
+    fn push(&mut self, shared: &Arc<Mutex<State>>, envelope: Envelope) -> Ticket {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        let queued = Queued {
+            id,
+            buf: envelope.buf,
+            sent: tx,
+        };
+        match envelope.priority {
+            Priority::Urgent => self.urgent.push_back(queued),
+            Priority::Background => self.background.push_back(queued),
+        }
+        if let Some(w) = self.writer.take() {
+            w.wake();
+        }
+        Ticket {
+            shared: shared.clone(),
+            id,
+            sent: rx,
+        }
+    }
+
+    fn check(&self, buf: &Buffer) -> Result<(), io::Error> {
+        if buf.len() > self.max_message_size {
+            let e = FrameError::TooLong {
+                len: buf.len(),
+                max: self.max_message_size,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        Ok(())
+    }
+
+    /// Drop the messages waiting to be sent, which fails their tickets.
+    fn clear(&mut self) {
+        self.urgent.clear();
+        self.background.clear();
+        self.wake_senders();
+    }
+
+    fn wake_senders(&mut self) {
+        for w in self.senders.drain(..) {
+            w.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(w) = self.writer.take() {
+            w.wake();
+        }
+        self.wake_senders();
+    }
+}
+
+impl MessageQueue {
+    pub(crate) fn spawn(send: SendHalf, capacity: usize) -> MessageQueue {
+        let queue = MessageQueue::new(capacity, send.max_message_size());
+        runtime::spawn(write_loop(send, queue.writer()));
+        queue
+    }
+
+    pub(crate) fn new(capacity: usize, max_message_size: usize) -> MessageQueue {
+        MessageQueue {
+            shared: Arc::new(Mutex::new(State {
+                urgent: VecDeque::new(),
+                background: VecDeque::new(),
+                capacity: capacity.max(1),
+                max_message_size,
+                next_id: 0,
+                handles: 1,
+                closed: false,
+                writer: None,
+                senders: Vec::new(),
+            })),
+        }
+    }
+
+    /// The writer that sends the queued messages, for a task that writes them to one connection
+    /// after another. It doesn't count as a handle.
+    pub(crate) fn writer(&self) -> Writer {
+        Writer {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Queue `buf` with `Priority::Background`, even if the queue is full.
+    pub(crate) fn push(&self, buf: Buffer) -> Result<(), io::Error> {
+        let mut state = self.shared.lock().unwrap();
+        if state.closed {
+            return Err(closed());
+        }
+        state.check(&buf)?;
+        state.push(&self.shared, Envelope::from_buffer(buf));
+        Ok(())
+    }
+
+    /// Fails like `send` would if `buf` is too long to be sent.
+    pub(crate) fn check(&self, buf: &Buffer) -> Result<(), io::Error> {
+        self.shared.lock().unwrap().check(buf)
+    }
+
+    /// Queue `envelope`, waiting for space if the queue is full.
+    ///
+    /// Fails with an error of kind `NotConnected` if the queue has been closed, or the connection
+    /// has failed, and of kind `InvalidInput` if the message is too long to be sent.
+    pub async fn send(&self, envelope: Envelope) -> Result<Ticket, io::Error> {
+        self.check(&envelope.buf)?;
+        let mut envelope = Some(envelope);
+        poll_fn(|cx| {
+            let mut state = self.shared.lock().unwrap();
+            if state.closed {
+                return Poll::Ready(Err(closed()));
+            }
+            if state.len() < state.capacity {
+                let envelope = envelope.take().unwrap();
+                return Poll::Ready(Ok(state.push(&self.shared, envelope)));
+            }
+            state.senders.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Queue `envelope` if there is space for it.
+    pub fn try_send(&self, envelope: Envelope) -> Result<Ticket, TrySendError> {
+        let mut state = self.shared.lock().unwrap();
+        if state.closed {
+            Err(TrySendError::Closed { envelope })
+        } else if envelope.buf.len() > state.max_message_size {
+            let max = state.max_message_size;
+            Err(TrySendError::TooLong { envelope, max })
+        } else if state.len() >= state.capacity {
+            Err(TrySendError::Full { envelope })
+        } else {
+            Ok(state.push(&self.shared, envelope))
+        }
+    }
+
+    /// The number of messages waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.shared.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of messages that can wait to be sent at once.
+    pub fn capacity(&self) -> usize {
+        self.shared.lock().unwrap().capacity
+    }
+
+    /// Stop accepting messages. The messages already queued are still sent, after which the
+    /// sending side of the connection is closed.
+    pub fn close(&self) {
+        self.shared.lock().unwrap().close();
+    }
+}
+
+impl Clone for MessageQueue {
+    fn clone(&self) -> MessageQueue {
+        self.shared.lock().unwrap().handles += 1;
+        MessageQueue {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for MessageQueue {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.handles -= 1;
+        if state.handles == 0 {
+            state.close();
+        }
+    }
+}
+
+impl fmt::Debug for MessageQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.shared.lock().unwrap();
+        f.debug_struct("MessageQueue")
+            .field("len", &state.len())
+            .field("capacity", &state.capacity)
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+/// A message in a `MessageQueue`. Dropping the ticket doesn't cancel the message.
+pub struct Ticket {
+    shared: Arc<Mutex<State>>,
+    id: u64,
+    sent: oneshot::Receiver<()>,
+}
+
+impl Ticket {
+    /// Remove the message from the queue. Returns `false` if it is already being written, or has
+    /// been.
+    pub fn cancel(self) -> bool {
+        fn remove(queue: &mut VecDeque<Queued>, id: u64) -> bool {
+            match queue.iter().position(|q| q.id == id) {
+                Some(i) => queue.remove(i).is_some(),
+                None => false,
+            }
+        }
+
+        let mut state = self.shared.lock().unwrap();
+        let removed = remove(&mut state.urgent, self.id) || remove(&mut state.background, self.id);
+        if removed {
+            state.wake_senders();
+        }
+        removed
+    }
+
+    /// Wait until the message has been written to the connection.
+    ///
+    /// Fails with an error of kind `NotConnected` if the connection failed before the message
+    /// could be written.
+    pub async fn sent(self) -> Result<(), io::Error> {
+        self.sent.await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection failed before the message was sent",
+            )
+        })
+    }
+}
+
+impl fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ticket").field("id", &self.id).finish()
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the message queue is closed")
+}
+
+/// Sends the messages of a `MessageQueue`, created by `MessageQueue::writer`.
+pub(crate) struct Writer {
+    shared: Arc<Mutex<State>>,
+}
+
+impl Writer {
+    /// Write the queued messages to `send` until the queue is closed and empty, and then close
+    /// `send`. If writing fails, the message being written is lost but the queue stays open.
+    pub(crate) async fn write(&self, mut send: SendHalf) -> Result<(), io::Error> {
+        self.shared.lock().unwrap().max_message_size = send.max_message_size();
+        loop {
+            let next = poll_fn(|cx| {
+                let mut state = self.shared.lock().unwrap();
+                let next = match state.urgent.pop_front() {
+                    Some(q) => Some(q),
+                    None => state.background.pop_front(),
+                };
+                match next {
+                    Some(q) => {
+                        state.wake_senders();
+                        Poll::Ready(Some(q))
+                    }
+                    None if state.closed => Poll::Ready(None),
+                    None => {
+                        state.writer = Some(cx.waker().clone());
+                        Poll::Pending
+                    }
+                }
+            })
+            .await;
+
+            let queued = match next {
+                Some(q) => q,
+                None => return send.close().await,
+            };
+            send.send(&queued.buf[..]).await?;
+            let _ = queued.sent.send(());
+        }
+    }
+
+    /// Drop the messages waiting to be sent, eg. because they were meant for a connection that
+    /// was lost.
+    pub(crate) fn clear(&self) {
+        self.shared.lock().unwrap().clear();
+    }
+
+    /// Drop the messages waiting to be sent, and stop accepting more.
+    pub(crate) fn abort(&self) {
+        let mut state = self.shared.lock().unwrap();
+        state.clear();
+        state.close();
+    }
+}
+
+/// Writes the queued messages until the queue is closed and empty, or writing fails.
+async fn write_loop(send: SendHalf, writer: Writer) {
+    if let Err(e) = writer.write(send).await {
+        debug!("failed to send queued message: {}", e);
+        writer.abort();
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_message_queue() {
+    use crate::runtime::UnixStream;
+    use crate::service::Connection;
+    use crate::util::{MessageHeader, MessageType};
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let (_, send) = Connection::from_stream("dummy".to_string(), client).split();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let msg = |typ| Envelope::new(&MessageHeader::new(4, typ));
+
+    // Fill the queue before the writer starts.
+    let queue = MessageQueue::new(3, send.max_message_size());
+    let first = queue.try_send(msg(MessageType::DUMMY)).unwrap();
+    let urgent = queue
+        .try_send(msg(MessageType::DUMMY2).with_priority(Priority::Urgent))
+        .unwrap();
+    let cancelled = queue.try_send(msg(MessageType::ARM_START)).unwrap();
+    match queue.try_send(msg(MessageType::ARM_STOP)) {
+        Err(TrySendError::Full { envelope }) => {
+            assert_eq!(envelope.priority(), Priority::Background)
+        }
+        r => panic!("expected Full, got {:?}", r),
+    }
+    assert!(cancelled.cancel());
+    assert_eq!(queue.len(), 2);
+    runtime::spawn(write_loop(send, queue.writer()));
+
+    // The urgent message overtakes the one queued before it.
+    assert_eq!(
+        service.recv().await.unwrap().0,
+        MessageType::DUMMY2.to_u16()
+    );
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    urgent.sent().await.unwrap();
+    first.sent().await.unwrap();
+
+    let last = queue.send(msg(MessageType::ARM_LIST)).await.unwrap();
+    assert_eq!(
+        service.recv().await.unwrap().0,
+        MessageType::ARM_LIST.to_u16()
+    );
+    last.sent().await.unwrap();
+
+    // Dropping the last handle closes the connection once the queue is empty.
+    let clone = queue.clone();
+    drop(queue);
+    clone.try_send(msg(MessageType::DUMMY)).unwrap();
+    drop(clone);
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    let e = service.recv().await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_message_queue_too_long() {
+    use crate::runtime::UnixStream;
+    use crate::service::Connection;
+    use crate::util::{MessageHeader, MessageType};
+    use zerocopy::AsBytes;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut client = Connection::from_stream("dummy".to_string(), client);
+    client.set_max_message_size(8);
+    let (_, send) = client.split();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let queue = send.into_queue(2);
+
+    // A message longer than the connection allows is handed back, and the queue carries on.
+    let mut long = MessageHeader::new(12, MessageType::DUMMY)
+        .as_bytes()
+        .to_vec();
+    long.extend_from_slice(&[0u8; 8]);
+    match queue.try_send(Envelope::new(&long[..])) {
+        Err(TrySendError::TooLong { max: 8, .. }) => (),
+        r => panic!("expected TooLong, got {:?}", r),
+    }
+    let e = queue.send(Envelope::new(&long[..])).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    let short = queue
+        .send(Envelope::new(&MessageHeader::new(4, MessageType::DUMMY2)))
+        .await
+        .unwrap();
+    assert_eq!(
+        service.recv().await.unwrap().0,
+        MessageType::DUMMY2.to_u16()
+    );
+    short.sent().await.unwrap();
+}
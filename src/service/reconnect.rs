@@ -0,0 +1,53 @@
+//! Types describing how a `Mux` re-establishes a lost connection. See `Mux::reconnecting`.
+
+use crate::service::ConnectError;
+use crate::util::time;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How to retry after the connection to a service is lost.
+///
+/// The delay before each attempt starts at `initial_delay` and doubles after every failed attempt
+/// (see `util::time::std_backoff`), up to `max_delay`.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: time::STD_EXPONENTIAL_BACKOFF_THRESHOLD,
+            max_attempts: None,
+        }
+    }
+}
+
+/// A change in the state of a reconnecting connection.
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// The connection was lost. Pending requests have failed. The first attempt to reconnect is
+    /// made after `retry_in`.
+    Disconnected {
+        error: Arc<io::Error>,
+        retry_in: Duration,
+    },
+    /// Attempt number `attempts` to reconnect failed. The next attempt is made after `retry_in`.
+    AttemptFailed {
+        error: Arc<ConnectError>,
+        attempts: u32,
+        retry_in: Duration,
+    },
+    /// The connection was re-established after `attempts` attempts, and registrations have been
+    /// sent again.
+    Reconnected { attempts: u32 },
+    /// The connection was lost, and `ReconnectPolicy::max_attempts` had been reached. The
+    /// connection is closed for good. Sent instead of a final `AttemptFailed`, or instead of
+    /// `Disconnected` if no attempts are allowed.
+    GaveUp,
+}
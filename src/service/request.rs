@@ -0,0 +1,298 @@
+//! Time limits on requests to services, and what happens to a connection when a request doesn't
+//! complete.
+//!
+//! Every service client has a `set_timeout` method, which limits how long each later request may
+//! take. By default requests wait forever. A request that times out fails with the client's
+//! `TimedOut` error.
+//!
+//! A request doesn't complete if it times out, or if its future is dropped. Either way, the rest
+//! of its reply may still arrive. Each client makes sure that the reply isn't mistaken for the
+//! reply to a later request:
+//!
+//! * Clients that send their requests through a `Mux`, such as `arm::Client` and
+//!   `gns::LookupHandle`, tell the replies to different requests apart by id. Replies to
+//!   requests that didn't complete are discarded.
+//! * `gns::Client` sends one request at a time, but also discards results whose id doesn't match
+//!   the current lookup.
+//! * `peerinfo::Client` and `identity::Client` know which messages end each reply, such as
+//!   PEERINFO_INFO_END, or the IDENTITY_UPDATE with `end_of_list` set that ends the list of egos.
+//!   The next request first receives and discards whatever is left of an unfinished reply, up to
+//!   and including that message. This counts against the next request's timeout.
+//!
+//! A message is either sent whole or not at all, even if the request is interrupted while sending
+//! it. Only an I/O error, or a received message that can't be framed, leaves the connection in an
+//! unknown state. The connection is then poisoned, and every later request fails with the
+//! client's `Poisoned` error. Connect again to continue.
+
+use crate::runtime::{self, Elapsed};
+use crate::service::transaction::{Channel, Request, Transaction};
+use crate::service::Connection;
+use crate::util::serial::Buffer;
+use crate::util::{AnyMessageType, MessageOutCompound};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tracing::debug;
+
+/// Errors from requests that didn't complete.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("The request did not complete within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("An earlier request on this connection failed part way through, so the connection can no longer be used")]
+    Poisoned,
+}
+
+/// Wait for `fut`, giving up once `timeout` has passed if it isn't `None`.
+pub(crate) async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    fut: F,
+) -> Result<F::Output, Elapsed> {
+    match timeout {
+        Some(d) => runtime::timeout(d, fut).await,
+        None => Ok(fut.await),
+    }
+}
+
+/// How an `Exclusive` connection recovers from a request that didn't complete.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Resync {
+    /// The requests themselves recognise and discard replies to earlier requests.
+    Discard,
+    /// Receive and discard messages up to and including one for which the function returns true,
+    /// which ends a reply.
+    DrainUntil(fn(u16, &[u8]) -> bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// A request was sent, and the end of its reply hasn't been received.
+    Owed,
+    Poisoned,
+}
+
+/// A connection that is used for one request at a time.
+pub(crate) struct Exclusive {
+    conn: Connection,
+    timeout: Option<Duration>,
+    resync: Resync,
+    state: State,
+}
+
+impl Exclusive {
+    pub(crate) fn new(conn: Connection, resync: Resync) -> Exclusive {
+        Exclusive {
+            conn,
+            timeout: None,
+            resync,
+            state: State::Idle,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.state == State::Poisoned
+    }
+
+    pub(crate) fn into_inner(self) -> Connection {
+        self.conn
+    }
+
+    /// Make a request by running `f`, which sends and receives through the `Exchange` it is
+    /// given, within the timeout.
+    pub(crate) async fn request<'a, F, Fut>(&'a mut self, f: F) -> Result<Fut::Output, RequestError>
+    where
+        F: FnOnce(Exchange<'a>) -> Fut,
+        Fut: Future + 'a,
+    {
+        let Exclusive {
+            conn,
+            timeout,
+            resync,
+            state,
+        } = self;
+        if *state == State::Poisoned {
+            return Err(RequestError::Poisoned);
+        }
+        let exchange = Exchange {
+            conn,
+            resync: *resync,
+            state,
+        };
+        with_timeout(*timeout, f(exchange))
+            .await
+            .map_err(|Elapsed(timeout)| RequestError::TimedOut { timeout })
+    }
+}
+
+/// The connection as seen by a request, which keeps track of how much of the reply is left.
+pub(crate) struct Exchange<'a> {
+    conn: &'a mut Connection,
+    resync: Resync,
+    state: &'a mut State,
+}
+
+impl Exchange<'_> {
+    /// Send the request, after discarding what's left of the reply to an earlier one.
+    pub(crate) async fn send<M: MessageOutCompound>(&mut self, msg: M) -> Result<(), io::Error> {
+        if *self.state == State::Owed {
+            self.drain().await?;
+        }
+        // If this is interrupted, the message hasn't been buffered and no reply is owed.
+        self.conn.feed(msg).await.map_err(|e| self.poison(e))?;
+        *self.state = State::Owed;
+        self.conn.flush().await.map_err(|e| self.poison(e))
+    }
+
+    /// Receive a message of the reply.
+    pub(crate) async fn recv(&mut self) -> Result<(u16, Buffer), io::Error> {
+        let (typ, buf) = self.recv_borrowed().await?;
+        Ok((typ, Buffer::from_slice(buf)))
+    }
+
+    /// Receive a message of the reply, borrowed from the connection. See
+    /// `Connection::recv_borrowed`.
+    pub(crate) async fn recv_borrowed(&mut self) -> Result<(u16, &[u8]), io::Error> {
+        self.recv_borrowed_ending(|_, _| false).await
+    }
+
+    /// Like `recv_borrowed`, but the reply also ends with a message for which `ends` returns true.
+    pub(crate) async fn recv_borrowed_ending<F>(
+        &mut self,
+        ends: F,
+    ) -> Result<(u16, &[u8]), io::Error>
+    where
+        F: FnOnce(u16, &[u8]) -> bool,
+    {
+        let Exchange {
+            conn,
+            resync,
+            state,
+        } = self;
+        let (typ, buf) = match conn.recv_borrowed().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                **state = State::Poisoned;
+                return Err(e);
+            }
+        };
+        let drained = match resync {
+            Resync::DrainUntil(ends_reply) => ends_reply(typ, buf),
+            Resync::Discard => false,
+        };
+        if drained || ends(typ, buf) {
+            **state = State::Idle;
+        }
+        Ok((typ, buf))
+    }
+
+    /// Send `req`, and receive its reply through the returned `Transaction`.
+    pub(crate) async fn transaction<Q>(
+        &mut self,
+        req: &Q,
+    ) -> Result<Transaction<'_, Q::Reply>, io::Error>
+    where
+        Q: Request,
+        for<'r> &'r Q: MessageOutCompound,
+    {
+        self.send(req).await?;
+        let exchange = Exchange {
+            conn: &mut *self.conn,
+            resync: self.resync,
+            state: &mut *self.state,
+        };
+        Ok(Transaction::new(Channel::Exchange(exchange)))
+    }
+
+    async fn drain(&mut self) -> Result<(), io::Error> {
+        if let Resync::Discard = self.resync {
+            *self.state = State::Idle;
+        }
+        while *self.state == State::Owed {
+            let (typ, _) = self.recv_borrowed().await?;
+            debug!(
+                typ = %AnyMessageType::from_u16(typ),
+                "discarding reply to an unfinished request"
+            );
+        }
+        Ok(())
+    }
+
+    fn poison(&mut self, e: io::Error) -> io::Error {
+        *self.state = State::Poisoned;
+        e
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_exclusive() {
+    use crate::runtime::UnixStream;
+    use crate::util::{MessageHeader, MessageType};
+    use futures::FutureExt;
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let mut conn = Exclusive::new(
+        Connection::from_stream("dummy".to_string(), client),
+        Resync::DrainUntil(|typ, _| typ == MessageType::DUMMY2.to_u16()),
+    );
+    async fn request(mut x: Exchange<'_>) -> Result<(), io::Error> {
+        x.send(&MessageHeader::new(4, MessageType::DUMMY)).await?;
+        loop {
+            let (typ, _) = x.recv().await?;
+            if typ == MessageType::DUMMY2.to_u16() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Dropping a request before it sends anything leaves nothing to drain.
+    assert!(conn
+        .request(|_| futures::future::pending::<()>())
+        .now_or_never()
+        .is_none());
+    assert_eq!(conn.request(|_| async { 1 }).await, Ok(1));
+
+    // Give up on a request before its reply arrives.
+    conn.set_timeout(Some(Duration::from_millis(50)));
+    match conn.request(request).await {
+        Err(RequestError::TimedOut { .. }) => (),
+        r => panic!("expected TimedOut, got {:?}", r),
+    }
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    service
+        .send(&MessageHeader::new(4, MessageType::DUMMY))
+        .await
+        .unwrap();
+
+    // The next request discards the rest of the reply before sending.
+    match conn.request(request).await {
+        Err(RequestError::TimedOut { .. }) => (),
+        r => panic!("expected TimedOut, got {:?}", r),
+    }
+    for typ in &[MessageType::DUMMY2, MessageType::DUMMY, MessageType::DUMMY2] {
+        service.send(&MessageHeader::new(4, *typ)).await.unwrap();
+    }
+    conn.set_timeout(None);
+    assert!(conn.request(request).await.unwrap().is_ok());
+    // The request that timed out while draining never sent anything.
+    assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    assert!(service.recv().now_or_never().is_none());
+
+    // An I/O error poisons the connection.
+    drop(service);
+    assert!(conn.request(request).await.unwrap().is_err());
+    assert_eq!(
+        conn.request(|_| async { 2 }).await,
+        Err(RequestError::Poisoned)
+    );
+}
@@ -0,0 +1,751 @@
+//! A framework for implementing GNUnet services.
+//!
+//! A `Server` listens where its section of the config says a service should be found, so that
+//! clients (including those written in C) can reach it with `service::connect`. Each message a
+//! client sends is dispatched by its type to a handler. A client's messages are handled one at a
+//! time, in the order they arrive, while different clients are served concurrently.
+//!
+//! Handlers are passed a `ClientHandle`, which can be cloned and kept to send notifications to the
+//! client later. If a handler fails, or a client sends a message that no handler accepts, the
+//! client is disconnected.
+//!
+//! The following options of the service's config section are used:
+//!
+//! * `UNIXPATH`, the UNIX socket to listen on. A stale socket left at the path is replaced. On
+//!   Linux, a path starting with `@` names a socket in the abstract namespace instead, which is
+//!   only protected by `UNIX_MATCH_UID` and `UNIX_MATCH_GID`.
+//! * `PORT` and `BINDTO`, the TCP port and address to listen on. `BINDTO` defaults to all IPv4
+//!   addresses.
+//! * `UNIX_MATCH_UID` and `UNIX_MATCH_GID`. If either is `YES`, UNIX clients must run as the same
+//!   user, or in the same group, as the service.
+//! * `ACCEPT_FROM` and `ACCEPT_FROM6`, the networks that TCP clients may connect from, written as
+//!   eg. `127.0.0.1;10.0.0.0/8;` and `::1;fe80::/10;`. Without them, any address is accepted.
+//!
+//! # Socket activation
+//!
+//! ARM (or systemd) can bind a service's sockets itself, and only start the service once a client
+//! connects. It passes the sockets to the service as the file descriptors from 3 onwards, with
+//! their number in the `LISTEN_FDS` environment variable. If `LISTEN_PID` is also set, the sockets
+//! are only meant for the process with that ID. `Server::bind` serves clients on these sockets
+//! instead of binding the configured ones. A passed socket may also be a connected one, which is
+//! served as a single client.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use gnunet::service::arm::{msg, ArmResult};
+//! use gnunet::service::server::ClientHandle;
+//! use gnunet::service::Server;
+//! use gnunet::util::Config;
+//!
+//! gnunet::runtime::block_on(async {
+//!     let cfg = Config::default().unwrap();
+//!     let mut server = Server::new("my-arm");
+//!     server.handle(|client: ClientHandle, req: msg::Start<String>| async move {
+//!         println!("asked to start {}", req.service);
+//!         let id = req.prefix.request_id.get();
+//!         client.send(&msg::ArmResultMessage::new(id, ArmResult::StartFailed))
+//!     });
+//!     let err = server.bind(&cfg).await.unwrap().run().await;
+//!     println!("server stopped: {}", err);
+//! });
+//! ```
+
+use crate::runtime::{self, TcpListener, TcpStream, UnixListener, UnixStream};
+use crate::service::access::{peer_ids, AccessPolicy, InvalidOption};
+use crate::service::connection::UnixPath;
+use crate::service::mux::to_buffer;
+use crate::service::{Connection, RecvHalf, SendHalf};
+use crate::util::serial::Buffer;
+use crate::util::{config, expect, Config, MessageIn, MessageOutCompound, MessageType};
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture, FutureExt, RemoteHandle};
+use futures::{pin_mut, select, StreamExt};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+mod activation;
+use activation::Inherited;
+
+type Handler = Box<dyn Fn(ClientHandle, u16, Buffer) -> HandlerFuture + Send + Sync>;
+type HandlerFuture = BoxFuture<'static, Result<(), io::Error>>;
+type Callback = Box<dyn Fn(&ClientHandle) + Send + Sync>;
+
+/// Errors returned by `Server::bind`.
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("The configuration does not describe where to listen for clients.\nConfig section [{name}] contains neither UNIXPATH nor PORT")]
+    NotConfigured { name: String },
+    #[error("The service's UNIXPATH could not be determined. Reason: {source}")]
+    InvalidUnixPath {
+        #[from]
+        source: config::ConfigGetFilenameError,
+    },
+    #[error("The service's PORT is not a valid port number. Reason: {source}")]
+    InvalidPort {
+        #[from]
+        source: config::ConfigGetIntError,
+    },
+    #[error("The service's PORT ({port}) is out of range")]
+    PortOutOfRange { port: u64 },
+    #[error("The option {key} in config section [{name}] has an invalid value: '{value}'")]
+    InvalidOption {
+        name: String,
+        key: String,
+        value: String,
+    },
+    #[error("There was an I/O error setting up the service's sockets. Specifically {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+}
+
+impl From<InvalidOption> for ServerError {
+    fn from(e: InvalidOption) -> ServerError {
+        let InvalidOption { name, key, value } = e;
+        ServerError::InvalidOption { name, key, value }
+    }
+}
+
+/// Builds a service by registering handlers for the messages it accepts. See the module docs.
+pub struct Server {
+    name: String,
+    handlers: HashMap<u16, Handler>,
+    fallback: Option<Handler>,
+    on_connect: Option<Callback>,
+    on_disconnect: Option<Callback>,
+}
+
+impl Server {
+    /// Start building the service `name`, which is also the name of its config section.
+    pub fn new(name: &str) -> Server {
+        Server {
+            name: name.to_string(),
+            handlers: HashMap::new(),
+            fallback: None,
+            on_connect: None,
+            on_disconnect: None,
+        }
+    }
+
+    /// Handle messages of type `M` with `f`.
+    ///
+    /// A message of the right type that fails to parse disconnects the client.
+    pub fn handle<M, F, Fut>(&mut self, f: F) -> &mut Server
+    where
+        M: for<'a> MessageIn<'a> + Send + 'static,
+        F: Fn(ClientHandle, M) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+    {
+        let handler = move |client, typ, buf: Buffer| match expect::<M>(typ, &buf) {
+            Ok(msg) => f(client, msg).boxed(),
+            Err(e) => future::err(io::Error::new(io::ErrorKind::InvalidData, e)).boxed(),
+        };
+        self.handlers
+            .insert(M::msg_type().to_u16(), Box::new(handler));
+        self
+    }
+
+    /// Handle messages of type `typ` with `f`, which is passed the whole message, header included.
+    pub fn handle_raw<F, Fut>(&mut self, typ: MessageType, f: F) -> &mut Server
+    where
+        F: Fn(ClientHandle, Buffer) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+    {
+        let handler = move |client, _, buf| f(client, buf).boxed();
+        self.handlers.insert(typ.to_u16(), Box::new(handler));
+        self
+    }
+
+    /// Handle messages that no other handler accepts with `f`, which is passed their type and the
+    /// whole message. Without a fallback, such messages disconnect the client.
+    pub fn fallback<F, Fut>(&mut self, f: F) -> &mut Server
+    where
+        F: Fn(ClientHandle, u16, Buffer) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), io::Error>> + Send + 'static,
+    {
+        self.fallback = Some(Box::new(move |client, typ, buf| {
+            f(client, typ, buf).boxed()
+        }));
+        self
+    }
+
+    /// Call `f` whenever a client connects, before any of its messages are handled.
+    pub fn on_connect<F>(&mut self, f: F) -> &mut Server
+    where
+        F: Fn(&ClientHandle) + Send + Sync + 'static,
+    {
+        self.on_connect = Some(Box::new(f));
+        self
+    }
+
+    /// Call `f` whenever a client disconnects, or is disconnected.
+    pub fn on_disconnect<F>(&mut self, f: F) -> &mut Server
+    where
+        F: Fn(&ClientHandle) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Box::new(f));
+        self
+    }
+
+    /// Start listening for clients.
+    ///
+    /// If the process that started the service passed it sockets through `LISTEN_FDS`, the first
+    /// `bind` in the process uses those. Otherwise the sockets configured in `cfg` are bound. See
+    /// the module docs.
+    pub async fn bind(self, cfg: &Config) -> Result<Listening, ServerError> {
+        let policy = AccessPolicy::from_config(cfg, &self.name)?;
+        let inherited = activation::take_listen_fds()?;
+        let sockets = if inherited.is_empty() {
+            Sockets::bind(cfg, &self.name, &policy).await?
+        } else {
+            debug!(
+                service = &*self.name,
+                "using {} sockets from LISTEN_FDS",
+                inherited.len()
+            );
+            Sockets::inherit(inherited)?
+        };
+
+        let Server {
+            name,
+            handlers,
+            fallback,
+            on_connect,
+            on_disconnect,
+        } = self;
+        Ok(Listening {
+            shared: Arc::new(Shared {
+                name,
+                handlers,
+                fallback,
+                on_connect,
+                on_disconnect,
+                policy,
+                next_id: AtomicU64::new(0),
+            }),
+            sockets,
+        })
+    }
+}
+
+/// A `Server` whose sockets are bound, created by `Server::bind`.
+pub struct Listening {
+    shared: Arc<Shared>,
+    sockets: Sockets,
+}
+
+struct Shared {
+    name: String,
+    handlers: HashMap<u16, Handler>,
+    fallback: Option<Handler>,
+    on_connect: Option<Callback>,
+    on_disconnect: Option<Callback>,
+    policy: AccessPolicy,
+    next_id: AtomicU64,
+}
+
+impl Listening {
+    /// The path of the UNIX socket the service bound, if any. Sockets in the abstract namespace,
+    /// and those passed through `LISTEN_FDS`, aren't included.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.sockets.unix.iter().find_map(|s| s.path.as_deref())
+    }
+
+    /// The address of the TCP socket the service is listening on, if any.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        self.sockets.tcp.iter().find_map(|l| l.local_addr().ok())
+    }
+
+    /// Accept and serve clients until accepting a connection fails, returning the error.
+    ///
+    /// The UNIX socket the service bound is removed when this returns, or is cancelled. Clients
+    /// that are already connected continue to be served.
+    pub async fn run(self) -> io::Error {
+        let Listening { shared, sockets } = self;
+        let Sockets {
+            unix,
+            tcp,
+            unix_clients,
+            tcp_clients,
+        } = sockets;
+        for sock in unix_clients {
+            admit_unix(&shared, sock);
+        }
+        for sock in tcp_clients {
+            match sock.peer_addr() {
+                Ok(addr) => admit_tcp(&shared, sock, addr),
+                Err(e) => debug!(service = &*shared.name, "rejected TCP client: {}", e),
+            }
+        }
+
+        let shared = &shared;
+        let mut accepting: Vec<BoxFuture<'_, io::Error>> = vec![];
+        accepting.extend(
+            unix.iter()
+                .map(|s| accept_unix(&s.listener, shared).boxed()),
+        );
+        accepting.extend(tcp.iter().map(|l| accept_tcp(l, shared).boxed()));
+        if accepting.is_empty() {
+            return future::pending().await;
+        }
+        let (e, _, _) = future::select_all(accepting).await;
+        e
+    }
+
+    /// Run the service in the background, until the returned handle is dropped.
+    pub fn spawn(self) -> ServerHandle {
+        let (fut, task) = self.run().remote_handle();
+        runtime::spawn(fut);
+        ServerHandle { _task: task }
+    }
+}
+
+/// A running `Server`, created by `Listening::spawn`. Dropping it stops accepting clients.
+pub struct ServerHandle {
+    _task: RemoteHandle<io::Error>,
+}
+
+/// The sockets a `Server` serves clients on.
+#[derive(Default)]
+struct Sockets {
+    unix: Vec<UnixSocket>,
+    tcp: Vec<TcpListener>,
+    /// Clients that connected to a socket passed through `LISTEN_FDS` before the service started.
+    unix_clients: Vec<UnixStream>,
+    tcp_clients: Vec<TcpStream>,
+}
+
+impl Sockets {
+    /// Bind the sockets configured in the service's section of `cfg`.
+    async fn bind(cfg: &Config, name: &str, policy: &AccessPolicy) -> Result<Sockets, ServerError> {
+        let unixpath = UnixPath::from_config(cfg, name)?;
+        let port = match cfg.get_int(name, "PORT") {
+            Ok(0)
+            | Err(config::ConfigGetIntError::NoSection)
+            | Err(config::ConfigGetIntError::NoKey) => None,
+            Ok(port) => Some(
+                port.try_into()
+                    .map_err(|_| ServerError::PortOutOfRange { port })?,
+            ),
+            Err(e) => return Err(e.into()),
+        };
+        if unixpath.is_none() && port.is_none() {
+            return Err(ServerError::NotConfigured {
+                name: name.to_string(),
+            });
+        }
+
+        let mut sockets = Sockets::default();
+        if let Some(path) = unixpath {
+            sockets.unix.push(UnixSocket::bind(path, policy).await?);
+        }
+        if let Some(port) = port {
+            let addr = match cfg.get_string(name, "BINDTO") {
+                Ok(addr) if !addr.is_empty() => addr,
+                _ => "0.0.0.0",
+            };
+            sockets.tcp.push(TcpListener::bind((addr, port)).await?);
+        }
+        Ok(sockets)
+    }
+
+    fn inherit(inherited: Vec<Inherited>) -> Result<Sockets, io::Error> {
+        let mut sockets = Sockets::default();
+        for socket in inherited {
+            match socket {
+                Inherited::UnixListener(l) => sockets.unix.push(UnixSocket {
+                    path: None,
+                    listener: runtime::unix_listener_from_std(l)?,
+                }),
+                Inherited::TcpListener(l) => sockets.tcp.push(runtime::tcp_listener_from_std(l)?),
+                Inherited::Unix(s) => sockets.unix_clients.push(runtime::unix_stream_from_std(s)?),
+                Inherited::Tcp(s) => sockets.tcp_clients.push(runtime::tcp_stream_from_std(s)?),
+            }
+        }
+        Ok(sockets)
+    }
+}
+
+/// A listening UNIX socket. If the service bound it, its `path` is removed when it is dropped.
+struct UnixSocket {
+    path: Option<PathBuf>,
+    listener: UnixListener,
+}
+
+impl UnixSocket {
+    async fn bind(path: UnixPath, policy: &AccessPolicy) -> Result<UnixSocket, io::Error> {
+        let path = match path {
+            UnixPath::File(path) => path,
+            UnixPath::Abstract(name) => {
+                return Ok(UnixSocket {
+                    path: None,
+                    listener: runtime::bind_unix_abstract(name.as_bytes())?,
+                })
+            }
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // see gnunet/src/util/service.c::open_listen_socket
+        match std::fs::remove_file(&path) {
+            Ok(()) => debug!("removed stale socket {:?}", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let listener = runtime::bind_unix(&path).await?;
+        let mode = match (policy.match_uid, policy.match_gid) {
+            (true, false) => 0o700,
+            (_, true) => 0o770,
+            (false, false) => 0o777,
+        };
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        Ok(UnixSocket {
+            path: Some(path),
+            listener,
+        })
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+async fn accept_unix(listener: &UnixListener, shared: &Arc<Shared>) -> io::Error {
+    loop {
+        match listener.accept().await {
+            Ok((sock, _)) => admit_unix(shared, sock),
+            Err(e) => return e,
+        }
+    }
+}
+
+async fn accept_tcp(listener: &TcpListener, shared: &Arc<Shared>) -> io::Error {
+    loop {
+        match listener.accept().await {
+            Ok((sock, addr)) => admit_tcp(shared, sock, addr),
+            Err(e) => return e,
+        }
+    }
+}
+
+/// Serve `sock` if the access policy allows the client.
+fn admit_unix(shared: &Arc<Shared>, sock: UnixStream) {
+    let ids = peer_ids(sock.as_raw_fd());
+    if !shared.policy.allows_unix(ids.as_ref().ok()) {
+        debug!(service = &*shared.name, "rejected UNIX client {:?}", ids);
+        return;
+    }
+    let conn = Connection::from_stream(shared.name.clone(), sock);
+    runtime::spawn(serve_client(shared.clone(), conn));
+}
+
+/// Serve `sock`, connected from `addr`, if the access policy allows the client.
+fn admit_tcp(shared: &Arc<Shared>, sock: TcpStream, addr: SocketAddr) {
+    if !shared.policy.allows_ip(addr.ip()) {
+        debug!(service = &*shared.name, "rejected TCP client from {}", addr);
+        return;
+    }
+    let conn = Connection::from_tcp_stream(shared.name.clone(), sock);
+    runtime::spawn(serve_client(shared.clone(), conn));
+}
+
+/// A cloneable handle to a client of a `Server`.
+#[derive(Clone)]
+pub struct ClientHandle {
+    id: u64,
+    tx: mpsc::UnboundedSender<Buffer>,
+}
+
+impl ClientHandle {
+    /// A number identifying the client, unique among the clients of the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Queue `msg` to be sent to the client. Messages are sent in the order they are queued.
+    pub fn send<M: MessageOutCompound>(&self, msg: M) -> Result<(), io::Error> {
+        self.tx
+            .unbounded_send(to_buffer(msg))
+            .map_err(|_| disconnected())
+    }
+
+    /// Disconnect the client, once the messages already queued have been sent.
+    pub fn disconnect(&self) {
+        self.tx.close_channel();
+    }
+
+    /// Whether the client is still connected.
+    pub fn is_connected(&self) -> bool {
+        !self.tx.is_closed()
+    }
+}
+
+impl fmt::Debug for ClientHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientHandle")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the client has disconnected")
+}
+
+async fn serve_client(shared: Arc<Shared>, conn: Connection) {
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let (recv, send) = conn.split();
+    let (tx, queue) = mpsc::unbounded();
+    let client = ClientHandle { id, tx };
+    debug!(service = &*shared.name, client = id, "client connected");
+    if let Some(f) = &shared.on_connect {
+        f(&client);
+    }
+
+    {
+        let reader = read_loop(recv, &shared, &client).fuse();
+        let writer = write_loop(send, queue).fuse();
+        pin_mut!(reader, writer);
+        let res = select! {
+            () = reader => {
+                // Send whatever the handlers queued before closing the connection.
+                client.disconnect();
+                writer.await
+            }
+            res = writer => res,
+        };
+        if let Err(e) = res {
+            debug!(
+                service = &*shared.name,
+                client = id,
+                "failed to send: {}",
+                e
+            );
+        }
+    }
+
+    client.disconnect();
+    debug!(service = &*shared.name, client = id, "client disconnected");
+    if let Some(f) = &shared.on_disconnect {
+        f(&client);
+    }
+}
+
+/// Handles the client's messages until it disconnects, or has to be disconnected.
+async fn read_loop(mut recv: RecvHalf, shared: &Shared, client: &ClientHandle) {
+    let name = &*shared.name;
+    loop {
+        let (typ, buf) = match recv.recv().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    debug!(
+                        service = name,
+                        client = client.id,
+                        "failed to receive: {}",
+                        e
+                    );
+                }
+                return;
+            }
+        };
+        let handler = match shared.handlers.get(&typ).or(shared.fallback.as_ref()) {
+            Some(h) => h,
+            None => {
+                debug!(
+                    service = name,
+                    client = client.id,
+                    typ,
+                    "no handler for message, disconnecting client"
+                );
+                return;
+            }
+        };
+        if let Err(e) = handler(client.clone(), typ, buf).await {
+            debug!(
+                service = name,
+                client = client.id,
+                typ,
+                "handler failed, disconnecting client: {}",
+                e
+            );
+            return;
+        }
+        if !client.is_connected() {
+            return;
+        }
+    }
+}
+
+/// Returns `Ok` once the client has been disconnected and every queued message has been sent.
+async fn write_loop(
+    mut send: SendHalf,
+    mut queue: mpsc::UnboundedReceiver<Buffer>,
+) -> Result<(), io::Error> {
+    while let Some(buf) = queue.next().await {
+        send.send(&buf[..]).await?;
+    }
+    send.close().await
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_server() {
+    use crate::service::arm::{self, msg, ArmError, ArmResult};
+    use std::sync::Mutex;
+
+    let path =
+        std::env::temp_dir().join(format!("gnunet-rs-test-server-{}.sock", std::process::id()));
+    let mut cfg = Config::empty();
+    cfg.set_string("arm", "UNIXPATH", path.to_string_lossy().into_owned());
+    cfg.set_string("arm", "UNIX_MATCH_UID", "YES".to_string());
+
+    let started = Arc::new(Mutex::new(vec![]));
+    let disconnected = Arc::new(Mutex::new(vec![]));
+    let mut server = Server::new("arm");
+    let started2 = started.clone();
+    server
+        .handle(move |client: ClientHandle, req: msg::Start<String>| {
+            started2.lock().unwrap().push(req.service);
+            let id = req.prefix.request_id.get();
+            async move { client.send(&msg::ArmResultMessage::new(id, ArmResult::Starting)) }
+        })
+        .handle_raw(MessageType::ARM_STOP, |client, buf| async move {
+            let req = expect::<msg::Stop<&str>>(MessageType::ARM_STOP.to_u16(), &buf).unwrap();
+            let id = req.prefix.request_id.get();
+            client.send(&msg::ArmResultMessage::new(id, ArmResult::IsNotKnown))
+        });
+    let disconnected2 = disconnected.clone();
+    server.on_disconnect(move |client| disconnected2.lock().unwrap().push(client.id()));
+    let listening = server.bind(&cfg).await.unwrap();
+    assert_eq!(listening.unix_path(), Some(&*path));
+    assert!(listening.tcp_addr().is_none());
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    let handle = listening.spawn();
+
+    let client = arm::Client::connect(&cfg).await.unwrap();
+    assert_eq!(client.start("gns").await.unwrap(), ArmResult::Starting);
+    assert_eq!(client.stop("nope").await.unwrap(), ArmResult::IsNotKnown);
+    assert_eq!(*started.lock().unwrap(), ["gns"]);
+    assert!(disconnected.lock().unwrap().is_empty());
+
+    // ARM_LIST has no handler, so the client is disconnected.
+    match client.list().await {
+        Err(ArmError::Disconnected) | Err(ArmError::Io { .. }) => (),
+        r => panic!(
+            "expected the client to be disconnected, got {:?}",
+            r.map(|_| ())
+        ),
+    }
+    runtime::timeout(std::time::Duration::from_secs(5), async {
+        while disconnected.lock().unwrap().is_empty() {
+            runtime::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(*disconnected.lock().unwrap(), [0]);
+
+    drop(handle);
+    runtime::sleep(std::time::Duration::from_millis(10)).await;
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_socket_activation() {
+    use crate::util::MessageHeader;
+    use std::io::{Read, Write};
+    use std::os::unix::io::RawFd;
+    use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    const CHILD_ENV: &str = "GNUNET_RS_TEST_ACTIVATION_CHILD";
+    if std::env::var_os(CHILD_ENV).is_some() {
+        // We are the service started by the parent below, which passed us its sockets.
+        runtime::block_on(async {
+            let mut server = Server::new("dummy");
+            server.handle_raw(MessageType::DUMMY, |client, _| async move {
+                client.send(&MessageHeader::new(4, MessageType::DUMMY2))
+            });
+            let listening = server.bind(&Config::empty()).await.unwrap();
+            let _ = runtime::timeout(Duration::from_secs(30), listening.run()).await;
+        });
+        return;
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "gnunet-rs-test-activation-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = StdUnixListener::bind(&path).unwrap();
+    let (mut early, theirs) = StdUnixStream::pair().unwrap();
+    // Move the sockets out of the way of the descriptors they are passed as.
+    let fds: Vec<RawFd> = [listener.as_raw_fd(), theirs.as_raw_fd()]
+        .iter()
+        .map(|&fd| unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 100) })
+        .collect();
+    assert!(fds.iter().all(|&fd| fd >= 100));
+
+    let mut cmd = Command::new(std::env::current_exe().unwrap());
+    cmd.args(["--exact", "service::server::test_socket_activation"])
+        .env(CHILD_ENV, "1")
+        .env("LISTEN_FDS", "2")
+        .env_remove("LISTEN_PID")
+        .stdout(Stdio::null());
+    let child_fds = fds.clone();
+    unsafe {
+        cmd.pre_exec(move || {
+            for (i, &fd) in child_fds.iter().enumerate() {
+                if libc::dup2(fd, activation::LISTEN_FDS_START + i as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn().unwrap();
+    for fd in fds {
+        unsafe { libc::close(fd) };
+    }
+    drop((listener, theirs));
+
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let roundtrip = |sock: &mut StdUnixStream| {
+        sock.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        sock.write_all(crate::util::serial::AsBytes::as_bytes(&dummy))
+            .unwrap();
+        let mut reply = [0; 4];
+        sock.read_exact(&mut reply).unwrap();
+        u16::from_be_bytes([reply[2], reply[3]])
+    };
+    // A client that connected before the service started, over the passed socketpair.
+    assert_eq!(roundtrip(&mut early), MessageType::DUMMY2.to_u16());
+    // A client connecting to the passed listening socket.
+    let mut late = StdUnixStream::connect(&path).unwrap();
+    assert_eq!(roundtrip(&mut late), MessageType::DUMMY2.to_u16());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,111 @@
+//! Socket activation: taking over sockets that were bound by the process that started the service.
+//! See the docs of `service::server`.
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The first file descriptor passed, after stdin, stdout and stderr.
+pub(super) const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the sockets have been taken, so that they are taken at most once per process.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A socket passed to the service by its parent.
+#[derive(Debug)]
+pub(super) enum Inherited {
+    UnixListener(UnixListener),
+    TcpListener(TcpListener),
+    /// A client that connected before the service started.
+    Unix(UnixStream),
+    /// A client that connected before the service started.
+    Tcp(TcpStream),
+}
+
+/// Take ownership of the sockets passed to this process, if any.
+///
+/// Only the first call takes them. The environment is left alone, since changing it isn't safe
+/// once other threads may be running, so processes the service starts still see `LISTEN_FDS`.
+/// Unless `LISTEN_PID` is set, remove it from their environment (eg. with `Command::env_remove`).
+pub(super) fn take_listen_fds() -> Result<Vec<Inherited>, io::Error> {
+    // see gnunet/src/util/service.c::setup_service
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) => count,
+        Err(_) => return Ok(vec![]),
+    };
+    let for_us = match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => true,
+    };
+    if !for_us {
+        return Ok(vec![]);
+    }
+
+    let count: RawFd = count.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS is not a number: '{}'", count),
+        )
+    })?;
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .map(inherit)
+        .collect()
+}
+
+fn inherit(fd: RawFd) -> Result<Inherited, io::Error> {
+    // SAFETY: `fd` is only used if it is a socket, and the environment promised it to this
+    // process, which takes it exactly once.
+    unsafe {
+        // Don't pass the socket on to processes the service starts.
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut listening: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(match (addr.ss_family as libc::c_int, listening != 0) {
+            (libc::AF_UNIX, true) => Inherited::UnixListener(UnixListener::from_raw_fd(fd)),
+            (libc::AF_UNIX, false) => Inherited::Unix(UnixStream::from_raw_fd(fd)),
+            (libc::AF_INET, true) | (libc::AF_INET6, true) => {
+                Inherited::TcpListener(TcpListener::from_raw_fd(fd))
+            }
+            (libc::AF_INET, false) | (libc::AF_INET6, false) => {
+                Inherited::Tcp(TcpStream::from_raw_fd(fd))
+            }
+            (family, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "file descriptor {} passed in LISTEN_FDS has unsupported address family {}",
+                        fd, family
+                    ),
+                ))
+            }
+        })
+    }
+}
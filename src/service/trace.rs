@@ -0,0 +1,314 @@
+//! Recording the messages exchanged with services, for debugging and for replaying in tests.
+//!
+//! A `TraceWriter` can be attached to a `Connection` with `Connection::set_trace`. Every
+//! connection made by `service::connect` is also traced to the file named by the
+//! `GNUNET_RS_TRACE` environment variable, if it is set. A recorded trace can be read back with
+//! `TraceReader`, and served to a client by `testing::MockService::replay`.
+//!
+//! A trace file starts with the 16 bytes `GNUNET-RS-TRACE\0`, followed by a record for each
+//! message:
+//!
+//! * the time, as a big-endian u64 of microseconds since the UNIX epoch,
+//! * the direction, a byte which is 0 if the client sent the message and 1 if it received it,
+//! * the connection the message was exchanged on, as a big-endian u32 that is unique within the
+//!   file,
+//! * the length of the service name as a byte, followed by the name,
+//! * the message itself, header included.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// The first bytes of every trace file.
+pub const MAGIC: &[u8; 16] = b"GNUNET-RS-TRACE\0";
+
+/// The environment variable naming the file that `service::connect` traces connections to.
+pub const TRACE_ENV: &str = "GNUNET_RS_TRACE";
+
+/// Which way a traced message went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client to the service.
+    Sent,
+    /// Received by the client from the service.
+    Received,
+}
+
+/// A message recorded in a trace.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// Tells apart the connections traced to the same file, including those to the same service.
+    pub connection: u32,
+    /// The name of the service the message was exchanged with.
+    pub service: String,
+    /// The whole message, header included.
+    pub frame: Vec<u8>,
+}
+
+impl TraceRecord {
+    /// The type of the recorded message, or `None` if `frame` is shorter than a message header.
+    pub fn msg_type(&self) -> Option<u16> {
+        let typ = self.frame.get(2..4)?;
+        Some(u16::from_be_bytes([typ[0], typ[1]]))
+    }
+}
+
+/// A cloneable handle to a trace file. Records from all clones are written to the same file.
+///
+/// `Connection::set_trace` marks the records of each connection with a connection number of
+/// their own.
+#[derive(Clone)]
+pub struct TraceWriter {
+    inner: Arc<Mutex<Box<dyn Write + Send>>>,
+    connections: Arc<AtomicU32>,
+    connection: u32,
+}
+
+impl TraceWriter {
+    /// Create a trace file at `path`, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<TraceWriter, io::Error> {
+        TraceWriter::new(File::create(path)?)
+    }
+
+    /// Write a trace to `w`. Each record is written with a single `write_all`.
+    pub fn new<W: Write + Send + 'static>(mut w: W) -> Result<TraceWriter, io::Error> {
+        w.write_all(MAGIC)?;
+        w.flush()?;
+        Ok(TraceWriter {
+            inner: Arc::new(Mutex::new(Box::new(w))),
+            connections: Arc::new(AtomicU32::new(1)),
+            connection: 0,
+        })
+    }
+
+    /// A writer to the same file, whose records are marked with a new connection number.
+    pub fn new_connection(&self) -> TraceWriter {
+        TraceWriter {
+            inner: self.inner.clone(),
+            connections: self.connections.clone(),
+            connection: self.connections.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// The writer named by `GNUNET_RS_TRACE`, if it is set. The file is created the first time
+    /// this is called, and shared by every later caller.
+    pub fn from_env() -> Option<TraceWriter> {
+        static ENV: OnceLock<Option<TraceWriter>> = OnceLock::new();
+        ENV.get_or_init(|| {
+            let path = std::env::var_os(TRACE_ENV)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&path);
+            match file.and_then(TraceWriter::new) {
+                Ok(w) => Some(w),
+                Err(e) => {
+                    warn!("failed to create trace file {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .clone()
+    }
+
+    /// Record `frame`, a whole message exchanged with `service`.
+    ///
+    /// Errors are logged rather than returned, so that tracing never interrupts a connection.
+    pub fn record(&self, service: &str, direction: Direction, frame: &[u8]) {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let name = &service.as_bytes()[..service.len().min(u8::MAX as usize)];
+
+        let mut buf = Vec::with_capacity(14 + name.len() + frame.len());
+        buf.extend_from_slice(&micros.to_be_bytes());
+        buf.push(match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        buf.extend_from_slice(&self.connection.to_be_bytes());
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(frame);
+
+        let mut w = self.inner.lock().unwrap();
+        if let Err(e) = w.write_all(&buf).and_then(|()| w.flush()) {
+            warn!(service, "failed to write trace record: {}", e);
+        }
+    }
+}
+
+/// Reads the records of a trace file, in the order they were written.
+pub struct TraceReader<R> {
+    inner: R,
+}
+
+impl TraceReader<File> {
+    /// Open the trace file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TraceReader<File>, io::Error> {
+        TraceReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Read a trace from `r`, checking that it starts with `MAGIC`.
+    pub fn new(mut r: R) -> Result<TraceReader<R>, io::Error> {
+        let mut magic = [0; 16];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a gnunet-rs trace file",
+            ));
+        }
+        Ok(TraceReader { inner: r })
+    }
+
+    fn read_record(&mut self) -> Result<Option<TraceRecord>, io::Error> {
+        let mut micros = [0; 8];
+        match self.inner.read_exact(&mut micros) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut direction = [0; 1];
+        self.inner.read_exact(&mut direction)?;
+        let direction = match direction[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            d => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid trace record direction {}", d),
+                ))
+            }
+        };
+        let mut connection = [0; 4];
+        self.inner.read_exact(&mut connection)?;
+        let mut name_len = [0; 1];
+        self.inner.read_exact(&mut name_len)?;
+        let mut service = vec![0; name_len[0] as usize];
+        self.inner.read_exact(&mut service)?;
+        let service = String::from_utf8(service)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut frame = vec![0; 4];
+        self.inner.read_exact(&mut frame)?;
+        let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+        if len < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "traced message is shorter than its header",
+            ));
+        }
+        frame.resize(len, 0);
+        self.inner.read_exact(&mut frame[4..])?;
+
+        Ok(Some(TraceRecord {
+            time: UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(micros)),
+            direction,
+            connection: u32::from_be_bytes(connection),
+            service,
+            frame,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_record_and_replay() {
+    use crate::service;
+    use crate::testing::{message_bytes, MockService};
+    use crate::util::{MessageHeader, MessageType};
+
+    let dummy = message_bytes(&MessageHeader::new(4, MessageType::DUMMY));
+    let dummy2 = message_bytes(&MessageHeader::new(4, MessageType::DUMMY2));
+    let path = std::env::temp_dir().join(format!("gnunet-rs-test-trace-{}", std::process::id()));
+
+    // Record two connections to a scripted service.
+    let mut mock = MockService::bind("dummy").await.unwrap();
+    mock.expect(MessageType::DUMMY, vec![dummy2.clone(), dummy2.clone()])
+        .expect(MessageType::DUMMY2, vec![dummy.clone()]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+    let trace = TraceWriter::create(&path).unwrap();
+    let mut first = service::connect(&cfg, "dummy").await.unwrap();
+    first.set_trace(Some(trace.clone()));
+    let mut second = service::connect(&cfg, "dummy").await.unwrap();
+    second.set_trace(Some(trace));
+    first.send(&dummy[..]).await.unwrap();
+    first.recv().await.unwrap();
+    first.recv().await.unwrap();
+    second.send(&dummy2[..]).await.unwrap();
+    second.recv().await.unwrap();
+    mock.finish().await.unwrap();
+
+    let records = TraceReader::open(&path)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let (a, b) = (records[0].connection, records[3].connection);
+    assert_ne!(a, b);
+    let summary: Vec<_> = records
+        .iter()
+        .map(|r| (r.service.as_str(), r.connection, r.direction, r.msg_type()))
+        .collect();
+    let dummy_type = Some(MessageType::DUMMY.to_u16());
+    let dummy2_type = Some(MessageType::DUMMY2.to_u16());
+    assert_eq!(
+        summary,
+        [
+            ("dummy", a, Direction::Sent, dummy_type),
+            ("dummy", a, Direction::Received, dummy2_type),
+            ("dummy", a, Direction::Received, dummy2_type),
+            ("dummy", b, Direction::Sent, dummy2_type),
+            ("dummy", b, Direction::Received, dummy_type),
+        ]
+    );
+
+    // Replay each connection to a new one, even if the second one goes first.
+    let replay = MockService::replay("dummy", &records).await.unwrap();
+    let cfg = replay.config();
+    let replay = replay.spawn();
+    let mut first = service::connect(&cfg, "dummy").await.unwrap();
+    let mut second = service::connect(&cfg, "dummy").await.unwrap();
+    second.send(&dummy2[..]).await.unwrap();
+    let (typ, _) = second.recv().await.unwrap();
+    assert_eq!(typ, MessageType::DUMMY.to_u16());
+    first.send(&dummy[..]).await.unwrap();
+    for _ in 0..2 {
+        let (typ, _) = first.recv().await.unwrap();
+        assert_eq!(typ, MessageType::DUMMY2.to_u16());
+    }
+    replay.finish().await.unwrap();
+}
+
+#[test]
+fn test_msg_type() {
+    let record = |frame: &[u8]| TraceRecord {
+        time: UNIX_EPOCH,
+        direction: Direction::Sent,
+        connection: 0,
+        service: "dummy".to_string(),
+        frame: frame.to_vec(),
+    };
+    assert_eq!(record(&[0, 4, 1, 2]).msg_type(), Some(0x102));
+    assert_eq!(record(&[0, 4, 1]).msg_type(), None);
+}
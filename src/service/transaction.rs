@@ -0,0 +1,326 @@
+//! Requests whose replies follow one of the common patterns.
+//!
+//! Most requests to a service are answered in one of three ways:
+//!
+//! * `Single`: one message, eg. the result of an ARM request.
+//! * `List`: any number of messages, followed by a message of another type that ends the list, eg.
+//!   the PEERINFO_INFO messages answering PEERINFO_GET_ALL, ended by PEERINFO_INFO_END.
+//! * `Updates`: messages that keep coming for as long as the connection is open, eg. the HELLOs
+//!   sent after PEERINFO_NOTIFY.
+//!
+//! A request message declares how it is answered by implementing `Request`. The reply is described
+//! by a `Response`, which is one of the types above, or is implemented by the service module when
+//! the items of the reply borrow from the connection or the end of a list is marked by a field of
+//! the last message.
+//!
+//! `Connection::transaction` sends a request, and returns a `Transaction` that receives the items
+//! of the reply in turn. `Connection::query` and `Connection::subscribe` are shortcuts for
+//! `Single` and `Updates` replies. The service clients make their requests the same way, within
+//! the limits described in `service::request`.
+//!
+//! ```rust,no_run
+//! # async fn example(mut conn: gnunet::service::Connection) -> Result<(), Box<dyn std::error::Error>> {
+//! use gnunet::service::peerinfo::msg::ListAllPeers;
+//!
+//! let mut peers = conn.transaction(&ListAllPeers::new(false)).await?;
+//! while let Some(info) = peers.next().await? {
+//!     println!("{}", info.hello.peer_id());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::service::request::Exchange;
+use crate::service::Connection;
+use crate::util::{expect, ExpectError, MessageIn, MessageOutCompound};
+use futures::stream::{self, Stream};
+use std::io;
+use std::marker::PhantomData;
+
+/// A request message, and the reply a service answers it with.
+///
+/// A reference to the request must implement `MessageOutCompound`, which is the case for
+/// fixed-size messages deriving `AsBytes` and for those deriving `GnunetMessage`.
+pub trait Request {
+    type Reply: Response;
+}
+
+/// The messages that make up a reply, and how to tell when it has ended.
+pub trait Response {
+    /// A message of the reply, which may borrow from the connection's receive buffer.
+    type Item<'a>: MessageIn<'a>;
+
+    /// Whether the reply consists of a single item.
+    const SINGLE: bool = false;
+
+    /// Whether the message of type `typ` ends the reply. A message that ends the reply isn't one
+    /// of its items.
+    fn ends(_typ: u16, _msg: &[u8]) -> bool {
+        false
+    }
+}
+
+/// A reply consisting of one `M`.
+pub struct Single<M>(PhantomData<fn() -> M>);
+
+impl<M: for<'a> MessageIn<'a>> Response for Single<M> {
+    type Item<'a> = M;
+    const SINGLE: bool = true;
+}
+
+/// A reply consisting of any number of `M`, ended by an `End`.
+pub struct List<M, End>(PhantomData<fn() -> (M, End)>);
+
+impl<M: for<'a> MessageIn<'a>, End: for<'a> MessageIn<'a>> Response for List<M, End> {
+    type Item<'a> = M;
+
+    fn ends(typ: u16, _msg: &[u8]) -> bool {
+        typ == End::msg_type().to_u16()
+    }
+}
+
+/// A reply of `M`s that never ends.
+pub struct Updates<M>(PhantomData<fn() -> M>);
+
+impl<M: for<'a> MessageIn<'a>> Response for Updates<M> {
+    type Item<'a> = M;
+}
+
+/// Errors receiving the reply to a request.
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("There was an I/O error communicating with the service. Specifically: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+    #[error("Unexpected response. Error: {source}")]
+    UnexpectedResponse {
+        #[from]
+        source: ExpectError,
+    },
+}
+
+/// Where a `Transaction` receives its reply from.
+pub(crate) enum Channel<'c> {
+    Connection(&'c mut Connection),
+    Exchange(Exchange<'c>),
+}
+
+/// A request that has been sent, whose reply is received by `next`. Created by
+/// `Connection::transaction`.
+pub struct Transaction<'c, R> {
+    channel: Channel<'c>,
+    done: bool,
+    response: PhantomData<fn() -> R>,
+}
+
+impl<'c, R: Response> Transaction<'c, R> {
+    pub(crate) fn new(channel: Channel<'c>) -> Transaction<'c, R> {
+        Transaction {
+            channel,
+            done: false,
+            response: PhantomData,
+        }
+    }
+
+    /// Receive the next item of the reply, or `None` once the reply has ended.
+    ///
+    /// If the service sends a message that is neither an item nor the end of the reply, it is
+    /// returned as an `UnexpectedResponse` error, and the transaction can go on receiving.
+    pub async fn next(&mut self) -> Result<Option<R::Item<'_>>, TransactionError> {
+        if self.done {
+            return Ok(None);
+        }
+        let (typ, buf) = match &mut self.channel {
+            Channel::Connection(conn) => conn.recv_borrowed().await?,
+            Channel::Exchange(exchange) => {
+                // Any message ends a single reply, even one that isn't the expected item.
+                let ends = |typ, msg: &[u8]| R::SINGLE || R::ends(typ, msg);
+                exchange.recv_borrowed_ending(ends).await?
+            }
+        };
+        if R::ends(typ, buf) {
+            self.done = true;
+            return Ok(None);
+        }
+        self.done = R::SINGLE;
+        Ok(Some(expect(typ, buf)?))
+    }
+
+    /// Call `f` with every remaining item of the reply.
+    pub async fn for_each<F>(mut self, mut f: F) -> Result<(), TransactionError>
+    where
+        F: FnMut(R::Item<'_>),
+    {
+        while let Some(item) = self.next().await? {
+            f(item);
+        }
+        Ok(())
+    }
+
+    /// Collect the remaining items of the reply, converted to `T`.
+    pub async fn collect<T>(self) -> Result<Vec<T>, TransactionError>
+    where
+        for<'a> R::Item<'a>: Into<T>,
+    {
+        let mut v = vec![];
+        self.for_each(|item| v.push(item.into())).await?;
+        Ok(v)
+    }
+}
+
+pub(crate) async fn start<'c, Q>(
+    conn: &'c mut Connection,
+    req: &Q,
+) -> Result<Transaction<'c, Q::Reply>, io::Error>
+where
+    Q: Request,
+    for<'r> &'r Q: MessageOutCompound,
+{
+    conn.send_compound(req).await?;
+    Ok(Transaction::new(Channel::Connection(conn)))
+}
+
+pub(crate) fn subscribe<M>(conn: Connection) -> impl Stream<Item = Result<M, TransactionError>>
+where
+    M: for<'a> MessageIn<'a>,
+{
+    // The stream ends after an I/O error.
+    stream::unfold(Some(conn), |conn| async move {
+        let mut conn = conn?;
+        match conn.recv_borrowed().await {
+            Ok((typ, buf)) => {
+                let item = expect(typ, buf).map_err(TransactionError::from);
+                Some((item, Some(conn)))
+            }
+            Err(e) => Some((Err(e.into()), None)),
+        }
+    })
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_transaction() {
+    use crate::runtime::UnixStream;
+    use crate::util::serial::*;
+    use crate::util::{GnunetMessage, MessageHeader, MessageType};
+    use futures::StreamExt;
+
+    #[derive(Clone, Copy, AsBytes, FromBytes)]
+    #[repr(C)]
+    struct NumberPrefix {
+        header: MessageHeader,
+        n: u32be,
+    }
+
+    #[derive(GnunetMessage)]
+    #[gnunet(msg_type = DUMMY)]
+    struct Number<B> {
+        #[gnunet(prefix)]
+        prefix: NumberPrefix,
+        #[gnunet(bytes)]
+        data: B,
+    }
+
+    #[derive(GnunetMessage)]
+    #[gnunet(msg_type = DUMMY2)]
+    struct End {
+        #[gnunet(prefix)]
+        prefix: EndPrefix,
+    }
+
+    #[derive(Clone, Copy, AsBytes, FromBytes)]
+    #[repr(C)]
+    struct EndPrefix {
+        header: MessageHeader,
+    }
+
+    fn number(n: u32) -> Vec<u8> {
+        let mut b = vec![0, 9];
+        b.extend_from_slice(&MessageType::DUMMY.to_u16().to_be_bytes());
+        b.extend_from_slice(&n.to_be_bytes());
+        b.push(n as u8);
+        b
+    }
+
+    /// A request answered by `R`.
+    struct Ask<R>(MessageHeader, PhantomData<R>);
+    impl<R: Response> Request for Ask<R> {
+        type Reply = R;
+    }
+    impl<'a, R> MessageOutCompound for &'a Ask<R> {
+        type Bytes = &'a [u8];
+        type Chunks = Option<&'a [u8]>;
+        fn as_byte_chunks(&self) -> Self::Chunks {
+            Some(self.0.as_bytes())
+        }
+    }
+    fn ask<R>() -> Ask<R> {
+        Ask(MessageHeader::new(4, MessageType::DUMMY), PhantomData)
+    }
+
+    // A list whose items borrow from the connection.
+    struct Borrowed;
+    impl Response for Borrowed {
+        type Item<'a> = Number<&'a [u8]>;
+        fn ends(typ: u16, _msg: &[u8]) -> bool {
+            typ == MessageType::DUMMY2.to_u16()
+        }
+    }
+
+    let (client, service) = UnixStream::pair().unwrap();
+    let mut service = Connection::from_stream("service".to_string(), service);
+    let mut conn = Connection::from_stream("dummy".to_string(), client);
+    let end = [0, 4, 0, 3];
+    let replies = [
+        number(1),
+        number(2),
+        number(3),
+        end.to_vec(),
+        number(4),
+        number(5),
+        end.to_vec(),
+        number(6),
+        number(7),
+    ];
+    for b in &replies {
+        service.send(&b[..]).await.unwrap();
+    }
+
+    let one = conn.query(&ask::<Single<Number<Vec<u8>>>>()).await.unwrap();
+    assert_eq!(one.prefix.n.get(), 1);
+
+    let mut many = conn.transaction(&ask::<Borrowed>()).await.unwrap();
+    let mut data = vec![];
+    while let Some(number) = many.next().await.unwrap() {
+        data.extend_from_slice(number.data);
+    }
+    assert_eq!(data, [2, 3]);
+    assert!(many.next().await.unwrap().is_none());
+
+    let owned: Vec<Number<Vec<u8>>> = conn
+        .transaction(&ask::<List<Number<Vec<u8>>, End>>())
+        .await
+        .unwrap()
+        .collect()
+        .await
+        .unwrap();
+    let ns: Vec<_> = owned.iter().map(|n| n.prefix.n.get()).collect();
+    assert_eq!(ns, [4, 5]);
+
+    let updates = conn
+        .subscribe(&ask::<Updates<Number<Vec<u8>>>>())
+        .await
+        .unwrap();
+    let ns: Vec<_> = updates
+        .take(2)
+        .map(|n| n.unwrap().prefix.n.get())
+        .collect()
+        .await;
+    assert_eq!(ns, [6, 7]);
+
+    for _ in 0..4 {
+        assert_eq!(service.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    }
+}
@@ -1,26 +1,60 @@
-use crate::service;
+use crate::runtime::Elapsed;
+use crate::service::{self, request, ReconnectPolicy};
 use crate::util::message::{expect, ExpectError};
-use crate::util::{Config, Hello, PeerIdentity};
+use crate::util::{Config, Hello, MessageType, PeerIdentity};
+use futures::StreamExt;
 use std::io;
+use std::time::Duration;
 
 pub mod msg;
 pub mod tcp;
 
 pub struct Client {
-    conn: service::Connection,
+    mux: service::Mux,
     pub our_hello: Hello,
 }
 
 impl Client {
     pub async fn connect(cfg: &Config) -> Result<Client, ConnectError> {
-        let mut conn = service::connect(cfg, "transport").await?;
+        Client::connect_with_timeout(cfg, None).await
+    }
+
+    /// Connect to the transport service, giving up if it hasn't sent our HELLO within `timeout`.
+    pub async fn connect_with_timeout(
+        cfg: &Config,
+        timeout: Option<Duration>,
+    ) -> Result<Client, ConnectError> {
+        let conn = service::connect(cfg, "transport").await?;
+        Client::start(service::Mux::new(conn), timeout).await
+    }
+
+    /// Connect like `connect_with_timeout`, and re-establish the connection whenever it is lost,
+    /// as described by `policy`. The service is sent START again on each new connection.
+    pub async fn connect_reconnecting(
+        cfg: &Config,
+        timeout: Option<Duration>,
+        policy: ReconnectPolicy,
+    ) -> Result<Client, ConnectError> {
+        let mux = service::Mux::reconnecting(cfg, "transport", policy).await?;
+        Client::start(mux, timeout).await
+    }
+
+    async fn start(mux: service::Mux, timeout: Option<Duration>) -> Result<Client, ConnectError> {
+        let mut hellos = mux.subscribe(&[MessageType::HELLO])?;
         // TODO: are we supposed to send the null id here?
-        conn.send(&msg::Start::new(0, PeerIdentity::default()))
-            .await?;
+        mux.register(&msg::Start::new(0, PeerIdentity::default()))?;
 
-        let (typ, buf) = conn.recv().await?;
+        let (typ, buf) = request::with_timeout(timeout, hellos.next())
+            .await
+            .map_err(|Elapsed(timeout)| ConnectError::TimedOut { timeout })?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
         let our_hello = expect::<Hello>(typ, &buf)?;
-        Ok(Client { conn, our_hello })
+        Ok(Client { mux, our_hello })
+    }
+
+    /// Receive events about the state of the connection to the service.
+    pub fn events(&self) -> Result<service::Events, io::Error> {
+        self.mux.events()
     }
 }
 
@@ -41,4 +75,67 @@ pub enum ConnectError {
         #[from]
         source: service::ConnectError,
     },
+    #[error("The service did not send our HELLO within {timeout:?}")]
+    TimedOut { timeout: Duration },
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_connect() {
+    use crate::testing::MockService;
+    use crate::util::serial::AsBytes;
+    use crate::util::MessageType;
+
+    let mut hello = vec![0, 40];
+    hello.extend_from_slice(&MessageType::HELLO.to_u16().to_be_bytes());
+    hello.extend_from_slice(&[0; 4]); // friend_only
+    hello.extend_from_slice(&[9; 32]);
+
+    let mut mock = MockService::bind("transport").await.unwrap();
+    mock.expect(MessageType::TRANSPORT_START, vec![hello]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let client = Client::connect(&cfg).await.unwrap();
+    assert_eq!(client.our_hello.peer_id().as_bytes(), &[9; 32][..]);
+    mock.finish().await.unwrap();
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_reconnect() {
+    use crate::service::ConnectionEvent;
+    use crate::testing::MockService;
+    use crate::util::MessageType;
+
+    let mut hello = vec![0, 40];
+    hello.extend_from_slice(&MessageType::HELLO.to_u16().to_be_bytes());
+    hello.extend_from_slice(&[0; 4]); // friend_only
+    hello.extend_from_slice(&[9; 32]);
+
+    let mut mock = MockService::bind("transport").await.unwrap();
+    mock.expect(MessageType::TRANSPORT_START, vec![hello.clone()]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let policy = ReconnectPolicy::default();
+    let client = Client::connect_reconnecting(&cfg, None, policy)
+        .await
+        .unwrap();
+    let mut events = client.events().unwrap();
+
+    // START is sent again once the client has reconnected to the restarted service.
+    let mut mock = mock.restart().await.unwrap();
+    mock.expect(MessageType::TRANSPORT_START, vec![hello]);
+    let mock = mock.spawn();
+    match events.next().await {
+        Some(ConnectionEvent::Disconnected { .. }) => (),
+        e => panic!("expected Disconnected, got {:?}", e),
+    }
+    match events.next().await {
+        Some(ConnectionEvent::Reconnected { .. }) => (),
+        e => panic!("expected Reconnected, got {:?}", e),
+    }
+    let received = mock.finish().await.unwrap();
+    assert_eq!(received.len(), 1);
 }
@@ -1,5 +1,7 @@
 use crate::util::serial::*;
 use crate::util::{MessageHeader, MessageType, PeerIdentity};
+use std::convert::TryInto;
+use std::mem::size_of;
 
 #[derive(Debug, AsBytes)]
 #[repr(C)]
@@ -12,7 +14,10 @@ pub struct Start {
 impl Start {
     pub fn new(options: u32, peer: PeerIdentity) -> Start {
         Self {
-            header: MessageHeader::for_type::<Start>(MessageType::TRANSPORT_START),
+            header: MessageHeader::new(
+                size_of::<Start>().try_into().unwrap(),
+                MessageType::TRANSPORT_START,
+            ),
             options: u32be::new(options),
             myself: peer,
         }
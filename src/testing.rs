@@ -0,0 +1,420 @@
+//! Utilities for testing service clients without a running GNUnet peer.
+//!
+//! Available to this crate's own tests, and to other crates with the `testing` cargo feature.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut mock = MockService::bind("peerinfo").await?;
+//! mock.expect(MessageType::PEERINFO_GET_ALL, vec![info, info_end]);
+//! let cfg = mock.config();
+//! let mock = mock.spawn();
+//!
+//! let peers = peerinfo::Client::connect(&cfg).await?.all_peers().await?;
+//! mock.finish().await?;
+//! ```
+
+use crate::runtime::{self, UnixListener};
+use crate::service::trace::{Direction, TraceRecord};
+use crate::service::Connection;
+use crate::util::{AnyMessageType, Config, MessageOutCompound, MessageType};
+use futures::future::{FutureExt, RemoteHandle};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+/// How long `MockHandle::finish` waits for outstanding expectations to be met.
+pub const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<Vec<u8>> + Send>;
+
+/// A scripted stand-in for a GNUnet service, listening on a temporary UNIX socket.
+///
+/// Incoming messages are matched first against the expectations added with `expect`, in the
+/// order they were added, and then against the canned responses added with `on`. A message that
+/// matches neither is recorded as an error, which `MockHandle::finish` reports.
+pub struct MockService {
+    name: String,
+    path: PathBuf,
+    listener: UnixListener,
+    script: Script,
+}
+
+#[derive(Default)]
+struct Script {
+    /// Played to every connection that `replayed` doesn't have a session for.
+    session: Session,
+    /// Played to the first connections, one session each in order of arrival. Set by `replay`.
+    replayed: Vec<Session>,
+    accepted: usize,
+    canned: HashMap<u16, Responder>,
+    received: Vec<(u16, Vec<u8>)>,
+    error: Option<MockError>,
+}
+
+/// The greeting and expectations played to a connection.
+#[derive(Default)]
+struct Session {
+    greeting: Vec<Vec<u8>>,
+    expected: VecDeque<(u16, Responder)>,
+}
+
+impl Session {
+    fn expect(&mut self, typ: u16, responses: Vec<Vec<u8>>) {
+        let f = move |_: &[u8]| responses.clone();
+        self.expected.push_back((typ, Box::new(f)));
+    }
+}
+
+impl Script {
+    /// The session played to the connection that was accepted `index`th.
+    fn session(&mut self, index: usize) -> &mut Session {
+        match self.replayed.get_mut(index) {
+            Some(session) => session,
+            None => &mut self.session,
+        }
+    }
+
+    /// The types of the expected messages that haven't arrived yet.
+    fn remaining(&self) -> Vec<u16> {
+        std::iter::once(&self.session)
+            .chain(&self.replayed)
+            .flat_map(|session| session.expected.iter().map(|(typ, _)| *typ))
+            .collect()
+    }
+}
+
+/// Errors reported by `MockHandle::finish`.
+#[derive(Debug, Error)]
+pub enum MockError {
+    #[error(
+        "Received an unexpected message of type {}",
+        AnyMessageType::from_u16(*typ)
+    )]
+    Unexpected { typ: u16 },
+    #[error("Still waiting for messages of types {remaining:?}")]
+    Unsatisfied { remaining: Vec<u16> },
+    #[error("There was an I/O error in the mock service. Specifically: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+}
+
+impl MockService {
+    /// Create a mock of the service `name`, listening on a new socket in the temp directory.
+    pub async fn bind(name: &str) -> Result<MockService, io::Error> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "gnunet-rs-mock-{}-{}-{}.sock",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        MockService::bind_path(name, path).await
+    }
+
+    async fn bind_path(name: &str, path: PathBuf) -> Result<MockService, io::Error> {
+        let _ = std::fs::remove_file(&path);
+        let listener = runtime::bind_unix(&path).await?;
+        Ok(MockService {
+            name: name.to_string(),
+            path,
+            listener,
+            script: Script::default(),
+        })
+    }
+
+    /// Create a mock of the service `name` that plays back its side of a recorded trace.
+    ///
+    /// Each connection to `name` in the trace is played back to one client connection, in the
+    /// order the traced connections were made. Each message the client sent on it is expected in
+    /// turn, by type only, and answered with the messages the client received after it. Messages
+    /// the client received before sending anything are sent as soon as it connects.
+    pub async fn replay(name: &str, records: &[TraceRecord]) -> Result<MockService, io::Error> {
+        let mut mock = MockService::bind(name).await?;
+        // A sent message's type, and the responses received after it so far.
+        type Current = Option<(u16, Vec<Vec<u8>>)>;
+        let mut connections: Vec<(u32, Session, Current)> = vec![];
+        for r in records.iter().filter(|r| r.service == name) {
+            let i = match connections.iter().position(|(c, _, _)| *c == r.connection) {
+                Some(i) => i,
+                None => {
+                    connections.push((r.connection, Session::default(), None));
+                    connections.len() - 1
+                }
+            };
+            let (_, session, current) = &mut connections[i];
+            match (r.direction, &mut *current) {
+                (Direction::Sent, _) => {
+                    let typ = match r.msg_type() {
+                        Some(typ) => typ,
+                        None => {
+                            debug!("skipping a traced message shorter than its header");
+                            continue;
+                        }
+                    };
+                    if let Some((typ, responses)) = current.replace((typ, vec![])) {
+                        session.expect(typ, responses);
+                    }
+                }
+                (Direction::Received, Some((_, responses))) => responses.push(r.frame.clone()),
+                (Direction::Received, None) => session.greeting.push(r.frame.clone()),
+            }
+        }
+        for (_, mut session, current) in connections {
+            if let Some((typ, responses)) = current {
+                session.expect(typ, responses);
+            }
+            mock.script.replayed.push(session);
+        }
+        Ok(mock)
+    }
+
+    /// The path of the socket the mock service is listening on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Point the `UNIXPATH` of this service's section of `cfg` at the mock service.
+    pub fn configure(&self, cfg: &mut Config) {
+        cfg.set_string(
+            &self.name,
+            "UNIXPATH",
+            self.path.to_string_lossy().into_owned(),
+        );
+    }
+
+    /// A config containing only this service's section, pointing at the mock service.
+    pub fn config(&self) -> Config {
+        let mut cfg = Config::empty();
+        self.configure(&mut cfg);
+        cfg
+    }
+
+    /// Send `msgs` to each client as soon as it connects.
+    pub fn greet(&mut self, msgs: Vec<Vec<u8>>) -> &mut MockService {
+        self.script.session.greeting = msgs;
+        self
+    }
+
+    /// Expect the next message to be of type `typ`, and answer it with `responses`.
+    pub fn expect(&mut self, typ: MessageType, responses: Vec<Vec<u8>>) -> &mut MockService {
+        self.script.session.expect(typ.to_u16(), responses);
+        self
+    }
+
+    /// Expect the next message to be of type `typ`, and answer it with the messages returned by
+    /// `f`, which is passed the received message.
+    pub fn expect_with<F>(&mut self, typ: MessageType, f: F) -> &mut MockService
+    where
+        F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        self.script
+            .session
+            .expected
+            .push_back((typ.to_u16(), Box::new(f)));
+        self
+    }
+
+    /// Answer every message of type `typ` that isn't expected with `responses`.
+    pub fn on(&mut self, typ: MessageType, responses: Vec<Vec<u8>>) -> &mut MockService {
+        self.on_with(typ, move |_| responses.clone())
+    }
+
+    /// Answer every message of type `typ` that isn't expected with the messages returned by `f`,
+    /// which is passed the received message.
+    pub fn on_with<F>(&mut self, typ: MessageType, f: F) -> &mut MockService
+    where
+        F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        self.script.canned.insert(typ.to_u16(), Box::new(f));
+        self
+    }
+
+    /// Start serving clients in the background.
+    ///
+    /// Clients may connect any number of times; all connections share one script.
+    pub fn spawn(self) -> MockHandle {
+        let MockService {
+            name,
+            path,
+            listener,
+            script,
+        } = self;
+        let script = Arc::new(Mutex::new(script));
+        let (fut, task) = serve(name.clone(), listener, script.clone()).remote_handle();
+        runtime::spawn(fut);
+        MockHandle {
+            name,
+            path,
+            script,
+            _task: task,
+        }
+    }
+}
+
+/// A running `MockService`. Dropping it stops the service, closing every connection to it, and
+/// removes its socket.
+pub struct MockHandle {
+    name: String,
+    path: PathBuf,
+    script: Arc<Mutex<Script>>,
+    _task: RemoteHandle<()>,
+}
+
+impl MockHandle {
+    /// The messages received so far, in order of arrival.
+    pub fn received(&self) -> Vec<(u16, Vec<u8>)> {
+        self.script.lock().unwrap().received.clone()
+    }
+
+    /// Wait up to `FINISH_TIMEOUT` for every expected message to arrive, then check that no
+    /// unexpected messages were received. Returns all of the messages received.
+    pub async fn finish(self) -> Result<Vec<(u16, Vec<u8>)>, MockError> {
+        let waiting = async {
+            while !self.script.lock().unwrap().remaining().is_empty() {
+                runtime::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        let _ = runtime::timeout(FINISH_TIMEOUT, waiting).await;
+
+        let mut script = self.script.lock().unwrap();
+        if let Some(e) = script.error.take() {
+            return Err(e);
+        }
+        let remaining = script.remaining();
+        if !remaining.is_empty() {
+            return Err(MockError::Unsatisfied { remaining });
+        }
+        Ok(std::mem::take(&mut script.received))
+    }
+
+    /// Stop the service, closing every connection to it, and return a new mock of the same
+    /// service listening on the same socket, with an empty script. Clients that reconnect reach
+    /// the new mock.
+    pub async fn restart(self) -> Result<MockService, io::Error> {
+        let name = self.name.clone();
+        let path = self.path.clone();
+        drop(self);
+        MockService::bind_path(&name, path).await
+    }
+}
+
+impl Drop for MockHandle {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Serialize `msg`, eg. to use as a response from a `MockService`.
+pub fn message_bytes<M: MessageOutCompound>(msg: M) -> Vec<u8> {
+    let mut v = vec![];
+    for chunk in msg.as_byte_chunks() {
+        v.extend_from_slice(chunk.as_ref());
+    }
+    v
+}
+
+async fn serve(name: String, listener: UnixListener, script: Arc<Mutex<Script>>) {
+    // Dropping the handles, along with this future, closes the connections.
+    let mut connections = vec![];
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(e) => {
+                script.lock().unwrap().error.get_or_insert(e.into());
+                return;
+            }
+        };
+        let conn = Connection::from_stream(format!("mock {}", name), sock);
+        let (fut, handle) = serve_connection(conn, script.clone()).remote_handle();
+        runtime::spawn(fut);
+        connections.push(handle);
+    }
+}
+
+async fn serve_connection(mut conn: Connection, script: Arc<Mutex<Script>>) {
+    let (index, greeting) = {
+        let mut script = script.lock().unwrap();
+        let index = script.accepted;
+        script.accepted += 1;
+        (index, script.session(index).greeting.clone())
+    };
+    for msg in greeting {
+        if let Err(e) = conn.send(&msg[..]).await {
+            script.lock().unwrap().error.get_or_insert(e.into());
+            return;
+        }
+    }
+    loop {
+        let (typ, buf) = match conn.recv().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    script.lock().unwrap().error.get_or_insert(e.into());
+                }
+                return;
+            }
+        };
+        let responses = {
+            let mut script = script.lock().unwrap();
+            script.received.push((typ, buf.to_vec()));
+            let expected = &mut script.session(index).expected;
+            if expected.front().map(|(t, _)| *t) == Some(typ) {
+                let (_, mut f) = expected.pop_front().unwrap();
+                f(&buf)
+            } else if let Some(f) = script.canned.get_mut(&typ) {
+                f(&buf)
+            } else {
+                debug!(typ = %AnyMessageType::from_u16(typ), "mock service received an unexpected message");
+                script.error.get_or_insert(MockError::Unexpected { typ });
+                continue;
+            }
+        };
+        for r in responses {
+            if let Err(e) = conn.send(&r[..]).await {
+                script.lock().unwrap().error.get_or_insert(e.into());
+                return;
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_mock_service() {
+    use crate::service;
+    use crate::util::MessageHeader;
+
+    let mut mock = MockService::bind("dummy").await.unwrap();
+    let dummy = message_bytes(&MessageHeader::new(4, MessageType::DUMMY));
+    let dummy2 = message_bytes(&MessageHeader::new(4, MessageType::DUMMY2));
+    mock.expect(MessageType::DUMMY, vec![dummy2.clone(), dummy2.clone()])
+        .on(MessageType::DUMMY2, vec![dummy.clone()]);
+    let cfg = mock.config();
+    let mock = mock.spawn();
+
+    let mut conn = service::connect(&cfg, "dummy").await.unwrap();
+    conn.send(&dummy[..]).await.unwrap();
+    for _ in 0..2 {
+        assert_eq!(conn.recv().await.unwrap().0, MessageType::DUMMY2.to_u16());
+    }
+    conn.send(&dummy2[..]).await.unwrap();
+    assert_eq!(conn.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+    // DUMMY is only expected once, and has no canned response.
+    conn.send(&dummy[..]).await.unwrap();
+    conn.send(&dummy2[..]).await.unwrap();
+    assert_eq!(conn.recv().await.unwrap().0, MessageType::DUMMY.to_u16());
+
+    let path = mock.path.clone();
+    match mock.finish().await {
+        Err(MockError::Unexpected { typ }) => assert_eq!(typ, MessageType::DUMMY.to_u16()),
+        r => panic!("expected Unexpected, got {:?}", r.map(|_| ())),
+    }
+    assert!(!path.exists());
+}
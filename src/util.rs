@@ -1,11 +1,14 @@
+pub mod codec;
 pub mod config;
 pub use config::Config;
 pub mod data;
+pub mod dispatch;
+pub use dispatch::Dispatch;
 mod hello;
 pub use hello::*;
 pub mod message;
 pub use message::*;
-mod message_type;
+pub mod message_type;
 pub use message_type::*;
 pub mod paths;
 mod peerid;
@@ -13,3 +16,5 @@ pub use peerid::*;
 pub mod serial;
 pub mod strings;
 pub mod time;
+pub mod version;
+pub use version::ProtocolVersion;
@@ -0,0 +1,446 @@
+//! Framing of GNUnet messages on a byte stream.
+//!
+//! Every GNUnet message starts with a `MessageHeader` giving the length of the whole message,
+//! header included. `Framed` splits an `AsyncRead` into such messages and writes messages to an
+//! `AsyncWrite`, checking the header of each.
+//!
+//! Messages are read into a buffer that is reused from one message to the next. They can be
+//! borrowed from it with `poll_read_frame` and `frame`, or copied out through the `Stream` impl.
+//! `poll_write_message` writes the chunks of a compound message with a single vectored write.
+
+use crate::util::serial::*;
+use crate::util::{MessageHeader, MessageOutCompound};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use smallvec::SmallVec;
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::mem::size_of;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tracing::debug;
+
+/// The largest message the header's length field can describe.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+const HEADER_SIZE: usize = size_of::<MessageHeader>();
+
+/// Once this many bytes are waiting to be written, `Framed` stops accepting messages until some of
+/// them have been written.
+const WRITE_BACKPRESSURE: usize = 8 * 1024;
+
+/// The reason a message was rejected by `Framed`.
+///
+/// Returned inside an `io::Error` of kind `InvalidData`.
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Message header claims a length of {len}, which is shorter than the header itself")]
+    TooShort { len: usize },
+    #[error("Message of length {len} exceeds the maximum message size of {max}")]
+    TooLong { len: usize, max: usize },
+    #[error("Message header claims a length of {header_len}, but the message is {len} bytes")]
+    LengthMismatch { header_len: usize, len: usize },
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A `Stream` of the messages read from `T`, and a `Sink` for messages to write to `T`.
+///
+/// The stream yields `(message type, buffer)`, where `buffer` contains the entire message
+/// (including the header). It ends cleanly if `T` ends between messages.
+///
+/// Only the bytes of the message being read are requested from `T`, so after each message the
+/// underlying reader is positioned at the start of the next one. After a message has been rejected
+/// the stream can't find the start of the next one, and should be dropped.
+pub struct Framed<T> {
+    inner: T,
+    max_message_size: usize,
+    read_buf: Vec<u8>,
+    read_len: usize,
+    /// The length of the message at the start of `read_buf`, or 0 if there is none.
+    frame_len: usize,
+    write_buf: Vec<u8>,
+    written: usize,
+}
+
+impl<T> Framed<T> {
+    pub fn new(inner: T) -> Framed<T> {
+        Framed {
+            inner,
+            max_message_size: MAX_MESSAGE_SIZE,
+            read_buf: Vec::new(),
+            read_len: 0,
+            frame_len: 0,
+            write_buf: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Reject messages longer than `max` bytes, in either direction.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max.clamp(HEADER_SIZE, MAX_MESSAGE_SIZE);
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader/writer. Any partially read message, and any messages that
+    /// haven't been flushed yet, are lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// The message last returned by `poll_read_frame`, header included. Empty if the last read
+    /// didn't return a message.
+    pub fn frame(&self) -> &[u8] {
+        &self.read_buf[..self.frame_len]
+    }
+
+    /// Check a message of `len` bytes that starts with `head`, which holds at least its header if
+    /// `len` is long enough.
+    fn check_message(&self, head: &[u8], len: usize) -> Result<(), FrameError> {
+        self.check_length(len)?;
+        let head: &MessageHeader = cast(&head[..HEADER_SIZE]);
+        match head.length() as usize {
+            header_len if header_len == len => Ok(()),
+            header_len => Err(FrameError::LengthMismatch { header_len, len }),
+        }
+    }
+
+    fn check_length(&self, len: usize) -> Result<(), FrameError> {
+        if len < HEADER_SIZE {
+            Err(FrameError::TooShort { len })
+        } else if len > self.max_message_size {
+            Err(FrameError::TooLong {
+                len,
+                max: self.max_message_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> Framed<T> {
+    /// Read the next message into the read buffer, where `frame` can borrow it until the next
+    /// read. Returns the type of the message, or `None` if `T` ended between messages.
+    pub fn poll_read_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<u16>, io::Error>> {
+        self.frame_len = 0;
+        loop {
+            let want = if self.read_len < HEADER_SIZE {
+                HEADER_SIZE
+            } else {
+                let head: &MessageHeader = cast(&self.read_buf[..HEADER_SIZE]);
+                let len = head.length() as usize;
+                if self.read_len == HEADER_SIZE {
+                    if let Err(e) = self.check_length(len) {
+                        self.read_len = 0;
+                        return Poll::Ready(Err(e.into()));
+                    }
+                }
+                if self.read_len == len {
+                    debug!(
+                        typ = head.msg_type_u16(),
+                        len = head.length(),
+                        "type: {:?}",
+                        head.msg_type(),
+                    );
+                    let typ = head.msg_type_u16();
+                    self.read_len = 0;
+                    self.frame_len = len;
+                    return Poll::Ready(Ok(Some(typ)));
+                }
+                len
+            };
+
+            if self.read_buf.len() < want {
+                self.read_buf.resize(want, 0u8);
+            }
+            let n =
+                ready!(Pin::new(&mut self.inner)
+                    .poll_read(cx, &mut self.read_buf[self.read_len..want]))?;
+            if n == 0 {
+                if self.read_len == 0 {
+                    return Poll::Ready(Ok(None));
+                }
+                self.read_len = 0;
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.read_len += n;
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for Framed<T> {
+    type Item = Result<(u16, Buffer), io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(this.poll_read_frame(cx)) {
+            Ok(Some(typ)) => Poll::Ready(Some(Ok((typ, Buffer::from_slice(this.frame()))))),
+            Ok(None) => Poll::Ready(None),
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Framed<T> {
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        while self.written < self.write_buf.len() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.written..])
+            {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write `msg` with a single vectored write, without first copying its chunks into the write
+    /// buffer. Whatever part of it isn't written straight away is buffered, to be written by
+    /// `poll_flush`. Messages buffered earlier are written first.
+    ///
+    /// Returns `Ready(Ok(()))` once all of `msg` has been written or buffered. Until then none of
+    /// it has been, so dropping a write that is pending never leaves part of a message behind.
+    pub fn poll_write_message<M: MessageOutCompound>(
+        &mut self,
+        cx: &mut Context<'_>,
+        msg: &M,
+    ) -> Poll<Result<(), io::Error>> {
+        if !self.write_buf.is_empty() {
+            ready!(self.poll_write_buf(cx))?;
+        }
+        let chunks: SmallVec<[M::Bytes; 8]> = msg.as_byte_chunks().into_iter().collect();
+        let len = chunks.iter().map(|c| c.as_ref().len()).sum();
+        let mut head = [0u8; HEADER_SIZE];
+        let mut filled = 0;
+        for chunk in &chunks {
+            let chunk = chunk.as_ref();
+            let n = chunk.len().min(HEADER_SIZE - filled);
+            head[filled..filled + n].copy_from_slice(&chunk[..n]);
+            filled += n;
+        }
+        self.check_message(&head, len)?;
+
+        let slices: SmallVec<[IoSlice<'_>; 8]> =
+            chunks.iter().map(|c| IoSlice::new(c.as_ref())).collect();
+        let mut n = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, &slices))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        for chunk in &chunks {
+            let chunk = chunk.as_ref();
+            if n < chunk.len() {
+                self.write_buf.extend_from_slice(&chunk[n..]);
+            }
+            n = n.saturating_sub(chunk.len());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, M> Sink<M> for Framed<T>
+where
+    T: AsyncWrite + Unpin,
+    M: MessageOutCompound,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= WRITE_BACKPRESSURE {
+            this.poll_write_buf(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: M) -> Result<(), io::Error> {
+        let this = self.get_mut();
+        let start = this.write_buf.len();
+        for chunk in msg.as_byte_chunks() {
+            this.write_buf.extend_from_slice(chunk.as_ref());
+        }
+        let len = this.write_buf.len() - start;
+        let res = this.check_message(&this.write_buf[start..], len);
+        if res.is_err() {
+            this.write_buf.truncate(start);
+        }
+        Ok(res?)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            other => other,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Framed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Framed")
+            .field("max_message_size", &self.max_message_size)
+            .field("read_len", &self.read_len)
+            .field("write_buf", &(self.write_buf.len() - self.written))
+            .finish()
+    }
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_framed() {
+    use crate::util::MessageType;
+    use futures::io::Cursor;
+    use futures::{SinkExt, StreamExt};
+
+    let dummy = MessageHeader::new(4, MessageType::DUMMY);
+    let mut framed = Framed::new(Cursor::new(Vec::new()));
+    framed.send(&dummy).await.unwrap();
+    framed.send(&[0u8, 6, 0, 3, 1, 2][..]).await.unwrap();
+    let e = framed.send(&[0u8, 7, 0, 3, 1, 2][..]).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    let bytes = framed.into_inner().into_inner();
+    assert_eq!(bytes, [0, 4, 0, 2, 0, 6, 0, 3, 1, 2]);
+    let mut framed = Framed::new(Cursor::new(bytes));
+    let (typ, buf) = framed.next().await.unwrap().unwrap();
+    assert_eq!((typ, &buf[..]), (2, &[0, 4, 0, 2][..]));
+    let (typ, buf) = framed.next().await.unwrap().unwrap();
+    assert_eq!((typ, &buf[..]), (3, &[0, 6, 0, 3, 1, 2][..]));
+    assert!(framed.next().await.is_none());
+
+    // header shorter than itself
+    let mut framed = Framed::new(Cursor::new(vec![0, 2, 0, 2]));
+    let e = framed.next().await.unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    // longer than allowed
+    let mut framed = Framed::new(Cursor::new(vec![0, 6, 0, 3, 1, 2]));
+    framed.set_max_message_size(5);
+    let e = framed.next().await.unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    // truncated
+    let mut framed = Framed::new(Cursor::new(vec![0, 6, 0, 3, 1]));
+    let e = framed.next().await.unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[cfg_attr(feature = "async-std", async_std::test)]
+#[cfg_attr(feature = "tokio", tokio::test)]
+async fn test_framed_in_place() {
+    use futures::future::poll_fn;
+    use futures::io::Cursor;
+    use futures::SinkExt;
+
+    /// Writes at most 3 bytes at a time.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let mut n = 0;
+            for b in bufs {
+                let k = b.len().min(3 - n);
+                self.0.extend_from_slice(&b[..k]);
+                n += k;
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Compound<'a>(&'a [u8], &'a [u8]);
+
+    impl<'a> MessageOutCompound for Compound<'a> {
+        type Bytes = &'a [u8];
+        type Chunks = [&'a [u8]; 2];
+        fn as_byte_chunks(&self) -> Self::Chunks {
+            [self.0, self.1]
+        }
+    }
+
+    // The first write takes 3 bytes from both chunks, and the rest is buffered.
+    let mut framed = Framed::new(Trickle(vec![]));
+    let msg = Compound(&[0, 7], &[0, 3, 1, 2, 3]);
+    poll_fn(|cx| framed.poll_write_message(cx, &msg))
+        .await
+        .unwrap();
+    assert_eq!(framed.get_ref().0, [0, 7, 0]);
+    let e = poll_fn(|cx| framed.poll_write_message(cx, &Compound(&[0, 8], &[0, 3])))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    SinkExt::<&[u8]>::flush(&mut framed).await.unwrap();
+    assert_eq!(framed.get_ref().0, [0, 7, 0, 3, 1, 2, 3]);
+
+    // Messages are read into the same buffer.
+    let mut framed = Framed::new(Cursor::new(vec![0, 6, 0, 3, 1, 2, 0, 4, 0, 2]));
+    let typ = poll_fn(|cx| framed.poll_read_frame(cx)).await.unwrap();
+    assert_eq!((typ, framed.frame()), (Some(3), &[0, 6, 0, 3, 1, 2][..]));
+    let ptr = framed.frame().as_ptr();
+    let typ = poll_fn(|cx| framed.poll_read_frame(cx)).await.unwrap();
+    assert_eq!((typ, framed.frame()), (Some(2), &[0, 4, 0, 2][..]));
+    assert_eq!(framed.frame().as_ptr(), ptr);
+    assert_eq!(
+        poll_fn(|cx| framed.poll_read_frame(cx)).await.unwrap(),
+        None
+    );
+    assert!(framed.frame().is_empty());
+}
@@ -93,6 +93,22 @@ pub enum ConfigGetIntError {
     },
 }
 #[derive(Debug, Error)]
+pub enum ConfigGetStringError {
+    #[error("The config does not contain a section with that name")]
+    NoSection,
+    #[error("The config section does contain that key")]
+    NoKey,
+}
+#[derive(Debug, Error)]
+pub enum ConfigGetYesNoError {
+    #[error("The config does not contain a section with that name")]
+    NoSection,
+    #[error("The config section does contain that key")]
+    NoKey,
+    #[error("The value is neither YES nor NO. Value: '{value}'")]
+    Parse { value: String },
+}
+#[derive(Debug, Error)]
 pub enum ConfigGetFloatError {
     #[error("The config does not contain a section with that name")]
     NoSection,
@@ -317,6 +333,35 @@ impl Config {
         }
     }
 
+    pub fn get_string(&self, section: &str, key: &str) -> Result<&str, ConfigGetStringError> {
+        use self::ConfigGetStringError::*;
+
+        match self.data.get(section) {
+            Some(map) => match map.get(key) {
+                Some(value) => Ok(value),
+                None => Err(NoKey),
+            },
+            None => Err(NoSection),
+        }
+    }
+
+    /// Get a boolean option, written as `YES` or `NO` in any case.
+    pub fn get_yes_no(&self, section: &str, key: &str) -> Result<bool, ConfigGetYesNoError> {
+        use self::ConfigGetYesNoError::*;
+
+        match self.data.get(section) {
+            Some(map) => match map.get(key) {
+                Some(value) if value.eq_ignore_ascii_case("YES") => Ok(true),
+                Some(value) if value.eq_ignore_ascii_case("NO") => Ok(false),
+                Some(value) => Err(Parse {
+                    value: value.clone(),
+                }),
+                None => Err(NoKey),
+            },
+            None => Err(NoSection),
+        }
+    }
+
     pub fn get_float(&self, section: &str, key: &str) -> Result<f32, ConfigGetFloatError> {
         use self::ConfigGetFloatError::*;
 
@@ -373,7 +418,7 @@ impl Config {
                 std::mem::swap(val, &mut value);
                 return Some(value);
             }
-            map.insert(section.into_owned(), value);
+            map.insert(key.into_owned(), value);
             return None;
         }
 
@@ -542,4 +587,33 @@ mod tests {
         let expanded = cfg.expand_dollar(unexpanded).unwrap();
         assert_eq!(expanded, "foo in_paths in_env in_env_wub_blah");
     }
+
+    #[test]
+    fn test_set_string() {
+        let mut cfg = Config::empty();
+
+        assert!(cfg
+            .set_string("gns", "PORT", String::from("2086"))
+            .is_none());
+        assert!(cfg
+            .set_string("gns", "UNIXPATH", String::from("/gns.sock"))
+            .is_none());
+        let old = cfg.set_string("gns", "PORT", String::from("2087"));
+        assert_eq!(old.as_deref(), Some("2086"));
+        assert_eq!(cfg.get_string("gns", "PORT").unwrap(), "2087");
+        assert_eq!(cfg.get_string("gns", "UNIXPATH").unwrap(), "/gns.sock");
+    }
+
+    #[test]
+    fn test_get_yes_no() {
+        let mut cfg = Config::empty();
+
+        cfg.set_string("arm", "UNIX_MATCH_UID", String::from("YES"));
+        cfg.set_string("arm", "UNIX_MATCH_GID", String::from("no"));
+        cfg.set_string("arm", "START_ON_DEMAND", String::from("maybe"));
+        assert!(cfg.get_yes_no("arm", "UNIX_MATCH_UID").unwrap());
+        assert!(!cfg.get_yes_no("arm", "UNIX_MATCH_GID").unwrap());
+        assert!(cfg.get_yes_no("arm", "START_ON_DEMAND").is_err());
+        assert!(cfg.get_yes_no("arm", "IMMEDIATE_START").is_err());
+    }
 }
@@ -0,0 +1,181 @@
+//! Dispatching received messages to handlers chosen by their type.
+//!
+//! A `Dispatch` replaces a `match` on the results of `expect` and `expect_either` when a reply
+//! may be one of several messages:
+//!
+//! ```rust
+//! use gnunet::util::dispatch::Dispatch;
+//! use gnunet::util::{ExpectError, MessageType};
+//! use gnunet::service::arm::msg::{ArmResultMessage, ListResult};
+//!
+//! # let (typ, buf) = (MessageType::ARM_RESULT.to_u16(), vec![0u8; 20]);
+//! let reply = Dispatch::new()
+//!     .on(|res: ArmResultMessage| format!("result {}", res.prefix.result.get()))
+//!     .on(|list: ListResult| format!("{} services", list.services.len()))
+//!     .fallback(|typ, _| format!("unknown message type {}", typ))
+//!     .dispatch(typ, &buf);
+//! # let _ = reply;
+//! ```
+//!
+//! A message whose type has no handler goes to the fallback, or fails with
+//! `ExpectError::UnexpectedMessage` if there isn't one. A message that can't be parsed as the
+//! type its handler takes fails with `ExpectError::ParseFailure`.
+
+use super::message::{ExpectError, MessageIn};
+use super::MessageType;
+use std::collections::HashMap;
+use std::fmt;
+
+type Handler<'h, T> = Box<dyn FnMut(&[u8]) -> Result<T, ExpectError> + Send + 'h>;
+type Fallback<'h, T> = Box<dyn FnMut(u16, &[u8]) -> T + Send + 'h>;
+
+/// A table of handlers for received messages, keyed by message type, each producing a `T`.
+///
+/// The handlers must be `Send`, so that a `Dispatch` can be kept across an `.await`.
+pub struct Dispatch<'h, T> {
+    handlers: HashMap<u16, Handler<'h, T>>,
+    fallback: Option<Fallback<'h, T>>,
+}
+
+impl<'h, T> Dispatch<'h, T> {
+    pub fn new() -> Dispatch<'h, T> {
+        Dispatch {
+            handlers: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// Handle messages of type `M::msg_type()` by parsing them and passing them to `f`. Replaces
+    /// any handler already registered for that type.
+    pub fn on<M, F>(mut self, mut f: F) -> Dispatch<'h, T>
+    where
+        M: for<'a> MessageIn<'a>,
+        F: FnMut(M) -> T + Send + 'h,
+    {
+        let handler = move |b: &[u8]| match M::from_bytes(b) {
+            Some(m) => Ok(f(m)),
+            None => Err(ExpectError::ParseFailure {
+                msg_type: M::msg_type(),
+                len: b.len(),
+            }),
+        };
+        self.handlers
+            .insert(M::msg_type().to_u16(), Box::new(handler));
+        self
+    }
+
+    /// Handle messages of type `msg_type` by passing their unparsed bytes, including the header,
+    /// to `f`. This suits messages that borrow from their bytes.
+    pub fn on_raw<F>(mut self, msg_type: MessageType, mut f: F) -> Dispatch<'h, T>
+    where
+        F: FnMut(&[u8]) -> T + Send + 'h,
+    {
+        self.handlers
+            .insert(msg_type.to_u16(), Box::new(move |b: &[u8]| Ok(f(b))));
+        self
+    }
+
+    /// Handle messages of every type without a handler of its own by passing their type and
+    /// unparsed bytes to `f`.
+    pub fn fallback<F>(mut self, f: F) -> Dispatch<'h, T>
+    where
+        F: FnMut(u16, &[u8]) -> T + Send + 'h,
+    {
+        self.fallback = Some(Box::new(f));
+        self
+    }
+
+    /// Whether messages of type `msg_type` have a handler of their own.
+    pub fn handles(&self, msg_type: u16) -> bool {
+        self.handlers.contains_key(&msg_type)
+    }
+
+    /// Pass the message of type `msg_type` in `bytes` to its handler.
+    pub fn dispatch(&mut self, msg_type: u16, bytes: &[u8]) -> Result<T, ExpectError> {
+        if let Some(handler) = self.handlers.get_mut(&msg_type) {
+            return handler(bytes);
+        }
+        match &mut self.fallback {
+            Some(fallback) => Ok(fallback(msg_type, bytes)),
+            None => Err(ExpectError::UnexpectedMessage { msg_type }),
+        }
+    }
+}
+
+impl<'h, T> Default for Dispatch<'h, T> {
+    fn default() -> Dispatch<'h, T> {
+        Dispatch::new()
+    }
+}
+
+impl<'h, T> fmt::Debug for Dispatch<'h, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut types: Vec<_> = self.handlers.keys().collect();
+        types.sort();
+        f.debug_struct("Dispatch")
+            .field("types", &types)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+#[test]
+fn test_dispatch() {
+    use crate::service::arm::msg::{ArmResultMessage, ListResult};
+    use crate::service::arm::ArmResult;
+    use crate::util::serial::AsBytes;
+
+    #[derive(Debug, PartialEq)]
+    enum Reply {
+        Result(ArmResult),
+        List(usize),
+        Other(u16),
+    }
+
+    let mut calls = 0;
+    let mut dispatch = Dispatch::new()
+        .on(|res: ArmResultMessage| {
+            calls += 1;
+            Reply::Result(ArmResult::from_u32(res.prefix.result.get()).unwrap())
+        })
+        .on(|list: ListResult| Reply::List(list.services.len()));
+    assert!(dispatch.handles(MessageType::ARM_RESULT.to_u16()));
+    assert!(!dispatch.handles(MessageType::ARM_STATUS.to_u16()));
+
+    let result = ArmResultMessage::new(5, ArmResult::Starting);
+    assert_eq!(
+        dispatch
+            .dispatch(MessageType::ARM_RESULT.to_u16(), result.prefix.as_bytes())
+            .unwrap(),
+        Reply::Result(ArmResult::Starting)
+    );
+    match dispatch.dispatch(
+        MessageType::ARM_RESULT.to_u16(),
+        &result.prefix.as_bytes()[..10],
+    ) {
+        Err(ExpectError::ParseFailure { msg_type, len }) => {
+            assert_eq!(msg_type, MessageType::ARM_RESULT);
+            assert_eq!(len, 10);
+        }
+        r => panic!("expected ParseFailure, got {:?}", r),
+    }
+    match dispatch.dispatch(MessageType::ARM_STATUS.to_u16(), &[]) {
+        Err(ExpectError::UnexpectedMessage { msg_type }) => {
+            assert_eq!(msg_type, MessageType::ARM_STATUS.to_u16())
+        }
+        r => panic!("expected UnexpectedMessage, got {:?}", r),
+    }
+
+    let mut dispatch = dispatch
+        .on_raw(MessageType::ARM_RESULT, |b| Reply::Other(b.len() as u16))
+        .fallback(|typ, _| Reply::Other(typ));
+    assert_eq!(
+        dispatch
+            .dispatch(MessageType::ARM_RESULT.to_u16(), result.prefix.as_bytes())
+            .unwrap(),
+        Reply::Other(20)
+    );
+    assert_eq!(dispatch.dispatch(4321, &[]).unwrap(), Reply::Other(4321));
+    drop(dispatch);
+    assert_eq!(calls, 1);
+}
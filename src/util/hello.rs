@@ -21,6 +21,24 @@ impl<S, B> HelloMessage<S, B> {
     }
 }
 
+impl HelloMessage<&str, &[u8]> {
+    /// Copy a HELLO borrowed from a received message.
+    pub fn into_owned(self) -> Hello {
+        HelloMessage {
+            prefix: self.prefix,
+            addresses: self
+                .addresses
+                .into_iter()
+                .map(|a| HelloAddress {
+                    transport_name: a.transport_name.to_string(),
+                    expiration: a.expiration,
+                    address: a.address.to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, AsBytes, FromBytes)]
 #[repr(C)]
 pub struct HelloPrefix {
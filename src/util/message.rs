@@ -1,10 +1,61 @@
-use super::MessageType;
+//! Traits for parsing and serializing GNUnet IPC messages.
+//!
+//! Most messages can derive their `MessageIn` and `MessageOutCompound` impls with
+//! `#[derive(GnunetMessage)]`. The struct is given the type of the message with
+//! `#[gnunet(msg_type = ...)]`, and each of its fields one of the following attributes:
+//!
+//! * `#[gnunet(prefix)]`: the first field, a fixed-size `#[repr(C)]` struct deriving `AsBytes`,
+//!   `FromBytes` and `Copy`, whose first field is `header: MessageHeader`.
+//! * `#[gnunet(cstr)]`: a null-terminated string. The field's type must implement `AsRef<str>` to
+//!   be sent and `From<&str>` to be received. If nothing is left of a received message, the string
+//!   is empty.
+//! * `#[gnunet(bytes)]`: raw bytes. The field's type must implement `AsRef<[u8]>` to be sent and
+//!   `From<&[u8]>` to be received.
+//! * `#[gnunet(array)]`: a `Vec` of fixed-size elements, which must be unaligned `AsBytes` and
+//!   `FromBytes` types such as `PeerIdentity` or `u32be`.
+//!
+//! `cstr` and `bytes` fields can be given `len = field`, naming a field of the prefix which holds
+//! their length in bytes (including the null for strings). Similarly `array` fields can be given
+//! `count = field`. A `bytes` or `array` field without a length extends to the end of the message,
+//! so must be the last field.
+//!
+//! The derive also adds a `fill_header` method, which sets the prefix's header, length and count
+//! fields, checking that the message isn't too long. A received message is rejected if its length
+//! doesn't match its contents.
+//!
+//! ```rust
+//! use gnunet::util::serial::*;
+//! use gnunet::util::{GnunetMessage, MessageHeader, MessageType};
+//!
+//! #[derive(Clone, Copy, AsBytes, FromBytes)]
+//! #[repr(C)]
+//! pub struct RenamePrefix {
+//!     header: MessageHeader,
+//!     old_name_len: u16be,
+//!     new_name_len: u16be,
+//! }
+//!
+//! #[derive(GnunetMessage)]
+//! #[gnunet(msg_type = IDENTITY_RENAME)]
+//! pub struct Rename<S> {
+//!     #[gnunet(prefix)]
+//!     prefix: RenamePrefix,
+//!     #[gnunet(cstr, len = old_name_len)]
+//!     old_name: S,
+//!     #[gnunet(cstr, len = new_name_len)]
+//!     new_name: S,
+//! }
+//! ```
+
+use super::{AnyMessageType, MessageType};
 use crate::util::serial::*;
 pub use either::*;
 use smallvec::{smallvec, SmallVec};
 use std::convert::TryInto;
 use std::mem::size_of;
 
+pub use gnunet_derive::GnunetMessage;
+
 pub trait MessageIn<'a>: Sized {
     fn msg_type() -> MessageType;
     fn from_bytes(b: &'a [u8]) -> Option<Self>;
@@ -15,7 +66,7 @@ pub trait MessageOut {
     fn as_bytes(&self) -> Self::Bytes;
 }
 
-impl<'a, T: AsBytes> MessageOut for &'a T {
+impl<'a, T: AsBytes + ?Sized> MessageOut for &'a T {
     type Bytes = &'a [u8];
     fn as_bytes(&self) -> &'a [u8] {
         AsBytes::as_bytes(*self)
@@ -28,6 +79,15 @@ pub trait MessageOutCompound {
     fn as_byte_chunks(&self) -> Self::Chunks;
 }
 
+/// Any `MessageOut` is a compound message with a single chunk.
+impl<M: MessageOut> MessageOutCompound for M {
+    type Bytes = M::Bytes;
+    type Chunks = Option<M::Bytes>;
+    fn as_byte_chunks(&self) -> Self::Chunks {
+        Some(self.as_bytes())
+    }
+}
+
 impl<'a> MessageOutCompound for &'a FooMessage {
     type Bytes = &'a [u8];
     type Chunks = SmallVec<[&'a [u8]; 5]>;
@@ -42,17 +102,73 @@ impl<'a> MessageOutCompound for &'a FooMessage {
     }
 }
 
+/// Helpers for the code generated by `#[derive(GnunetMessage)]`.
+#[doc(hidden)]
+pub mod derive_support {
+    use crate::util::serial::*;
+    use std::convert::TryInto;
+    use std::str;
+
+    pub use smallvec::SmallVec;
+
+    /// Split a null-terminated string of `len` bytes, or up to the first null, from `b`.
+    pub fn parse_cstr(b: &[u8], len: Option<usize>) -> Option<(&str, &[u8])> {
+        let (s, rest) = match len {
+            Some(0) => return Some(("", b)),
+            Some(len) => {
+                let (s, rest) = try_split_at(b, len)?;
+                let (&nul, s) = s.split_last()?;
+                if nul != 0 || s.contains(&0) {
+                    return None;
+                }
+                (s, rest)
+            }
+            None if b.is_empty() => return Some(("", b)),
+            None => split_on(b, 0)?,
+        };
+        Some((str::from_utf8(s).ok()?, rest))
+    }
+
+    /// Split `len` bytes, or all of the bytes, from `b`.
+    pub fn parse_bytes(b: &[u8], len: Option<usize>) -> Option<(&[u8], &[u8])> {
+        try_split_at(b, len.unwrap_or(b.len()))
+    }
+
+    /// Split `count` elements, or as many as fit exactly, from `b`.
+    pub fn parse_array<'a, T: FromBytes + Copy + 'a>(
+        mut b: &'a [u8],
+        count: Option<usize>,
+    ) -> Option<(Vec<T>, &'a [u8])> {
+        let mut v = vec![];
+        while count.map_or(!b.is_empty(), |c| v.len() < c) {
+            let (t, rest) = try_cast_prefix::<T>(b)?;
+            v.push(*t);
+            b = rest;
+        }
+        Some((v, b))
+    }
+
+    /// The total length of `chunks`, if it fits in a message.
+    pub fn checked_len<C: IntoIterator>(chunks: C) -> Option<u16>
+    where
+        C::Item: AsRef<[u8]>,
+    {
+        let len: usize = chunks.into_iter().map(|c| c.as_ref().len()).sum();
+        len.try_into().ok()
+    }
+}
+
 /// Error that can be generated when attempting to connect to a service.
 #[derive(Debug, Error)]
 pub enum ExpectError {
     #[error(
-        "Unexpected message type: {msg_type} ({:?})",
-        MessageType::from_u16(*msg_type)
+        "Unexpected message type: {}",
+        AnyMessageType::from_u16(*msg_type)
     )]
     UnexpectedMessage { msg_type: u16 },
 
-    #[error("Failed to parse message of type: {:?}", msg_type)]
-    ParseFailure { msg_type: MessageType },
+    #[error("Failed to parse message of type {msg_type:?} ({len} bytes)")]
+    ParseFailure { msg_type: MessageType, len: usize },
 }
 
 pub fn expect<'a, M: MessageIn<'a>>(msg_type: u16, bytes: &'a [u8]) -> Result<M, ExpectError> {
@@ -83,6 +199,7 @@ fn parse_msg<'a, M: MessageIn<'a>>(msg_type: u16, b: &'a [u8]) -> Result<M, Expe
         Some(m) => Ok(m),
         None => Err(ExpectError::ParseFailure {
             msg_type: M::msg_type(),
+            len: b.len(),
         }),
     }
 }
@@ -134,6 +251,11 @@ impl MessageHeader {
     pub fn msg_type(&self) -> Option<MessageType> {
         MessageType::from_u16(self.msg_type_u16())
     }
+
+    /// The message type, whether or not it is one this crate knows about.
+    pub fn any_msg_type(&self) -> AnyMessageType {
+        AnyMessageType::from_u16(self.msg_type_u16())
+    }
 }
 
 // pub trait MessageTrait {
@@ -166,3 +288,66 @@ fn test_message_to_slice() {
     assert!(slice.iter().all(|&x| x == 0));
     assert_eq!(slice.len(), 6);
 }
+
+#[test]
+fn test_derive_gnunet_message() {
+    use crate::util::PeerIdentity;
+
+    #[derive(Clone, Copy, AsBytes, FromBytes)]
+    #[repr(C)]
+    struct DummyPrefix {
+        header: MessageHeader,
+        name_len: u16be,
+        peer_count: u16be,
+    }
+
+    #[derive(GnunetMessage)]
+    #[gnunet(msg_type = DUMMY)]
+    struct Dummy<'a> {
+        #[gnunet(prefix)]
+        prefix: DummyPrefix,
+        #[gnunet(cstr, len = name_len)]
+        name: String,
+        #[gnunet(array, count = peer_count)]
+        peers: Vec<PeerIdentity>,
+        #[gnunet(cstr)]
+        comment: &'a str,
+        #[gnunet(bytes)]
+        data: &'a [u8],
+    }
+
+    let msg = Dummy {
+        prefix: DummyPrefix {
+            header: MessageHeader::new(0, MessageType::DUMMY),
+            name_len: U16::ZERO,
+            peer_count: U16::ZERO,
+        },
+        name: "name".to_string(),
+        peers: vec![PeerIdentity::default(); 2],
+        comment: "",
+        data: &[1, 2, 3],
+    }
+    .fill_header()
+    .unwrap();
+    let mut bytes = vec![];
+    for chunk in (&msg).as_byte_chunks() {
+        bytes.extend_from_slice(chunk);
+    }
+    assert_eq!(bytes.len(), 8 + 5 + 64 + 1 + 3);
+    assert_eq!(
+        cast::<DummyPrefix>(&bytes[..8]).header,
+        MessageHeader::new(81, MessageType::DUMMY)
+    );
+    assert_eq!(bytes[4..8], [0, 5, 0, 2]); // name_len, peer_count
+
+    let parsed = Dummy::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.name, "name");
+    assert_eq!(parsed.peers.len(), 2);
+    assert_eq!(parsed.comment, "");
+    assert_eq!(parsed.data, [1, 2, 3]);
+
+    // The header length must match, and every field must be complete.
+    assert!(Dummy::from_bytes(&bytes[..80]).is_none());
+    bytes[1] = 40;
+    assert!(Dummy::from_bytes(&bytes[..40]).is_none());
+}
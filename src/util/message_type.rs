@@ -1,4 +1,23 @@
+//! The types of GNUnet IPC messages.
+//!
+//! `MessageType` lists the types this crate knows about, which are those of a GNUnet release. A few
+//! numbers were reused between releases:
+//!
+//! * GNUnet 0.11 and later number them `TRANSPORT_RECV_OK`, `CONSENSUS_P2P_ROUND_CONTEXT` and
+//!   `CONSENSUS_P2P_ABORT`. This is the default.
+//! * GNUnet 0.10 numbers them `TRANSPORT_SET_QUOTA`, `CONSENSUS_P2P_SYNCED` and
+//!   `CONSENSUS_P2P_FIN`. The `gnunet-0-10` cargo feature selects these names, and can't be
+//!   combined with the features selecting a later release (see `util::version`).
+//!
+//! Clients switched to another release with `set_version` still use this numbering, which none of
+//! their messages depend on. The types GNUnet only defines when configured with
+//! `--enable-malicious`, such as `DHT_ACT_MALICIOUS`, are added by the `malicious` feature.
+//!
+//! A type received from a peer may be one that isn't listed at all. `AnyMessageType` holds any
+//! `u16`, so that such types can still be logged and dispatched on.
+
 use num::{FromPrimitive, ToPrimitive};
+use std::fmt;
 
 impl MessageType {
     pub fn to_u16(&self) -> u16 {
@@ -12,7 +31,7 @@ impl MessageType {
 
 #[allow(non_camel_case_types)]
 #[repr(u16)]
-#[derive(Debug, FromPrimitive, ToPrimitive, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive, PartialEq, Eq, Hash)]
 pub enum MessageType {
     // UTIL message types
     /// Test if service is online.
@@ -620,9 +639,11 @@ pub enum MessageType {
     /// Message telling transport to limit its receive rate.
     /// (FIXME: was the above comment ever accurate?)
     /// Note: dead in TNG, replaced by RECV_OK!
-    // TRANSPORT_SET_QUOTA = 366,
+    #[cfg(feature = "gnunet-0-10")]
+    TRANSPORT_SET_QUOTA = 366,
 
     /// Message telling transport to limit its receive rate.
+    #[cfg(not(feature = "gnunet-0-10"))]
     TRANSPORT_RECV_OK = 366,
 
     /// Request to look addresses of peers in server.
@@ -955,17 +976,19 @@ pub enum MessageType {
 
     /// Report that the peer is synced with the partner after successfuly decoding
     /// the invertible bloom filter.
-    // XXX: duplicate
-    // CONSENSUS_P2P_SYNCED = 547,
+    #[cfg(feature = "gnunet-0-10")]
+    CONSENSUS_P2P_SYNCED = 547,
 
     /// Interaction os over, got synched and reported all elements
-    // XXX: duplicate
-    // CONSENSUS_P2P_FIN = 548,
+    #[cfg(feature = "gnunet-0-10")]
+    CONSENSUS_P2P_FIN = 548,
 
     /// Abort a round, don't send requested elements anymore
+    #[cfg(not(feature = "gnunet-0-10"))]
     CONSENSUS_P2P_ABORT = 548,
 
     /// Abort a round, don't send requested elements anymore
+    #[cfg(not(feature = "gnunet-0-10"))]
     CONSENSUS_P2P_ROUND_CONTEXT = 547,
 
     // SET message types
@@ -1495,16 +1518,14 @@ pub enum MessageType {
     /// that you got the notify successor message.
     XDHT_P2P_NOTIFY_SUCCESSOR_CONFIRMATION = 892,
 
-    // TODO: control with #[cfg]
-    // #if ENABLE_MALICIOUS
     /// Turn X-VINE DHT service malicious
+    #[cfg(feature = "malicious")]
     DHT_ACT_MALICIOUS = 893,
 
     /// Acknowledge receiving ACT MALICIOUS request
+    #[cfg(feature = "malicious")]
     DHT_CLIENT_ACT_MALICIOUS_OK = 894,
 
-    // #endif
-
     // Whanau DHT messages
     /// This message contains the query for performing a random walk
     WDHT_RANDOM_WALK = 910,
@@ -1551,11 +1572,10 @@ pub enum MessageType {
     /// RPS CS SEED Message for the Client to seed peers into rps
     RPS_CS_SEED = 954,
 
-    // #if ENABLE_MALICIOUS
     /// Turn RPS service malicious
+    #[cfg(feature = "malicious")]
     RPS_ACT_MALICIOUS = 955,
 
-    // #endif // ENABLE_MALICIOUS
     /// RPS client-service message to start a sub sampler
     RPS_CS_SUB_START = 956,
 
@@ -1916,3 +1936,96 @@ pub enum MessageType {
     /// Type used to match 'all' message types.
     ALL = 65535,
 }
+
+/// Either a message type this crate knows about, or the number of one it doesn't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AnyMessageType {
+    Known(MessageType),
+    Unknown(u16),
+}
+
+impl AnyMessageType {
+    pub fn from_u16(t: u16) -> AnyMessageType {
+        match MessageType::from_u16(t) {
+            Some(known) => AnyMessageType::Known(known),
+            None => AnyMessageType::Unknown(t),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            AnyMessageType::Known(known) => known.to_u16(),
+            AnyMessageType::Unknown(t) => *t,
+        }
+    }
+
+    /// The `MessageType`, if this is one this crate knows about.
+    pub fn known(&self) -> Option<MessageType> {
+        match self {
+            AnyMessageType::Known(known) => Some(*known),
+            AnyMessageType::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u16> for AnyMessageType {
+    fn from(t: u16) -> AnyMessageType {
+        AnyMessageType::from_u16(t)
+    }
+}
+
+impl From<MessageType> for AnyMessageType {
+    fn from(t: MessageType) -> AnyMessageType {
+        AnyMessageType::Known(t)
+    }
+}
+
+impl From<AnyMessageType> for u16 {
+    fn from(t: AnyMessageType) -> u16 {
+        t.to_u16()
+    }
+}
+
+/// Displays known types by name and unknown ones by number, eg. `ARM_START (8)` or
+/// `unknown (4321)`.
+impl fmt::Display for AnyMessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnyMessageType::Known(known) => write!(f, "{:?} ({})", known, known.to_u16()),
+            AnyMessageType::Unknown(t) => write!(f, "unknown ({})", t),
+        }
+    }
+}
+
+#[test]
+fn test_any_message_type() {
+    for t in 0..=u16::MAX {
+        let any = AnyMessageType::from_u16(t);
+        assert_eq!(any.to_u16(), t);
+        if let Some(known) = any.known() {
+            assert_eq!(known.to_u16(), t);
+            assert_eq!(MessageType::from_u16(t), Some(known));
+        }
+    }
+    assert_eq!(
+        AnyMessageType::from(8),
+        AnyMessageType::Known(MessageType::ARM_START)
+    );
+    assert_eq!(AnyMessageType::from(8).to_string(), "ARM_START (8)");
+    assert_eq!(AnyMessageType::from(1499), AnyMessageType::Unknown(1499));
+    assert_eq!(AnyMessageType::from(1499).to_string(), "unknown (1499)");
+    let name = |t| MessageType::from_u16(t).map(|t| format!("{:?}", t));
+    if cfg!(feature = "gnunet-0-10") {
+        assert_eq!(name(366).as_deref(), Some("TRANSPORT_SET_QUOTA"));
+        assert_eq!(name(547).as_deref(), Some("CONSENSUS_P2P_SYNCED"));
+        assert_eq!(name(548).as_deref(), Some("CONSENSUS_P2P_FIN"));
+    } else {
+        assert_eq!(name(366).as_deref(), Some("TRANSPORT_RECV_OK"));
+        assert_eq!(name(547).as_deref(), Some("CONSENSUS_P2P_ROUND_CONTEXT"));
+        assert_eq!(name(548).as_deref(), Some("CONSENSUS_P2P_ABORT"));
+    }
+    assert_eq!(
+        MessageType::from_u16(893).is_some(),
+        cfg!(feature = "malicious")
+    );
+}
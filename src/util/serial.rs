@@ -1,7 +1,7 @@
 use byteorder::BigEndian;
 use smallvec::SmallVec;
 use std::str;
-pub use zerocopy::{AsBytes, FromBytes, LayoutVerified, U16, U32, U64};
+pub use zerocopy::{AsBytes, FromBytes, LayoutVerified, I16, I32, U16, U32, U64};
 
 #[allow(non_camel_case_types)]
 pub type u16be = U16<BigEndian>;
@@ -9,6 +9,10 @@ pub type u16be = U16<BigEndian>;
 pub type u32be = U32<BigEndian>;
 #[allow(non_camel_case_types)]
 pub type u64be = U64<BigEndian>;
+#[allow(non_camel_case_types)]
+pub type i16be = I16<BigEndian>;
+#[allow(non_camel_case_types)]
+pub type i32be = I32<BigEndian>;
 
 pub type Buffer = SmallVec<[u8; 64]>;
 
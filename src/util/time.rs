@@ -92,6 +92,20 @@ impl From<Relative> for Duration {
     }
 }
 
+/// The longest delay produced by `std_backoff`.
+pub const STD_EXPONENTIAL_BACKOFF_THRESHOLD: Duration = Duration::from_secs(15 * 60);
+
+/// Compute the next delay of an exponential backoff, like `GNUNET_TIME_STD_BACKOFF`.
+///
+/// Doubles `r`, but returns at least one millisecond and at most
+/// `STD_EXPONENTIAL_BACKOFF_THRESHOLD`.
+pub fn std_backoff(r: Duration) -> Duration {
+    r.checked_mul(2)
+        .unwrap_or(STD_EXPONENTIAL_BACKOFF_THRESHOLD)
+        .max(Duration::from_millis(1))
+        .min(STD_EXPONENTIAL_BACKOFF_THRESHOLD)
+}
+
 #[cfg(tests)]
 mod test {
     #[test]
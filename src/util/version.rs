@@ -0,0 +1,115 @@
+//! The GNUnet releases whose IPC protocols this crate can speak.
+//!
+//! A few messages changed layout between GNUnet releases: GNS lookups dropped their shorten key in
+//! 0.11, cadet ports became hash codes in 0.11, and keys started carrying their type in 0.14. Each
+//! affected client speaks the `ProtocolVersion::default()` unless given another with its
+//! `set_version` method, so one program can talk to peers of different releases.
+//!
+//! The default is GNUnet 0.10, or the newest release selected by the `gnunet-0-11` and
+//! `gnunet-0-14` cargo features. The `gnunet-0-10` feature also numbers the few message types
+//! whose numbers were reused by later releases as GNUnet 0.10 does, and so can't be combined with
+//! the other two; see `util::message_type`.
+
+#[cfg(all(
+    feature = "gnunet-0-10",
+    any(feature = "gnunet-0-11", feature = "gnunet-0-14")
+))]
+compile_error!("The `gnunet-0-10` feature can't be combined with `gnunet-0-11` or `gnunet-0-14`.");
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A GNUnet release, or range of releases sharing a protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// GNUnet 0.10.
+    V0_10,
+    /// GNUnet 0.11 to 0.13.
+    V0_11,
+    /// GNUnet 0.14 and 0.15.
+    V0_14,
+}
+
+impl ProtocolVersion {
+    /// Every supported version, oldest first.
+    pub const ALL: [ProtocolVersion; 3] = [
+        ProtocolVersion::V0_10,
+        ProtocolVersion::V0_11,
+        ProtocolVersion::V0_14,
+    ];
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> ProtocolVersion {
+        if cfg!(feature = "gnunet-0-14") {
+            ProtocolVersion::V0_14
+        } else if cfg!(feature = "gnunet-0-11") {
+            ProtocolVersion::V0_11
+        } else {
+            ProtocolVersion::V0_10
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ProtocolVersion::V0_10 => "0.10",
+            ProtocolVersion::V0_11 => "0.11",
+            ProtocolVersion::V0_14 => "0.14",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Error returned when parsing a `ProtocolVersion` fails.
+#[derive(Debug, Error)]
+#[error("Unsupported GNUnet version: \"{version}\"")]
+pub struct ProtocolVersionParseError {
+    pub version: String,
+}
+
+/// Parses a GNUnet release number such as `0.12.2`, choosing the version that release speaks.
+impl FromStr for ProtocolVersion {
+    type Err = ProtocolVersionParseError;
+
+    fn from_str(s: &str) -> Result<ProtocolVersion, ProtocolVersionParseError> {
+        let err = || ProtocolVersionParseError {
+            version: s.to_string(),
+        };
+        let mut parts = s.split('.');
+        let major: u32 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(err)?;
+        let minor: u32 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(err)?;
+        if parts.any(|p| p.parse::<u32>().is_err()) {
+            return Err(err());
+        }
+        match (major, minor) {
+            (0, 10) => Ok(ProtocolVersion::V0_10),
+            (0, 11..=13) => Ok(ProtocolVersion::V0_11),
+            (0, 14..=15) => Ok(ProtocolVersion::V0_14),
+            _ => Err(err()),
+        }
+    }
+}
+
+#[test]
+fn test_parse_version() {
+    assert_eq!(
+        "0.10".parse::<ProtocolVersion>().unwrap(),
+        ProtocolVersion::V0_10
+    );
+    assert_eq!(
+        "0.12.2".parse::<ProtocolVersion>().unwrap(),
+        ProtocolVersion::V0_11
+    );
+    assert_eq!(
+        "0.15.3".parse::<ProtocolVersion>().unwrap(),
+        ProtocolVersion::V0_14
+    );
+    assert!("0.9.5".parse::<ProtocolVersion>().is_err());
+    assert!("0.14.x".parse::<ProtocolVersion>().is_err());
+    assert!("latest".parse::<ProtocolVersion>().is_err());
+    for v in ProtocolVersion::ALL.iter() {
+        assert_eq!(v.to_string().parse::<ProtocolVersion>().unwrap(), *v);
+    }
+}